@@ -0,0 +1,198 @@
+use std::io::{Cursor, Read};
+
+use anyhow::{anyhow, Result};
+use binread::{BinRead, BinReaderExt, BinResult};
+
+use crate::{common::{FourCC, four_cc}, mac_roman, resources::Resources};
+
+/// Header of a 'cfrg' resource
+///
+/// <https://web.archive.org/web/20020219190852/http://developer.apple.com/techpubs/mac/runtimehtml/RTArch-91.html>
+#[derive(BinRead, Debug)]
+#[br(big)]
+struct Header {
+	_reserved_a: u32,
+	_reserved_b: u32,
+	_reserved_c: u16,
+	version: u16,
+	_reserved_d: [u32; 4],
+	_reserved_h: u16,
+	member_count: u16
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+struct Member {
+	architecture: FourCC,
+	_reserved_a: u16,
+	_reserved_b: u8,
+	_update_level: u8,
+	current_version: u32,
+	old_def_version: u32,
+	app_stack_size: u32,
+	_app_subdir_id: u16,
+	usage: u8,
+	location: u8,
+	offset: u32,
+	length: u32,
+	_reserved_c: u32,
+	_reserved_d: u16,
+	_extension_count: u16,
+	member_size: u16,
+	name_length: u8
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Usage {
+	ImportLibrary,
+	Application,
+	DropIn,
+	StubLibrary,
+	WeakStubLibrary,
+	Unknown(u8)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+	Memory,
+	DataFork,
+	ResourceFork,
+	Unknown(u8)
+}
+
+#[derive(Debug)]
+pub struct Fragment {
+	pub name: String,
+	pub architecture: FourCC,
+	pub current_version: u32,
+	pub old_def_version: u32,
+	pub app_stack_size: u32,
+	pub usage: Usage,
+	pub location: Location,
+	pub offset: u32,
+	pub length: u32
+}
+
+impl Fragment {
+	pub fn is_powerpc(&self) -> bool {
+		self.architecture == four_cc(*b"pwpc")
+	}
+
+	/// Gets the container for this fragment out of a data fork. A length of 0
+	/// means that the fragment extends to the end of the fork.
+	pub fn slice<'a>(&self, data_fork: &'a [u8]) -> Option<&'a [u8]> {
+		let start = self.offset as usize;
+		let end = if self.length == 0 { data_fork.len() } else { start + self.length as usize };
+		data_fork.get(start .. end)
+	}
+}
+
+pub fn parse_cfrg(data: &[u8]) -> BinResult<Vec<Fragment>> {
+	let mut cursor = Cursor::new(data);
+	let header: Header = cursor.read_be()?;
+	if header.version != 1 {
+		warn!(target: "cfrg", "Unexpected cfrg version {}", header.version);
+	}
+
+	let mut fragments = Vec::new();
+	let mut member_pos = cursor.position();
+
+	for _ in 0..header.member_count {
+		cursor.set_position(member_pos);
+		let member: Member = cursor.read_be()?;
+
+		let mut name = vec![0u8; member.name_length as usize];
+		cursor.read_exact(&mut name)?;
+
+		fragments.push(Fragment {
+			name: mac_roman::decode_string(&name, false).into_owned(),
+			architecture: member.architecture,
+			current_version: member.current_version,
+			old_def_version: member.old_def_version,
+			app_stack_size: member.app_stack_size,
+			usage: match member.usage {
+				0 => Usage::ImportLibrary,
+				1 => Usage::Application,
+				2 => Usage::DropIn,
+				3 => Usage::StubLibrary,
+				4 => Usage::WeakStubLibrary,
+				n => Usage::Unknown(n)
+			},
+			location: match member.location {
+				0 => Location::Memory,
+				1 => Location::DataFork,
+				2 => Location::ResourceFork,
+				n => Location::Unknown(n)
+			},
+			offset: member.offset,
+			length: member.length
+		});
+
+		member_pos += member.member_size as u64;
+	}
+
+	Ok(fragments)
+}
+
+/// Reads the fragment list from the 'cfrg' 0 resource, if the file has one.
+pub fn get_fragments(resources: &Resources) -> BinResult<Option<Vec<Fragment>>> {
+	match resources.get(four_cc(*b"cfrg"), 0) {
+		Some(res) => Ok(Some(parse_cfrg(&res.borrow().data)?)),
+		None => Ok(None)
+	}
+}
+
+/// Picks a PowerPC fragment that lives in the data fork. `wanted` may either be
+/// an index into the fragment list or a fragment name; if it's not specified,
+/// applications are preferred over any other kind of fragment.
+pub fn select_fragment<'a>(fragments: &'a [Fragment], wanted: Option<&str>) -> Option<&'a Fragment> {
+	let usable = |f: &&Fragment| f.is_powerpc() && f.location == Location::DataFork;
+
+	match wanted {
+		Some(wanted) => {
+			if let Ok(index) = wanted.parse::<usize>() {
+				fragments.get(index).filter(usable)
+			} else {
+				fragments.iter().filter(usable).find(|f| f.name == wanted)
+			}
+		}
+		None => {
+			fragments.iter().filter(usable).find(|f| f.usage == Usage::Application)
+				.or_else(|| fragments.iter().find(usable))
+		}
+	}
+}
+
+/// Finds the container to load out of a file: the chosen PowerPC fragment if
+/// there's a 'cfrg' resource, or the whole data fork if there isn't.
+pub fn find_container<'a>(data_fork: &'a [u8], resources: Option<&Resources>, wanted: Option<&str>) -> Result<&'a [u8]> {
+	let fragments = match resources {
+		Some(res) => get_fragments(res)?,
+		None => None
+	};
+
+	match fragments {
+		Some(fragments) => {
+			for (i, fragment) in fragments.iter().enumerate() {
+				info!(
+					target: "cfrg",
+					"Fragment #{i}: {:?} Arch={:?} Usage={:?} Location={:?} Offset={:X} Length={:X} Version(Current={:X}, OldDef={:X}) StackSize={:X}",
+					fragment.name, fragment.architecture, fragment.usage, fragment.location,
+					fragment.offset, fragment.length, fragment.current_version, fragment.old_def_version,
+					fragment.app_stack_size);
+			}
+
+			let fragment = select_fragment(&fragments, wanted)
+				.ok_or_else(|| anyhow!("No suitable PowerPC fragment found"))?;
+			info!(target: "cfrg", "Using fragment {:?}", fragment.name);
+			fragment.slice(data_fork)
+				.ok_or_else(|| anyhow!("Fragment {:?} lies outside the data fork", fragment.name))
+		}
+		None => {
+			if wanted.is_some() {
+				warn!(target: "cfrg", "File has no cfrg resource, ignoring fragment choice");
+			}
+			Ok(data_fork)
+		}
+	}
+}
@@ -0,0 +1,51 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+use crate::{emulator::{self, ImportStatus}, linker};
+
+fn status_name(status: ImportStatus) -> &'static str {
+	match status {
+		ImportStatus::Implemented => "implemented",
+		ImportStatus::Data => "data",
+		ImportStatus::Library => "library",
+		ImportStatus::Weak => "weak",
+		ImportStatus::Missing => "missing"
+	}
+}
+
+/// Prints the status of every import. Returns false if any strong ones are missing.
+pub fn check(path: &str, fragment_choice: Option<&str>, library_path: Vec<PathBuf>) -> Result<bool> {
+	let (exe, res) = linker::load_executable(Path::new(path), fragment_choice, library_path, emulator::data_import_size)?;
+
+	let statuses = emulator::import_coverage(&exe, res);
+	let mut totals = [0usize; 5];
+
+	for (library_index, library) in exe.libraries.iter().enumerate() {
+		let mut imports = exe.imports.iter().zip(&statuses)
+			.filter(|(import, _)| import.library == library_index)
+			.map(|(import, &status)| (status, import.name.as_str()))
+			.collect::<Vec<_>>();
+		if imports.is_empty() {
+			continue;
+		}
+		imports.sort();
+
+		println!("{library} ({} imports)", imports.len());
+		for (status, name) in imports {
+			println!("  {:<12} {name}", status_name(status));
+			totals[status as usize] += 1;
+		}
+	}
+
+	let missing = totals[ImportStatus::Missing as usize];
+	println!(
+		"{} imports: {} implemented, {} data, {} from libraries, {} weak, {missing} missing",
+		statuses.len(),
+		totals[ImportStatus::Implemented as usize],
+		totals[ImportStatus::Data as usize],
+		totals[ImportStatus::Library as usize],
+		totals[ImportStatus::Weak as usize]);
+
+	Ok(missing == 0)
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+
+/// Just enough JSON to write machine-readable output.
+#[derive(Clone, Debug)]
+pub enum Value {
+	Null,
+	Bool(bool),
+	Number(i64),
+	String(String),
+	Array(Vec<Value>),
+	Object(Vec<(String, Value)>)
+}
+
+impl Value {
+	pub fn object<K: Into<String>>(fields: impl IntoIterator<Item = (K, Value)>) -> Value {
+		Value::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+	}
+}
+
+impl From<bool> for Value {
+	fn from(v: bool) -> Self { Value::Bool(v) }
+}
+impl From<i64> for Value {
+	fn from(v: i64) -> Self { Value::Number(v) }
+}
+impl From<u32> for Value {
+	fn from(v: u32) -> Self { Value::Number(v.into()) }
+}
+impl From<i32> for Value {
+	fn from(v: i32) -> Self { Value::Number(v.into()) }
+}
+impl From<u16> for Value {
+	fn from(v: u16) -> Self { Value::Number(v.into()) }
+}
+impl From<i16> for Value {
+	fn from(v: i16) -> Self { Value::Number(v.into()) }
+}
+impl From<u8> for Value {
+	fn from(v: u8) -> Self { Value::Number(v.into()) }
+}
+impl From<usize> for Value {
+	fn from(v: usize) -> Self { Value::Number(v as i64) }
+}
+impl From<&str> for Value {
+	fn from(v: &str) -> Self { Value::String(v.to_string()) }
+}
+impl From<String> for Value {
+	fn from(v: String) -> Self { Value::String(v) }
+}
+impl<T: Into<Value>> From<Option<T>> for Value {
+	fn from(v: Option<T>) -> Self { v.map_or(Value::Null, Into::into) }
+}
+impl<T: Into<Value>> From<Vec<T>> for Value {
+	fn from(v: Vec<T>) -> Self { Value::Array(v.into_iter().map(Into::into).collect()) }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+	f.write_str("\"")?;
+	for c in s.chars() {
+		match c {
+			'"' => f.write_str("\\\"")?,
+			'\\' => f.write_str("\\\\")?,
+			'\n' => f.write_str("\\n")?,
+			'\r' => f.write_str("\\r")?,
+			'\t' => f.write_str("\\t")?,
+			c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+			c => write!(f, "{c}")?
+		}
+	}
+	f.write_str("\"")
+}
+
+impl fmt::Display for Value {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Value::Null => f.write_str("null"),
+			Value::Bool(b) => write!(f, "{b}"),
+			Value::Number(n) => write!(f, "{n}"),
+			Value::String(s) => write_string(f, s),
+			Value::Array(items) => {
+				f.write_str("[")?;
+				for (i, item) in items.iter().enumerate() {
+					if i > 0 {
+						f.write_str(",")?;
+					}
+					write!(f, "{item}")?;
+				}
+				f.write_str("]")
+			}
+			Value::Object(fields) => {
+				f.write_str("{")?;
+				for (i, (key, value)) in fields.iter().enumerate() {
+					if i > 0 {
+						f.write_str(",")?;
+					}
+					write_string(f, key)?;
+					write!(f, ":{value}")?;
+				}
+				f.write_str("}")
+			}
+		}
+	}
+}
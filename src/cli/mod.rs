@@ -0,0 +1,67 @@
+//! What the command line tool does. Parsing arguments and picking exit codes
+//! is left to the binary; everything here reports problems as errors.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::{emulator, linker};
+
+mod check;
+mod json;
+mod pef_dump;
+#[cfg(unix)]
+mod server;
+mod strip_imports;
+
+pub use check::check;
+pub use pef_dump::dump as pef_dump;
+#[cfg(unix)]
+pub use server::{client, serve};
+pub use strip_imports::strip_imports;
+
+/// Everything the command line can ask for besides the tool's own arguments
+#[derive(Default)]
+pub struct RunOptions {
+	/// Picks a fragment by index or name, for tools that contain several
+	pub fragment_choice: Option<String>,
+	/// Directories to search for shared libraries the tool imports
+	pub library_path: Vec<PathBuf>,
+	/// Port to wait for a GDB connection on
+	pub gdb_port: Option<u16>,
+	/// Logs every HLE call. Giving an output or filters turns this on too.
+	pub trace_calls: bool,
+	/// File to write the call trace to, or None for stderr
+	pub trace_output: Option<PathBuf>,
+	/// Calls to trace, if not all of them
+	pub trace_filters: Vec<String>,
+	/// Where to stop for a snapshot, and the file to write it to
+	pub snapshot: Option<(String, PathBuf)>,
+	/// Snapshot to carry on from instead of starting afresh
+	pub resume: Option<PathBuf>,
+	pub sanitize_heap: bool,
+	pub limits: emulator::Limits
+}
+
+/// Loads a tool and runs it with `args`, the first of which is the tool
+/// itself. Returns the tool's exit status.
+pub fn run(options: RunOptions, args: &[String], env_vars: &[(String, String)]) -> Result<i32> {
+	let path = args.first().ok_or_else(|| anyhow!("No executable specified"))?;
+	let (exe, res) = linker::load_executable(Path::new(path), options.fragment_choice.as_deref(), options.library_path, emulator::data_import_size)
+		.map_err(|e| anyhow!("Cannot load executable {path:?}: {e}"))?;
+
+	let trace_calls = (options.trace_calls || options.trace_output.is_some() || !options.trace_filters.is_empty())
+		.then(|| emulator::CallTraceOptions { output: options.trace_output, filters: options.trace_filters });
+	let options = emulator::Options {
+		gdb_port: options.gdb_port,
+		trace_calls,
+		snapshot: options.snapshot.map(|(at, path)| emulator::SnapshotOptions { at, path }),
+		resume: options.resume,
+		limits: options.limits,
+		sanitize_heap: options.sanitize_heap,
+		..Default::default()
+	};
+
+	emulator::emulate(&exe, res, args, env_vars, options)
+		.map_err(|e| anyhow!("Emulation failed: {e:?}"))
+}
@@ -0,0 +1,258 @@
+use anyhow::Result;
+
+use crate::{cfrg, common::{self, Shared}, filesystem, pef, resources};
+
+use super::json::Value;
+
+/// Prints the container, loader and relocations of a PEF file, or of the
+/// chosen fragment of an application, as text or JSON.
+pub fn dump(path: &str, fragment_choice: Option<&str>, as_json: bool) -> Result<()> {
+	let file = Shared::new(filesystem::MacFile::open(path)?);
+	let res = resources::parse_resources(Shared::clone(&file)).ok();
+
+	let file = file.borrow();
+	let container = cfrg::find_container(&file.data_fork, res.as_ref(), fragment_choice)?;
+	let pef = pef::read_pef(container)?;
+
+	let loader = match pef.sections.iter().find(|s| s.section_kind == pef::SectionType::Loader) {
+		Some(section) => Some(pef::parse_loader(section.packed_contents.as_deref().unwrap_or(&[]))?),
+		None => None
+	};
+
+	if as_json {
+		println!("{}", to_json(&pef, loader.as_ref()));
+	} else {
+		print_text(&pef, loader.as_ref());
+	}
+
+	Ok(())
+}
+
+fn entry_point(section: i32, offset: u32) -> Option<(i32, u32)> {
+	(section >= 0).then_some((section, offset))
+}
+
+/// Sections store their alignment as a power of two, which may be too big for a u32
+fn alignment(section: &pef::Section) -> Option<u32> {
+	1u32.checked_shl(section.alignment.into())
+}
+
+fn reloc_name(op: &pef::RelocOp) -> &'static str {
+	match op {
+		pef::RelocOp::BySectDWithSkip { .. } => "BySectDWithSkip",
+		pef::RelocOp::BySectC { .. } => "BySectC",
+		pef::RelocOp::BySectD { .. } => "BySectD",
+		pef::RelocOp::TVector12 { .. } => "TVector12",
+		pef::RelocOp::TVector8 { .. } => "TVector8",
+		pef::RelocOp::VTable8 { .. } => "VTable8",
+		pef::RelocOp::ImportRun { .. } => "ImportRun",
+		pef::RelocOp::ByImport { .. } => "ByImport",
+		pef::RelocOp::SetSectC { .. } => "SetSectC",
+		pef::RelocOp::SetSectD { .. } => "SetSectD",
+		pef::RelocOp::BySection { .. } => "BySection",
+		pef::RelocOp::IncrPosition { .. } => "IncrPosition",
+		pef::RelocOp::Repeat { .. } => "Repeat",
+		pef::RelocOp::SetPosition { .. } => "SetPosition",
+		pef::RelocOp::Unknown(_) => "Unknown"
+	}
+}
+
+fn reloc_params(op: &pef::RelocOp) -> Vec<(&'static str, u32)> {
+	match *op {
+		pef::RelocOp::BySectDWithSkip { skip, count } => vec![("skip", skip), ("count", count)],
+		pef::RelocOp::BySectC { count } | pef::RelocOp::BySectD { count } |
+		pef::RelocOp::TVector12 { count } | pef::RelocOp::TVector8 { count } |
+		pef::RelocOp::VTable8 { count } | pef::RelocOp::ImportRun { count } => vec![("count", count)],
+		pef::RelocOp::ByImport { index } => vec![("index", index)],
+		pef::RelocOp::SetSectC { section } | pef::RelocOp::SetSectD { section } |
+		pef::RelocOp::BySection { section } => vec![("section", section)],
+		pef::RelocOp::IncrPosition { offset } | pef::RelocOp::SetPosition { offset } => vec![("offset", offset)],
+		pef::RelocOp::Repeat { blocks, times } => vec![("blocks", blocks), ("times", times)],
+		pef::RelocOp::Unknown(block) => vec![("block", block.into())]
+	}
+}
+
+/// Decodes a relocation section into (block offset, instruction) pairs.
+fn decode_relocs(relocs: &pef::RelocSection) -> Vec<(usize, pef::RelocOp)> {
+	let mut result = Vec::new();
+	let mut pos = 0;
+	while pos < relocs.data.len() {
+		let (op, length) = pef::decode_reloc(&relocs.data, pos);
+		result.push((pos, op));
+		pos += length;
+	}
+	result
+}
+
+fn print_text(pef: &pef::PEF, loader: Option<&pef::Loader>) {
+	println!("Container:");
+	println!("  Architecture: {:?}", pef.architecture);
+	println!("  Format version: {}", pef.format_version);
+	println!("  Timestamp: {:08X} ({})", pef.date_time_stamp, common::parse_mac_time(pef.date_time_stamp).format("%Y-%m-%d %H:%M:%S"));
+	println!("  Versions: current={:08X} oldDef={:08X} oldImp={:08X}", pef.current_version, pef.old_def_version, pef.old_imp_version);
+
+	println!();
+	println!("Sections:");
+	for (i, section) in pef.sections.iter().enumerate() {
+		println!(
+			"  #{i}: {:?} {:?} share={:?} align={} default={:08X} total={:X} unpacked={:X} packed={:X}",
+			section.name.as_deref().unwrap_or(""), section.section_kind, section.share_kind,
+			alignment(section).map_or_else(|| format!("2^{}", section.alignment), |a| a.to_string()),
+			section.default_address,
+			section.total_size, section.unpacked_size, section.packed_size);
+	}
+
+	let loader = match loader {
+		Some(loader) => loader,
+		None => return
+	};
+
+	println!();
+	println!("Entry points:");
+	for (what, section, offset) in [
+		("Init", loader.init_section, loader.init_offset),
+		("Main", loader.main_section, loader.main_offset),
+		("Term", loader.term_section, loader.term_offset)
+	] {
+		match entry_point(section, offset) {
+			Some((section, offset)) => println!("  {what}: section #{section} + {offset:X}"),
+			None => println!("  {what}: none")
+		}
+	}
+
+	println!();
+	println!("Imported libraries:");
+	for (i, lib) in loader.imported_libraries.iter().enumerate() {
+		println!(
+			"  #{i}: {} current={:08X} oldImp={:08X}{}{} ({} symbols)",
+			lib.name, lib.current_version, lib.old_imp_version,
+			if lib.is_weak { " weak" } else { "" },
+			if lib.import_order { " init-before" } else { "" },
+			lib.imported_symbols.len());
+	}
+
+	println!();
+	println!("Imported symbols:");
+	for (i, sym) in loader.imported_symbols.iter().enumerate() {
+		let lib = loader.imported_libraries.get(sym.library).map_or("?", |lib| lib.name.as_str());
+		println!("  #{i}: {lib}::{} {:?}{}", sym.name, sym.class, if sym.weak { " weak" } else { "" });
+	}
+
+	println!();
+	println!("Exports:");
+	for sym in &loader.exports.symbols {
+		match sym.location() {
+			pef::ExportLocation::Section(section, offset) => println!("  {} {:?} section #{section} + {offset:X}", sym.name, sym.class),
+			pef::ExportLocation::Absolute(address) => println!("  {} {:?} absolute {address:08X}", sym.name, sym.class),
+			pef::ExportLocation::ReexportedImport(index) => println!("  {} {:?} re-exports import #{index}", sym.name, sym.class)
+		}
+	}
+
+	println!();
+	println!("Relocations:");
+	for relocs in &loader.reloc_sections {
+		println!("  Section #{} ({} blocks):", relocs.section_index, relocs.data.len());
+		for (pos, op) in decode_relocs(relocs) {
+			let params = reloc_params(&op).iter()
+				.map(|(name, value)| format!("{name}={value:X}"))
+				.collect::<Vec<_>>()
+				.join(" ");
+			let symbol = match op {
+				pef::RelocOp::ByImport { index } => loader.imported_symbols.get(index as usize)
+					.map_or_else(String::new, |sym| format!(" ({})", sym.name)),
+				_ => String::new()
+			};
+			println!("    [{pos:04X}] {} {params}{symbol}", reloc_name(&op));
+		}
+	}
+}
+
+fn to_json(pef: &pef::PEF, loader: Option<&pef::Loader>) -> Value {
+	let sections = pef.sections.iter().enumerate().map(|(i, section)| Value::object([
+		("index", i.into()),
+		("name", section.name.clone().into()),
+		("kind", format!("{:?}", section.section_kind).into()),
+		("share", format!("{:?}", section.share_kind).into()),
+		("alignment", alignment(section).into()),
+		("default_address", section.default_address.into()),
+		("total_size", section.total_size.into()),
+		("unpacked_size", section.unpacked_size.into()),
+		("packed_size", section.packed_size.into())
+	])).collect::<Vec<_>>();
+
+	let mut fields = vec![
+		("architecture", format!("{:?}", pef.architecture).into()),
+		("format_version", pef.format_version.into()),
+		("date_time_stamp", pef.date_time_stamp.into()),
+		("current_version", pef.current_version.into()),
+		("old_def_version", pef.old_def_version.into()),
+		("old_imp_version", pef.old_imp_version.into()),
+		("sections", Value::Array(sections))
+	];
+
+	if let Some(loader) = loader {
+		let vector = |section, offset| match entry_point(section, offset) {
+			Some((section, offset)) => Value::object([("section", section.into()), ("offset", offset.into())]),
+			None => Value::Null
+		};
+
+		let libraries = loader.imported_libraries.iter().map(|lib| Value::object([
+			("name", lib.name.as_str().into()),
+			("current_version", lib.current_version.into()),
+			("old_imp_version", lib.old_imp_version.into()),
+			("weak", lib.is_weak.into()),
+			("init_before", lib.import_order.into())
+		])).collect::<Vec<_>>();
+
+		let imports = loader.imported_symbols.iter().map(|sym| Value::object([
+			("library", sym.library.into()),
+			("name", sym.name.as_str().into()),
+			("class", format!("{:?}", sym.class).into()),
+			("weak", sym.weak.into())
+		])).collect::<Vec<_>>();
+
+		let exports = loader.exports.symbols.iter().map(|sym| {
+			let mut fields = vec![
+				("name", sym.name.as_str().into()),
+				("class", format!("{:?}", sym.class).into())
+			];
+			match sym.location() {
+				pef::ExportLocation::Section(section, offset) => {
+					fields.push(("section", section.into()));
+					fields.push(("offset", offset.into()));
+				}
+				pef::ExportLocation::Absolute(address) => fields.push(("address", address.into())),
+				pef::ExportLocation::ReexportedImport(index) => fields.push(("import", index.into()))
+			}
+			Value::object(fields)
+		}).collect::<Vec<_>>();
+
+		let relocations = loader.reloc_sections.iter().map(|relocs| {
+			let instructions = decode_relocs(relocs).into_iter().map(|(pos, op)| {
+				let mut fields = vec![
+					("offset", pos.into()),
+					("op", reloc_name(&op).into())
+				];
+				for (name, value) in reloc_params(&op) {
+					fields.push((name, value.into()));
+				}
+				Value::object(fields)
+			}).collect::<Vec<_>>();
+
+			Value::object([
+				("section", relocs.section_index.into()),
+				("instructions", Value::Array(instructions))
+			])
+		}).collect::<Vec<_>>();
+
+		fields.push(("init", vector(loader.init_section, loader.init_offset)));
+		fields.push(("main", vector(loader.main_section, loader.main_offset)));
+		fields.push(("term", vector(loader.term_section, loader.term_offset)));
+		fields.push(("imported_libraries", Value::Array(libraries)));
+		fields.push(("imported_symbols", Value::Array(imports)));
+		fields.push(("exports", Value::Array(exports)));
+		fields.push(("relocations", Value::Array(relocations)));
+	}
+
+	Value::object(fields)
+}
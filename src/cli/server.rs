@@ -0,0 +1,276 @@
+use std::{
+	io::{self, Read, Write},
+	os::unix::{
+		fs::FileTypeExt,
+		io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+		net::{UnixListener, UnixStream}
+	},
+	path::{Path, PathBuf}
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{emulator, linker};
+
+// A job is sent as a 4-byte length with the client's stdin, stdout and stderr
+// attached, followed by that many bytes: the working directory, the arguments
+// and the environment, each string prefixed by its length. The server answers
+// with the tool's 4-byte exit status once it's done.
+
+const PASSED_FDS: usize = 3;
+/// Refuse jobs bigger than this, as they're probably garbage
+const MAX_JOB_SIZE: u32 = 16 * 1024 * 1024;
+
+struct Job {
+	cwd: PathBuf,
+	args: Vec<String>,
+	env_vars: Vec<(String, String)>
+}
+
+fn write_string(buffer: &mut Vec<u8>, s: &str) {
+	buffer.extend_from_slice(&(s.len() as u32).to_be_bytes());
+	buffer.extend_from_slice(s.as_bytes());
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32> {
+	if data.len() < 4 {
+		return Err(anyhow!("job is truncated"));
+	}
+	let (value, rest) = data.split_at(4);
+	*data = rest;
+	Ok(u32::from_be_bytes(value.try_into().unwrap()))
+}
+
+fn read_string(data: &mut &[u8]) -> Result<String> {
+	let length = read_u32(data)? as usize;
+	if data.len() < length {
+		return Err(anyhow!("job is truncated"));
+	}
+	let (value, rest) = data.split_at(length);
+	*data = rest;
+	Ok(String::from_utf8(value.to_vec())?)
+}
+
+impl Job {
+	fn encode(&self) -> Vec<u8> {
+		let mut buffer = Vec::new();
+		write_string(&mut buffer, &self.cwd.to_string_lossy());
+		buffer.extend_from_slice(&(self.args.len() as u32).to_be_bytes());
+		for arg in &self.args {
+			write_string(&mut buffer, arg);
+		}
+		buffer.extend_from_slice(&(self.env_vars.len() as u32).to_be_bytes());
+		for (name, value) in &self.env_vars {
+			write_string(&mut buffer, name);
+			write_string(&mut buffer, value);
+		}
+		buffer
+	}
+
+	fn decode(mut data: &[u8]) -> Result<Job> {
+		let cwd = PathBuf::from(read_string(&mut data)?);
+		let args = (0..read_u32(&mut data)?).map(|_| read_string(&mut data)).collect::<Result<_>>()?;
+		let env_vars = (0..read_u32(&mut data)?)
+			.map(|_| Ok((read_string(&mut data)?, read_string(&mut data)?)))
+			.collect::<Result<_>>()?;
+		Ok(Job { cwd, args, env_vars })
+	}
+}
+
+/// Sends some bytes along with copies of the given file descriptors.
+fn send_with_fds(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+	let fds_size = std::mem::size_of_val(fds) as u32;
+	let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_size) } as usize];
+	let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut _, iov_len: data.len() };
+
+	let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+	msg.msg_iov = &mut iov;
+	msg.msg_iovlen = 1;
+	msg.msg_control = control.as_mut_ptr() as *mut _;
+	msg.msg_controllen = control.len() as _;
+
+	let sent = unsafe {
+		let cmsg = libc::CMSG_FIRSTHDR(&msg);
+		(*cmsg).cmsg_level = libc::SOL_SOCKET;
+		(*cmsg).cmsg_type = libc::SCM_RIGHTS;
+		(*cmsg).cmsg_len = libc::CMSG_LEN(fds_size) as _;
+		std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+		libc::sendmsg(stream.as_raw_fd(), &msg, 0)
+	};
+	if sent < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	let mut stream = stream;
+	stream.write_all(&data[sent as usize..])
+}
+
+/// Fills `data`, returning whatever file descriptors came along with it.
+fn recv_with_fds(stream: &UnixStream, data: &mut [u8]) -> io::Result<Vec<OwnedFd>> {
+	let mut control = vec![0u8; unsafe { libc::CMSG_SPACE((PASSED_FDS * std::mem::size_of::<RawFd>()) as u32) } as usize];
+	let mut iov = libc::iovec { iov_base: data.as_mut_ptr() as *mut _, iov_len: data.len() };
+
+	let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+	msg.msg_iov = &mut iov;
+	msg.msg_iovlen = 1;
+	msg.msg_control = control.as_mut_ptr() as *mut _;
+	msg.msg_controllen = control.len() as _;
+
+	let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+	if received < 0 {
+		return Err(io::Error::last_os_error());
+	}
+
+	let mut fds = Vec::new();
+	unsafe {
+		let cmsg = libc::CMSG_FIRSTHDR(&msg);
+		if !cmsg.is_null() && (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+			let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / std::mem::size_of::<RawFd>();
+			let first = libc::CMSG_DATA(cmsg) as *const RawFd;
+			for i in 0..count {
+				fds.push(OwnedFd::from_raw_fd(first.add(i).read_unaligned()));
+			}
+		}
+	}
+
+	let mut stream = stream;
+	stream.read_exact(&mut data[received as usize..])?;
+	Ok(fds)
+}
+
+fn receive_job(stream: &UnixStream) -> Result<(Job, Vec<OwnedFd>)> {
+	let mut header = [0u8; 4];
+	let fds = recv_with_fds(stream, &mut header)?;
+	if fds.len() != PASSED_FDS {
+		return Err(anyhow!("expected {PASSED_FDS} file descriptors, got {}", fds.len()));
+	}
+
+	let size = u32::from_be_bytes(header);
+	if size > MAX_JOB_SIZE {
+		return Err(anyhow!("job is too large ({size} bytes)"));
+	}
+	let mut data = vec![0u8; size as usize];
+	let mut stream = stream;
+	stream.read_exact(&mut data)?;
+
+	Ok((Job::decode(&data)?, fds))
+}
+
+/// Sets up a freshly forked child to run a job.
+fn enter_job(job: &Job, fds: Vec<OwnedFd>) -> io::Result<()> {
+	for (target, fd) in fds.iter().enumerate() {
+		if unsafe { libc::dup2(fd.as_raw_fd(), target as RawFd) } < 0 {
+			return Err(io::Error::last_os_error());
+		}
+	}
+	std::env::set_current_dir(&job.cwd)
+}
+
+fn listen(socket: &Path) -> io::Result<UnixListener> {
+	// clear out whatever a previous server left behind, but nothing else
+	if let Ok(metadata) = std::fs::symlink_metadata(socket) {
+		if metadata.file_type().is_socket() {
+			std::fs::remove_file(socket)?;
+		}
+	}
+	UnixListener::bind(socket)
+}
+
+/// Loads `tool` once, runs it up to main, then forks off a copy of it for
+/// each job sent to `socket`. Only returns in the server if it can't carry on,
+/// but in each forked copy this returns once the job is done, with the status
+/// that process should exit with.
+pub fn serve(socket: &Path, tool: &str, fragment_choice: Option<&str>, library_path: Vec<PathBuf>) -> Result<i32> {
+	let (exe, res) = linker::load_executable(Path::new(tool), fragment_choice, library_path, emulator::data_import_size)
+		.map_err(|e| anyhow!("Cannot load executable {tool:?}: {e}"))?;
+
+	let listener = listen(socket).map_err(|e| anyhow!("Cannot listen on {socket:?}: {e}"))?;
+
+	// Everything up to main runs once here; each job then gets a forked copy
+	// of the initialised process and talks to the client through `connection`
+	let mut connection = None;
+	let mut job_started = true;
+	let result = emulator::emulate_after_init(&exe, res, emulator::Options::default(), || {
+		// nobody waits for the children, so don't leave zombies around
+		unsafe { libc::signal(libc::SIGCHLD, libc::SIG_IGN) };
+		info!(target: "server", "Serving {tool} on {socket:?}");
+
+		for stream in listener.incoming() {
+			let stream = match stream {
+				Ok(stream) => stream,
+				Err(e) => {
+					warn!(target: "server", "Cannot accept connection: {e}");
+					continue;
+				}
+			};
+			let (job, fds) = match receive_job(&stream) {
+				Ok(job) => job,
+				Err(e) => {
+					warn!(target: "server", "Ignoring bad job: {e}");
+					continue;
+				}
+			};
+
+			match unsafe { libc::fork() } {
+				-1 => error!(target: "server", "Cannot fork: {}", io::Error::last_os_error()),
+				0 => {
+					connection = Some(stream);
+					if let Err(e) = enter_job(&job, fds) {
+						eprintln!("Cannot start job in {:?}: {e}", job.cwd);
+						job_started = false;
+						return None;
+					}
+
+					let mut args = vec![tool.to_string()];
+					args.extend(job.args);
+					return Some(emulator::Job { args, env_vars: job.env_vars, current_dir: Some(job.cwd) });
+				}
+				pid => debug!(target: "server", "Job {:?} running as process {pid}", job.args)
+			}
+		}
+
+		None
+	});
+
+	match connection {
+		Some(mut stream) => {
+			// this is a job, whose stderr is the client's
+			let status = match result {
+				Ok(status) if job_started => status,
+				Ok(_) => 1,
+				Err(e) => {
+					eprintln!("Emulation failed: {e:?}");
+					1
+				}
+			};
+			let _ = io::stdout().flush();
+			let _ = stream.write_all(&status.to_be_bytes());
+			Ok(0)
+		}
+		None => {
+			// only the server itself gets here, if it couldn't get going
+			result.map_err(|e| anyhow!("Emulation failed: {e:?}"))?;
+			Err(anyhow!("Server stopped"))
+		}
+	}
+}
+
+/// Sends a job to the server listening on `socket`, with this process's
+/// working directory, environment and stdio. Returns the job's exit status.
+pub fn client(socket: &Path, args: &[String]) -> Result<i32> {
+	let job = Job {
+		cwd: std::env::current_dir()?,
+		args: args.to_vec(),
+		env_vars: std::env::vars().collect()
+	};
+	let payload = job.encode();
+
+	let mut stream = UnixStream::connect(socket).map_err(|e| anyhow!("cannot connect to {socket:?}: {e}"))?;
+	let fds = [io::stdin().as_raw_fd(), io::stdout().as_raw_fd(), io::stderr().as_raw_fd()];
+	send_with_fds(&stream, &(payload.len() as u32).to_be_bytes(), &fds)?;
+	stream.write_all(&payload)?;
+
+	let mut status = [0u8; 4];
+	stream.read_exact(&mut status).map_err(|_| anyhow!("the server dropped the job without an exit status"))?;
+	Ok(i32::from_be_bytes(status))
+}
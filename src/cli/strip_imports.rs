@@ -0,0 +1,355 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::pef::{self, RelocOp};
+
+/// What a relocation adds to one word of a section
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Fixup {
+	Section(u32),
+	Import(u32)
+}
+
+/// Runs a section's relocation instructions without loading anything,
+/// noting down which fixup each word gets
+struct Flattener<'a> {
+	data: &'a [u16],
+	/// Size of the section, which relocations must stay within
+	size: u32,
+	position: u32,
+	import_index: u32,
+	sect_c: u32,
+	sect_d: u32,
+	fixups: Vec<(u32, Fixup)>
+}
+
+impl Flattener<'_> {
+	fn fix(&mut self, fixup: Fixup) -> Result<()> {
+		// this also stops runaway repeats, as no word is fixed up more than once
+		if u64::from(self.position) + 4 > u64::from(self.size) || self.fixups.len() as u32 >= self.size / 4 {
+			bail!("relocations go outside the section");
+		}
+		self.fixups.push((self.position, fixup));
+		self.skip(4)
+	}
+
+	fn skip(&mut self, amount: u32) -> Result<()> {
+		self.position = self.position.checked_add(amount).ok_or_else(|| anyhow!("relocations run past 4GB"))?;
+		Ok(())
+	}
+
+	fn run(&mut self, start: usize, end: usize) -> Result<()> {
+		let mut pos = start;
+		while pos < end {
+			let (op, length) = pef::decode_reloc(self.data, pos);
+			match op {
+				RelocOp::BySectDWithSkip { skip, count } => {
+					self.skip(skip * 4)?;
+					for _ in 0..count {
+						self.fix(Fixup::Section(self.sect_d))?;
+					}
+				}
+				RelocOp::BySectC { count } => for _ in 0..count {
+					self.fix(Fixup::Section(self.sect_c))?;
+				}
+				RelocOp::BySectD { count } => for _ in 0..count {
+					self.fix(Fixup::Section(self.sect_d))?;
+				}
+				RelocOp::TVector12 { count } => for _ in 0..count {
+					self.fix(Fixup::Section(self.sect_c))?;
+					self.fix(Fixup::Section(self.sect_d))?;
+					self.skip(4)?;
+				}
+				RelocOp::TVector8 { count } => for _ in 0..count {
+					self.fix(Fixup::Section(self.sect_c))?;
+					self.fix(Fixup::Section(self.sect_d))?;
+				}
+				RelocOp::VTable8 { count } => for _ in 0..count {
+					self.fix(Fixup::Section(self.sect_d))?;
+					self.skip(4)?;
+				}
+				RelocOp::ImportRun { count } => for _ in 0..count {
+					self.fix(Fixup::Import(self.import_index))?;
+					self.import_index = self.import_index.wrapping_add(1);
+				}
+				RelocOp::ByImport { index } => {
+					self.fix(Fixup::Import(index))?;
+					self.import_index = index.wrapping_add(1);
+				}
+				RelocOp::SetSectC { section } => self.sect_c = section,
+				RelocOp::SetSectD { section } => self.sect_d = section,
+				RelocOp::BySection { section } => self.fix(Fixup::Section(section))?,
+				RelocOp::IncrPosition { offset } => self.skip(offset)?,
+				RelocOp::Repeat { blocks, times } => {
+					let repeat_start = pos.checked_sub(blocks as usize)
+						.filter(|&repeat_start| repeat_start >= start)
+						.ok_or_else(|| anyhow!("relocation {pos:04X} repeats blocks from before the start"))?;
+					for _ in 0..times {
+						self.run(repeat_start, pos)?;
+					}
+				}
+				RelocOp::SetPosition { offset } => self.position = offset,
+				RelocOp::Unknown(block) => bail!("unknown relocation opcode {block:04X} at {pos:04X}")
+			}
+			pos += length;
+		}
+		Ok(())
+	}
+}
+
+/// Writes out relocations that apply `fixups` in order
+fn encode_fixups(fixups: &[(u32, Fixup)]) -> Result<Vec<u16>> {
+	let mut ops: Vec<RelocOp> = Vec::new();
+	let mut position = 0;
+	// sectC is left pointing at the first section, usually the code
+	let sect_c = 0;
+	let (mut sect_d, mut import_index) = (1, 0);
+
+	for &(offset, fixup) in fixups {
+		if offset > position {
+			ops.push(RelocOp::IncrPosition { offset: offset - position });
+		} else if offset < position {
+			ops.push(RelocOp::SetPosition { offset });
+		}
+		position = offset + 4;
+
+		// carry on the previous run if this word continues it
+		let op = match fixup {
+			Fixup::Section(section) if section == sect_d => RelocOp::BySectD { count: 1 },
+			Fixup::Section(section) if section == sect_c => RelocOp::BySectC { count: 1 },
+			Fixup::Section(section) => {
+				ops.push(RelocOp::SetSectD { section });
+				sect_d = section;
+				RelocOp::BySectD { count: 1 }
+			}
+			Fixup::Import(index) if index == import_index => RelocOp::ImportRun { count: 1 },
+			Fixup::Import(index) => RelocOp::ByImport { index }
+		};
+		if let Fixup::Import(index) = fixup {
+			import_index = index + 1;
+		}
+		match (ops.last_mut(), op) {
+			(Some(RelocOp::BySectC { count }), RelocOp::BySectC { .. }) |
+			(Some(RelocOp::BySectD { count }), RelocOp::BySectD { .. }) |
+			(Some(RelocOp::ImportRun { count }), RelocOp::ImportRun { .. }) => *count += 1,
+			_ => ops.push(op)
+		}
+	}
+
+	let mut data = Vec::new();
+	for op in ops {
+		data.extend(pef::encode_reloc(op).ok_or_else(|| anyhow!("cannot encode relocation {op:?}"))?);
+	}
+	Ok(data)
+}
+
+/// Removes the named imports from a loader, rewriting the relocations and
+/// exports that refer to them. Returns how many imports were removed.
+fn strip_loader(loader: &mut pef::Loader, section_sizes: &[u32], names: &[String]) -> Result<usize> {
+	if let Some(name) = names.iter().find(|&name| !loader.imported_symbols.iter().any(|sym| &sym.name == name)) {
+		bail!("{name} is not imported");
+	}
+
+	// work out where each import ends up, if anywhere
+	let mut new_index = Vec::new();
+	let mut kept = 0;
+	for sym in &loader.imported_symbols {
+		if names.contains(&sym.name) {
+			new_index.push(None);
+		} else {
+			new_index.push(Some(kept));
+			kept += 1;
+		}
+	}
+
+	for relocs in &mut loader.reloc_sections {
+		let section = relocs.section_index;
+		let size = section_sizes.get(section as usize).copied()
+			.ok_or_else(|| anyhow!("relocations for section #{section}, which doesn't exist"))?;
+		let mut flattener = Flattener { data: &relocs.data, size, position: 0, import_index: 0, sect_c: 0, sect_d: 1, fixups: Vec::new() };
+		flattener.run(0, relocs.data.len()).map_err(|e| anyhow!("section #{section}: {e}"))?;
+
+		let mut fixups = Vec::new();
+		for (offset, fixup) in flattener.fixups {
+			match fixup {
+				Fixup::Import(index) => match new_index.get(index as usize) {
+					Some(Some(index)) => fixups.push((offset, Fixup::Import(*index))),
+					Some(None) => {}
+					None => bail!("section #{section} refers to import {index}, which doesn't exist")
+				},
+				fixup => fixups.push((offset, fixup))
+			}
+		}
+		relocs.data = encode_fixups(&fixups)?;
+	}
+
+	// re-exports of a removed import have nothing left to refer to
+	let mut exports = Vec::new();
+	for mut sym in std::mem::take(&mut loader.exports.symbols) {
+		if let pef::ExportLocation::ReexportedImport(index) = sym.location() {
+			match new_index.get(index as usize).copied().flatten() {
+				Some(index) => sym.value = index,
+				None => continue
+			}
+		}
+		exports.push(sym);
+	}
+	loader.exports = pef::ExportTable::new(exports);
+
+	let removed = loader.imported_symbols.len() - kept as usize;
+	loader.imported_symbols.retain(|sym| !names.contains(&sym.name));
+
+	// drop libraries that only provided removed imports
+	let libraries = std::mem::take(&mut loader.imported_libraries);
+	for (index, mut lib) in libraries.into_iter().enumerate() {
+		let new_library = loader.imported_libraries.len();
+		let mut imports = loader.imported_symbols.iter_mut().filter(|sym| sym.library == index).peekable();
+		if !lib.imported_symbols.is_empty() && imports.peek().is_none() {
+			continue;
+		}
+		for sym in imports {
+			sym.library = new_library;
+		}
+		lib.imported_symbols = loader.imported_symbols.iter().filter(|sym| sym.library == new_library).cloned().collect();
+		loader.imported_libraries.push(lib);
+	}
+
+	Ok(removed)
+}
+
+/// Removes the named imports from a tool whose data fork is a PEF container,
+/// writing the result to `output`. Words that referred to a removed import are
+/// left as they are, as if it were a weak import that nothing provides, and
+/// libraries left with nothing to import are dropped. Returns how many imports
+/// were removed.
+pub fn strip_imports(path: &Path, output: &Path, names: &[String]) -> Result<usize> {
+	let mut pef = pef::read_pef(&std::fs::read(path)?)?;
+	let loader_index = pef.sections.iter()
+		.position(|s| s.section_kind == pef::SectionType::Loader)
+		.ok_or_else(|| anyhow!("PEF container has no loader section"))?;
+	let mut loader = pef::parse_loader(pef.sections[loader_index].packed_contents.as_deref().unwrap_or(&[]))?;
+
+	let section_sizes = pef.sections.iter().map(|s| s.total_size).collect::<Vec<_>>();
+	let removed = strip_loader(&mut loader, &section_sizes, names)?;
+	pef.sections[loader_index].set_contents(&pef::write_loader(&loader));
+
+	// copying brings the resource fork and Finder info along, on a Mac at least
+	if output.canonicalize().ok() != Some(path.canonicalize()?) {
+		std::fs::copy(path, output)?;
+	}
+	std::fs::write(output, pef::write_pef(&pef))?;
+	Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pef::{ExportTable, ExportedSymbol, ImportedLibrary, ImportedSymbol, Loader, RelocSection, SymbolClass};
+
+	fn encode(ops: &[RelocOp]) -> Vec<u16> {
+		ops.iter().flat_map(|&op| pef::encode_reloc(op).unwrap()).collect()
+	}
+
+	fn flatten(data: &[u16]) -> Result<Vec<(u32, Fixup)>> {
+		let mut flattener = Flattener { data, size: 0x1000, position: 0, import_index: 0, sect_c: 0, sect_d: 1, fixups: Vec::new() };
+		flattener.run(0, data.len())?;
+		Ok(flattener.fixups)
+	}
+
+	fn library(name: &str, imports: &[ImportedSymbol]) -> ImportedLibrary {
+		ImportedLibrary {
+			name: name.to_string(),
+			old_imp_version: 0,
+			current_version: 0,
+			imported_symbols: imports.to_vec(),
+			import_order: false,
+			is_weak: false
+		}
+	}
+
+	/// Imports a, b from LibA and c from LibB, with a run of relocations
+	/// that refers to each of them in turn and an export of c
+	fn loader() -> Loader {
+		let symbols = [(0, "a"), (0, "b"), (1, "c")].map(|(library, name)|
+			ImportedSymbol { library, name: name.to_string(), class: SymbolClass::TVect, weak: false });
+		let reexport = ExportedSymbol { name: "c".to_string(), class: SymbolClass::TVect, value: 2, section: -3, key: 0 };
+		Loader {
+			main_section: -1,
+			main_offset: 0,
+			init_section: -1,
+			init_offset: 0,
+			term_section: -1,
+			term_offset: 0,
+			imported_libraries: vec![library("LibA", &symbols[..2]), library("LibB", &symbols[2..])],
+			imported_symbols: symbols.to_vec(),
+			reloc_sections: vec![RelocSection {
+				section_index: 1,
+				data: encode(&[RelocOp::TVector8 { count: 1 }, RelocOp::ImportRun { count: 3 }])
+			}],
+			exports: ExportTable::new(vec![reexport])
+		}
+	}
+
+	#[test]
+	fn flattened_relocations_encode_the_same() {
+		let data = encode(&[
+			RelocOp::TVector8 { count: 2 },
+			RelocOp::ImportRun { count: 3 },
+			RelocOp::SetSectD { section: 2 },
+			RelocOp::BySectD { count: 1 },
+			RelocOp::IncrPosition { offset: 8 },
+			RelocOp::BySectC { count: 1 },
+			RelocOp::IncrPosition { offset: 4 },
+			RelocOp::Repeat { blocks: 2, times: 3 },
+			RelocOp::ByImport { index: 0 },
+			RelocOp::SetPosition { offset: 0x100 },
+			RelocOp::VTable8 { count: 2 }
+		]);
+		let fixups = flatten(&data).unwrap();
+		assert_eq!(fixups.len(), 4 + 3 + 1 + 4 + 1 + 2);
+		assert!(fixups[..4].iter().map(|f| f.1).eq([0, 1, 0, 1].map(Fixup::Section)));
+		assert!(fixups[4..7].iter().map(|f| f.1).eq([0, 1, 2].map(Fixup::Import)));
+		assert_eq!(fixups[7], (0x1C, Fixup::Section(2)));
+		assert!(fixups[8..12].iter().map(|f| f.0).eq([0x28, 0x30, 0x38, 0x40]));
+		assert_eq!(fixups[12], (0x48, Fixup::Import(0)));
+		assert_eq!(fixups[13..], [(0x100, Fixup::Section(2)), (0x108, Fixup::Section(2))]);
+
+		assert!(flatten(&encode_fixups(&fixups).unwrap()).unwrap() == fixups);
+	}
+
+	#[test]
+	fn relocations_outside_the_section() {
+		assert!(flatten(&encode(&[RelocOp::SetPosition { offset: 0x1000 }, RelocOp::BySectC { count: 1 }])).is_err());
+		assert!(flatten(&encode(&[RelocOp::Repeat { blocks: 1, times: 1 }])).is_err());
+		// every repeat covers the same words
+		let runaway = encode(&[RelocOp::BySectC { count: 1 }, RelocOp::SetPosition { offset: 0 }, RelocOp::Repeat { blocks: 3, times: 0x3FFFFF }]);
+		assert!(flatten(&runaway).is_err());
+	}
+
+	#[test]
+	fn strip_renumbers_the_rest() {
+		let mut loader = loader();
+		assert_eq!(strip_loader(&mut loader, &[0, 0x100], &["a".to_string()]).unwrap(), 1);
+
+		assert!(loader.imported_symbols.iter().map(|sym| (sym.library, sym.name.as_str())).eq([(0, "b"), (1, "c")]));
+		assert_eq!(loader.imported_libraries.len(), 2);
+		assert_eq!(loader.imported_libraries[0].imported_symbols.len(), 1);
+		assert_eq!(flatten(&loader.reloc_sections[0].data).unwrap()[2..], [(12, Fixup::Import(0)), (16, Fixup::Import(1))]);
+		assert_eq!(loader.exports.find("c").unwrap().location(), pef::ExportLocation::ReexportedImport(1));
+	}
+
+	#[test]
+	fn strip_drops_emptied_libraries_and_reexports() {
+		let mut loader = loader();
+		assert_eq!(strip_loader(&mut loader, &[0, 0x100], &["c".to_string()]).unwrap(), 1);
+
+		assert!(loader.imported_libraries.iter().map(|lib| lib.name.as_str()).eq(["LibA"]));
+		assert_eq!(loader.imported_symbols.len(), 2);
+		let fixups = flatten(&loader.reloc_sections[0].data).unwrap();
+		assert_eq!(fixups[2..], [(8, Fixup::Import(0)), (12, Fixup::Import(1))]);
+		assert!(loader.exports.symbols.is_empty());
+
+		assert!(strip_loader(&mut loader, &[0, 0x100], &["c".to_string()]).is_err());
+	}
+}
@@ -1,4 +1,4 @@
-use std::{fmt, time::SystemTime};
+use std::{fmt, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}, time::SystemTime};
 use binread::BinRead;
 use chrono::{prelude::*, Duration};
 
@@ -72,6 +72,8 @@ pub enum OSErr {
 	ResFileNotFound = -193,
 	AddResFailed = -194,
 	MapRead = -199,
+	CFragNoSymbol = -2802,
+	CFragNoLibrary = -2804,
 	GestaltUndefSelector = -5551
 }
 
@@ -79,4 +81,69 @@ impl OSErr {
 	pub fn to_u32(self) -> u32 {
 		self as i16 as i32 as u32
 	}
+
+	pub fn from_i16(code: i16) -> Option<OSErr> {
+		use OSErr::*;
+		[
+			NoError, NoSuchVolume, IOError, BadName, Eof, Position, FileNotFound, FileLocked,
+			FileBusy, DuplicateFilename, Param, RefNum, NotEnoughMemory, NilHandle, DirNotFound,
+			ResNotFound, ResFileNotFound, AddResFailed, MapRead, CFragNoSymbol, CFragNoLibrary,
+			GestaltUndefSelector
+		].into_iter().find(|&err| err as i16 == code)
+	}
+
+	/// Returns the name that Apple's headers give an error code, if we know it.
+	pub fn name_of(code: i16) -> Option<&'static str> {
+		let name = match code {
+			0 => "noErr",
+			-35 => "nsvErr",
+			-36 => "ioErr",
+			-37 => "bdNamErr",
+			-39 => "eofErr",
+			-40 => "posErr",
+			-43 => "fnfErr",
+			-45 => "fLckdErr",
+			-47 => "fBsyErr",
+			-48 => "dupFNErr",
+			-50 => "paramErr",
+			-51 => "rfNumErr",
+			-108 => "memFullErr",
+			-109 => "nilHandleErr",
+			-120 => "dirNFErr",
+			-192 => "resNotFound",
+			-193 => "resFNotFound",
+			-194 => "addResFailed",
+			-199 => "mapReadErr",
+			-2802 => "cfragNoSymbolErr",
+			-2804 => "cfragNoLibraryErr",
+			-5551 => "gestaltUndefSelectorErr",
+			_ => return None
+		};
+		Some(name)
+	}
+}
+
+/// Like `Rc<RefCell<T>>`, for things that several parts of a run hold on to,
+/// but it can be sent to another thread along with the rest of the run.
+/// Overlapping borrows panic just as they would with a RefCell.
+pub struct Shared<T>(Arc<RwLock<T>>);
+
+impl<T> Shared<T> {
+	pub fn new(value: T) -> Self {
+		Shared(Arc::new(RwLock::new(value)))
+	}
+
+	pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+		self.0.try_read().expect("already mutably borrowed")
+	}
+
+	pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+		self.0.try_write().expect("already borrowed")
+	}
+}
+
+impl<T> Clone for Shared<T> {
+	fn clone(&self) -> Self {
+		Shared(Arc::clone(&self.0))
+	}
 }
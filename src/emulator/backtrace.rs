@@ -0,0 +1,207 @@
+use std::io::{self, Write};
+
+use unicorn_engine::RegisterPPC;
+
+use crate::{linker, pef};
+
+use super::{EmuState, EmuUC, helpers::UnicornExtras};
+
+/// Number of shim calls remembered for crash reports
+pub(super) const RECENT_CALL_COUNT: usize = 16;
+const MAX_FRAMES: usize = 64;
+/// How far past an address to look for the end of its function
+const MAX_FUNCTION_SIZE: u32 = 0x10000;
+
+/// A call into HLE code, as remembered for crash reports
+#[derive(Clone, Copy)]
+pub(super) enum ShimCall {
+	Import(usize),
+	Dynamic(usize)
+}
+
+/// The bits of a traceback table that we care about
+struct Traceback {
+	/// Where the function starts, if the table says
+	start: Option<u32>,
+	name: Option<String>
+}
+
+/// Reads the traceback table following the function that contains `addr`.
+/// These begin with a zero word, which is never a valid instruction.
+fn find_traceback(uc: &EmuUC, exe: &linker::Executable, addr: u32) -> Option<Traceback> {
+	let region = exe.region_for(addr)?;
+	let region_end = region.address + region.size;
+	let limit = region_end.min(addr.saturating_add(MAX_FUNCTION_SIZE));
+
+	let mut table = addr & !3;
+	while uc.read_u32(table).ok()? != 0 {
+		table += 4;
+		if table + 8 > limit {
+			return None;
+		}
+	}
+
+	let flags1 = uc.read_u8(table + 6).ok()?;
+	let flags2 = uc.read_u8(table + 7).ok()?;
+	let fixed_parms = uc.read_u8(table + 10).ok()?;
+	let float_parms = uc.read_u8(table + 11).ok()? >> 1;
+	let has_tb_offset = (flags1 & 0x20) != 0;
+	let has_ctl = (flags1 & 0x08) != 0;
+	let int_handler = (flags2 & 0x80) != 0;
+	let name_present = (flags2 & 0x40) != 0;
+
+	// the optional fields come in a fixed order
+	let mut pos = table + 12;
+	if fixed_parms != 0 || float_parms != 0 {
+		pos += 4; // parminfo
+	}
+	let mut start = None;
+	if has_tb_offset {
+		start = table.checked_sub(uc.read_u32(pos).ok()?);
+		pos += 4;
+	}
+	if int_handler {
+		pos += 4;
+	}
+	if has_ctl {
+		let count = uc.read_u32(pos).ok()?;
+		pos += 4 + count * 4;
+	}
+
+	let mut name = None;
+	if name_present {
+		let length = uc.read_u16(pos).ok()?;
+		let bytes = uc.mem_read_as_vec((pos + 2).into(), length.into()).ok()?;
+		name = Some(String::from_utf8_lossy(&bytes).into_owned());
+	}
+
+	Some(Traceback { start, name })
+}
+
+/// Finds the closest exported function at or before `addr`.
+fn find_export(uc: &EmuUC, state: &EmuState, addr: u32) -> Option<(String, u32)> {
+	let mut best: Option<(String, u32)> = None;
+
+	for fragment in &state.fragments {
+		let section_address = |index: u16| fragment.sections.get(index as usize).copied().flatten();
+
+		for sym in &fragment.exports.symbols {
+			let code = match (sym.class, sym.location()) {
+				(pef::SymbolClass::TVect, pef::ExportLocation::Section(section, offset)) =>
+					section_address(section).and_then(|base| uc.read_u32(base + offset).ok()),
+				(pef::SymbolClass::Code, pef::ExportLocation::Section(section, offset)) =>
+					section_address(section).map(|base| base + offset),
+				_ => None
+			};
+			if let Some(code) = code.filter(|&code| code <= addr) {
+				if best.as_ref().map_or(true, |(_, best)| code > *best) {
+					best = Some((sym.name.clone(), code));
+				}
+			}
+		}
+	}
+
+	best
+}
+
+/// Finds the import shim that contains `addr`.
+fn find_shim(state: &EmuState, exe: &linker::Executable, addr: u32) -> Option<String> {
+	if addr >= exe.sc_thunk_addr && addr < exe.sc_thunk_addr + 16 {
+		return Some(String::from("<shim thunk>"));
+	}
+
+	state.imports.iter().find(|import| {
+		let (start, end) = match import.class {
+			pef::SymbolClass::TVect => (import.shim_address, import.shim_address + 12),
+			// the transition vector sits before the stub code
+			pef::SymbolClass::Code | pef::SymbolClass::Glue => (import.shim_address.wrapping_sub(12), import.shim_address + 20),
+			pef::SymbolClass::Data | pef::SymbolClass::TOC => return false
+		};
+		addr >= start && addr < end
+	}).map(|import| format!("<shim {}::{}>", import.library_name, import.name))
+}
+
+/// Describes a code address as well as we can.
+pub(super) fn symbolize(uc: &EmuUC, state: &EmuState, exe: &linker::Executable, addr: u32) -> String {
+	if let Some(shim) = find_shim(state, exe, addr) {
+		return shim;
+	}
+
+	let region = match exe.region_for(addr) {
+		Some(region) => format!(" ({} + {:X})", region.name, addr - region.address),
+		None => String::new()
+	};
+
+	let traceback = find_traceback(uc, exe, addr);
+	if let Some(Traceback { name: Some(name), start }) = traceback {
+		return match start {
+			Some(start) => format!("{name} + {:X}{region}", addr - start),
+			None => format!("{name}{region}")
+		};
+	}
+
+	if let Some((name, start)) = find_export(uc, state, addr) {
+		return format!("{name} + {:X}?{region}", addr - start);
+	}
+
+	if region.is_empty() { String::from("???") } else { region.trim_start().to_string() }
+}
+
+/// Walks the stack through the back chain, returning the address each frame
+/// will return to. The innermost function may not have saved LR yet, so its
+/// value is included if it doesn't match the first saved one.
+fn walk_stack(uc: &EmuUC, exe: &linker::Executable) -> Vec<u32> {
+	let stack_start = exe.stack_addr;
+	let stack_end = exe.stack_addr + exe.stack_size;
+
+	let mut frames = Vec::new();
+	let mut sp = uc.reg_read(RegisterPPC::R1).unwrap_or(0) as u32;
+	let lr = uc.reg_read(RegisterPPC::LR).unwrap_or(0) as u32;
+
+	while frames.len() < MAX_FRAMES {
+		let back_chain = match uc.read_u32(sp) {
+			Ok(next) if next > sp && next >= stack_start && next + 12 <= stack_end => next,
+			_ => break
+		};
+		let saved_lr = match uc.read_u32(back_chain + 8) {
+			Ok(saved_lr) => saved_lr,
+			Err(_) => break
+		};
+
+		if frames.is_empty() && saved_lr != lr {
+			frames.push(lr);
+		}
+		if saved_lr == 0 || saved_lr == exe.return_addr {
+			break;
+		}
+		frames.push(saved_lr);
+		sp = back_chain;
+	}
+
+	if frames.is_empty() && lr != 0 && lr != exe.return_addr {
+		frames.push(lr);
+	}
+
+	frames
+}
+
+/// Prints a symbolized backtrace and the most recent shim calls.
+pub(super) fn print(out: &mut dyn Write, uc: &EmuUC, state: &EmuState, exe: &linker::Executable) -> io::Result<()> {
+	let pc = uc.pc_read().unwrap_or(0) as u32;
+
+	writeln!(out, "Backtrace:")?;
+	writeln!(out, "  #0  {pc:08X} {}", symbolize(uc, state, exe, pc))?;
+	for (i, addr) in walk_stack(uc, exe).into_iter().enumerate() {
+		writeln!(out, "  #{:<2} {addr:08X} {}", i + 1, symbolize(uc, state, exe, addr))?;
+	}
+
+	if !state.recent_calls.is_empty() {
+		writeln!(out, "Last shim calls (oldest first):")?;
+		for &(lr, call) in &state.recent_calls {
+			let (library, name) = state.shim_call_names(call);
+			writeln!(out, "  {library}::{name} from {lr:08X} {}", symbolize(uc, state, exe, lr))?;
+		}
+	}
+
+	Ok(())
+}
@@ -1,6 +1,6 @@
 use crate::mac_roman;
 
-use super::{EmuState, EmuUC, FuncResult, UcResult, helpers::{ArgReader, UnicornExtras}};
+use super::{STDCLIB, EmuState, EmuUC, FuncResult, UcResult, helpers::{ArgReader, UnicornExtras}};
 
 const CTYPE_DATA: [u8; 256] = [
 	0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x28, 0x28, 0x28, 0x28, 0x28, 0x20, 0x20,
@@ -31,19 +31,15 @@ fn toupper(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> Fun
 	Ok(Some(mac_roman::to_upper(ch).into()))
 }
 
-pub(super) fn install_shims(uc: &mut EmuUC, state: &mut EmuState) -> UcResult<()> {
-	state.install_shim_function("tolower", tolower);
-	state.install_shim_function("toupper", toupper);
-
-	if let Some(p_ctype) = state.get_shim_addr(uc, "__p_CType")? {
-		debug!(target: "c_ctype", "__p_CType ptr is at: {p_ctype:08X}");
-
-		// The linker gives us 1024 bytes of free space in each symbol,
-		// so let's take advantage of this...
-		let ctype = p_ctype + 4;
-		uc.write_u32(p_ctype, ctype)?;
-		uc.mem_write(ctype.into(), &CTYPE_DATA)?;
-	}
-
+/// Points `__p_CType` at a copy of the character class table
+pub(super) fn init_p_ctype(uc: &mut EmuUC, state: &mut EmuState, p_ctype: u32) -> UcResult<()> {
+	let ctype = state.heap.new_ptr(uc, CTYPE_DATA.len() as u32)?;
+	uc.write_u32(p_ctype, ctype)?;
+	uc.mem_write(ctype.into(), &CTYPE_DATA)?;
 	Ok(())
 }
+
+pub(super) fn install_shims(state: &mut EmuState) {
+	state.install_shim_function(STDCLIB, "tolower", tolower);
+	state.install_shim_function(STDCLIB, "toupper", toupper);
+}
@@ -1,6 +1,6 @@
 use unicorn_engine::RegisterPPC;
 
-use super::{EmuState, EmuUC, FuncResult, helpers::ArgReader};
+use super::{MATH_LIB, EmuState, EmuUC, FuncResult, helpers::ArgReader};
 
 fn feclearexcept(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let excepts: u32 = reader.read1(uc)?;
@@ -18,6 +18,6 @@ fn fetestexcept(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("feclearexcept", feclearexcept);
-	state.install_shim_function("fetestexcept", fetestexcept);
+	state.install_shim_function(MATH_LIB, "feclearexcept", feclearexcept);
+	state.install_shim_function(MATH_LIB, "fetestexcept", fetestexcept);
 }
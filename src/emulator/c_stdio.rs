@@ -1,11 +1,13 @@
-use std::{ffi::CString, io::{Write, Read}, rc::Rc, cell::RefCell};
+use std::{ffi::CString, io::{Write, Read}};
 
-use crate::{mac_roman, filesystem::MacFile};
+use anyhow::{anyhow, Result};
 
-use super::{EmuState, EmuUC, FuncResult, UcResult, helpers::{ArgReader, UnicornExtras}};
+use crate::{common::Shared, mac_roman, filesystem::{FileSystem, MacFile}, snapshot::{SnapshotReader, SnapshotWriter}};
+
+use super::{STDCLIB, Console, EmuState, EmuUC, FuncResult, UcResult, helpers::{ArgReader, UnicornExtras}};
 
 pub(super) struct FileHandle {
-	file: Rc<RefCell<MacFile>>,
+	file: Shared<MacFile>,
 	position: usize
 }
 
@@ -24,9 +26,53 @@ impl CFile {
 		}
 	}
 
-	pub(super) fn generic_read(&mut self, buffer: &mut [u8]) -> u32 {
+	pub(super) fn save_snapshot(&self, w: &mut SnapshotWriter) {
+		match self {
+			CFile::StdIn => w.u8(0),
+			CFile::StdOut => w.u8(1),
+			CFile::StdErr => w.u8(2),
+			CFile::File(handle) => {
+				w.u8(3);
+				w.path(&handle.file.borrow().path);
+				w.count(handle.position);
+			}
+		}
+	}
+
+	pub(super) fn restore_snapshot(r: &mut SnapshotReader, filesystem: &mut FileSystem) -> Result<CFile> {
+		match r.u8()? {
+			0 => Ok(CFile::StdIn),
+			1 => Ok(CFile::StdOut),
+			2 => Ok(CFile::StdErr),
+			3 => {
+				let file = filesystem.get_file(&r.path()?)?;
+				Ok(CFile::File(FileHandle { file, position: r.count()? }))
+			}
+			kind => Err(anyhow!("unknown stdio file kind {kind}"))
+		}
+	}
+
+	/// Pushes out anything written so far. Returns false if that failed.
+	pub(super) fn flush(&mut self, console: &mut Console) -> bool {
+		let result: anyhow::Result<()> = match self {
+			CFile::StdIn => return true,
+			CFile::StdOut => console.stdout.flush().map_err(Into::into),
+			CFile::StdErr => console.stderr.flush().map_err(Into::into),
+			CFile::File(handle) => handle.file.borrow_mut().save_if_dirty()
+		};
+
+		match result {
+			Ok(()) => true,
+			Err(e) => {
+				error!(target: "stdio", "failed to flush file: {e:?}");
+				false
+			}
+		}
+	}
+
+	pub(super) fn generic_read(&mut self, console: &mut Console, buffer: &mut [u8]) -> u32 {
 		let read_result = match self {
-			CFile::StdIn => std::io::stdin().read(buffer),
+			CFile::StdIn => console.stdin.read(buffer),
 			CFile::StdOut | CFile::StdErr => return 0,
 			CFile::File(handle) => {
 				let file = handle.file.borrow();
@@ -47,11 +93,11 @@ impl CFile {
 		}
 	}
 
-	pub(super) fn generic_write(&mut self, buffer: &[u8]) -> u32 {
+	pub(super) fn generic_write(&mut self, console: &mut Console, buffer: &[u8]) -> u32 {
 		let write_result = match self {
 			CFile::StdIn => return 0,
-			CFile::StdOut => std::io::stdout().write(buffer),
-			CFile::StdErr => std::io::stderr().write(buffer),
+			CFile::StdOut => console.stdout.write(buffer),
+			CFile::StdErr => console.stderr.write(buffer),
 			CFile::File(handle) => {
 				let mut file = handle.file.borrow_mut();
 				let current_pos = handle.position;
@@ -60,6 +106,7 @@ impl CFile {
 					file.data_fork.resize(new_pos, 0);
 				}
 				file.data_fork[current_pos..new_pos].copy_from_slice(buffer);
+				file.set_dirty();
 				handle.position = new_pos;
 				return buffer.len() as u32;
 			}
@@ -230,10 +277,10 @@ fn setvbuf(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> Fun
 fn fclose(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let file: u32 = reader.read1(uc)?;
 
-	if state.stdio_files.contains_key(&file) {
-		state.stdio_files.remove(&file);
+	if let Some(mut f) = state.stdio_files.remove(&file) {
+		let flushed = f.flush(&mut state.console);
 		state.heap.dispose_ptr(uc, file)?;
-		Ok(Some(0))
+		Ok(Some(if flushed { 0 } else { 0xFFFFFFFF }))
 	} else {
 		warn!(target: "stdio", "fclose() on invalid file {file:08X}");
 		// TODO: this should be EOF, check what it is in MSL
@@ -244,9 +291,15 @@ fn fclose(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncR
 fn fflush(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let file: u32 = reader.read1(uc)?;
 
-	if state.stdio_files.contains_key(&file) {
-		// pretend we did something
-		Ok(Some(0))
+	if file == 0 {
+		// flush everything
+		let mut flushed = true;
+		for f in state.stdio_files.values_mut() {
+			flushed &= f.flush(&mut state.console);
+		}
+		Ok(Some(if flushed { 0 } else { 0xFFFFFFFF }))
+	} else if let Some(f) = state.stdio_files.get_mut(&file) {
+		Ok(Some(if f.flush(&mut state.console) { 0 } else { 0xFFFFFFFF }))
 	} else {
 		warn!(target: "stdio", "fflush() on invalid file {file:08X}");
 		// TODO: this should be EOF, check what it is in MSL
@@ -275,7 +328,7 @@ fn fopen(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncRe
 		}
 	};
 
-	let ptr = state.heap.new_ptr(uc, 0x18)?;
+	let ptr = state.heap.new_ptr(uc, FILE_SIZE)?;
 	// set _cnt to a negative value so getc() and putc() will always call a hooked function
 	uc.write_u32(ptr, 0xFFFFFFFE)?;
 	state.stdio_files.insert(ptr, CFile::File(FileHandle { file, position: 0 }));
@@ -291,9 +344,9 @@ fn fprintf(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> Func
 	match state.stdio_files.get_mut(&file) {
 		Some(f) => {
 			if f.is_terminal() {
-				Ok(Some(f.generic_write(&mac_roman::decode_buffer(&output, true))))
+				Ok(Some(f.generic_write(&mut state.console, &mac_roman::decode_buffer(&output, true))))
 			} else {
-				Ok(Some(f.generic_write(&output)))
+				Ok(Some(f.generic_write(&mut state.console, &output)))
 			}
 		}
 		None => {
@@ -304,11 +357,11 @@ fn fprintf(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> Func
 	}
 }
 
-fn printf(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
+fn printf(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let format: CString = reader.read1(uc)?;
 	trace!(target: "stdio", "printf({format:?}, ...)");
 	let output = internal_printf(uc, format.as_bytes(), reader)?;
-	Ok(Some(CFile::StdOut.generic_write(&mac_roman::decode_buffer(&output, true))))
+	Ok(Some(CFile::StdOut.generic_write(&mut state.console, &mac_roman::decode_buffer(&output, true))))
 }
 
 fn sprintf(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
@@ -329,9 +382,9 @@ fn vfprintf(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> Fun
 	match state.stdio_files.get_mut(&file) {
 		Some(f) => {
 			if f.is_terminal() {
-				Ok(Some(f.generic_write(&mac_roman::decode_buffer(&output, true))))
+				Ok(Some(f.generic_write(&mut state.console, &mac_roman::decode_buffer(&output, true))))
 			} else {
-				Ok(Some(f.generic_write(&output)))
+				Ok(Some(f.generic_write(&mut state.console, &output)))
 			}
 		}
 		None => {
@@ -351,7 +404,7 @@ fn fgets(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncRe
 			let mut byte = [0u8];
 
 			while buffer.len() < (max_size - 1) as usize {
-				if f.generic_read(&mut byte) == 0 {
+				if f.generic_read(&mut state.console, &mut byte) == 0 {
 					break;
 				}
 				buffer.push(byte[0]);
@@ -388,9 +441,9 @@ fn fputs(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncRe
 	match state.stdio_files.get_mut(&file) {
 		Some(f) => {
 			if f.is_terminal() {
-				Ok(Some(f.generic_write(&mac_roman::decode_buffer(&output, true))))
+				Ok(Some(f.generic_write(&mut state.console, &mac_roman::decode_buffer(&output, true))))
 			} else {
-				Ok(Some(f.generic_write(&output)))
+				Ok(Some(f.generic_write(&mut state.console, &output)))
 			}
 		}
 		None => {
@@ -408,9 +461,9 @@ fn fwrite(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncR
 	match state.stdio_files.get_mut(&file) {
 		Some(f) => {
 			if f.is_terminal() {
-				Ok(Some(f.generic_write(&mac_roman::decode_buffer(&output, true)) / size))
+				Ok(Some(f.generic_write(&mut state.console, &mac_roman::decode_buffer(&output, true)) / size))
 			} else {
-				Ok(Some(f.generic_write(&output) / size))
+				Ok(Some(f.generic_write(&mut state.console, &output) / size))
 			}
 		}
 		None => {
@@ -436,9 +489,9 @@ fn ftell(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncRe
 	}
 }
 
-fn putchar(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
+fn putchar(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let ch: u8 = reader.read1(uc)?;
-	print!("{}", mac_roman::decode_char(ch, true));
+	CFile::StdOut.generic_write(&mut state.console, &mac_roman::decode_buffer(&[ch], true));
 	Ok(Some(ch.into()))
 }
 
@@ -451,7 +504,7 @@ fn filbuf(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncR
 	// get a singular byte
 	let mut byte = [0u8];
 	if let Some(file) = state.stdio_files.get_mut(&file_ptr) {
-		if file.generic_read(&mut byte) == 1 {
+		if file.generic_read(&mut state.console, &mut byte) == 1 {
 			Ok(Some(byte[0] as u32))
 		} else {
 			Ok(Some(0xFFFFFFFF))
@@ -470,7 +523,7 @@ fn flsbuf(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncR
 	// write a singular byte
 	let byte = [ch];
 	if let Some(file) = state.stdio_files.get_mut(&file_ptr) {
-		if file.generic_write(&byte) == 1 {
+		if file.generic_write(&mut state.console, &byte) == 1 {
 			return Ok(Some(byte[0] as u32));
 		}
 	}
@@ -478,48 +531,62 @@ fn flsbuf(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncR
 	Ok(Some(0xFFFFFFFF))
 }
 
-pub(super) fn install_shims(uc: &mut EmuUC, state: &mut EmuState) -> UcResult<()> {
-	if let Some(iob) = state.get_shim_addr(uc, "_iob")? {
-		state.stdio_files.insert(iob, CFile::StdIn);
-		state.stdio_files.insert(iob + 0x18, CFile::StdOut);
-		state.stdio_files.insert(iob + 0x30, CFile::StdErr);
+/// Size of a FILE
+const FILE_SIZE: u32 = 0x18;
+/// `_iob` holds _NFILE (20) FILEs
+pub(super) const IOB_SIZE: u32 = 20 * FILE_SIZE;
 
-		// for stuff using write(), etc
-		state.stdio_files.insert(0, CFile::StdIn);
-		state.stdio_files.insert(1, CFile::StdOut);
-		state.stdio_files.insert(2, CFile::StdErr);
+pub(super) fn init_iob(_uc: &mut EmuUC, state: &mut EmuState, iob: u32) -> UcResult<()> {
+	state.stdio_files.insert(iob, CFile::StdIn);
+	state.stdio_files.insert(iob + FILE_SIZE, CFile::StdOut);
+	state.stdio_files.insert(iob + FILE_SIZE * 2, CFile::StdErr);
+
+	// for stuff using write(), etc
+	state.stdio_files.insert(0, CFile::StdIn);
+	state.stdio_files.insert(1, CFile::StdOut);
+	state.stdio_files.insert(2, CFile::StdErr);
+	Ok(())
+}
+
+/// Flushes every stream and closes the files, as exit() does.
+pub(super) fn close_all(state: &mut EmuState) {
+	for file in state.stdio_files.values_mut() {
+		file.flush(&mut state.console);
 	}
+	state.stdio_files.retain(|_, file| file.is_terminal());
+}
 
+pub(super) fn install_shims(state: &mut EmuState) {
 	// remove
 	// rename
 	// tmpnam
 	// tmpfile
 	// setbuf
-	state.install_shim_function("setvbuf", setvbuf);
-	state.install_shim_function("fclose", fclose);
-	state.install_shim_function("fflush", fflush);
-	state.install_shim_function("fopen", fopen);
+	state.install_shim_function(STDCLIB, "setvbuf", setvbuf);
+	state.install_shim_function(STDCLIB, "fclose", fclose);
+	state.install_shim_function(STDCLIB, "fflush", fflush);
+	state.install_shim_function(STDCLIB, "fopen", fopen);
 	// freopen
-	state.install_shim_function("fprintf", fprintf);
+	state.install_shim_function(STDCLIB, "fprintf", fprintf);
 	// fscanf
-	state.install_shim_function("printf", printf);
+	state.install_shim_function(STDCLIB, "printf", printf);
 	// scanf
-	state.install_shim_function("sprintf", sprintf);
+	state.install_shim_function(STDCLIB, "sprintf", sprintf);
 	// sscanf
-	state.install_shim_function("vfprintf", vfprintf);
+	state.install_shim_function(STDCLIB, "vfprintf", vfprintf);
 	// vprintf
 	// vsprintf
 	// fgetc
-	state.install_shim_function("fgets", fgets);
+	state.install_shim_function(STDCLIB, "fgets", fgets);
 	// fputc
-	state.install_shim_function("fputs", fputs);
+	state.install_shim_function(STDCLIB, "fputs", fputs);
 	// gets
 	// puts
 	// ungetc
 	// fread
-	state.install_shim_function("fwrite", fwrite);
+	state.install_shim_function(STDCLIB, "fwrite", fwrite);
 	// fgetpos
-	state.install_shim_function("ftell", ftell);
+	state.install_shim_function(STDCLIB, "ftell", ftell);
 	// fsetpos
 	// fseek
 	// rewind
@@ -528,12 +595,10 @@ pub(super) fn install_shims(uc: &mut EmuUC, state: &mut EmuState) -> UcResult<()
 	// getc
 	// putc
 	// getchar
-	state.install_shim_function("putchar", putchar);
+	state.install_shim_function(STDCLIB, "putchar", putchar);
 	// feof
 	// ferror
 
-	state.install_shim_function("_filbuf", filbuf);
-	state.install_shim_function("_flsbuf", flsbuf);
-
-	Ok(())
+	state.install_shim_function(STDCLIB, "_filbuf", filbuf);
+	state.install_shim_function(STDCLIB, "_flsbuf", flsbuf);
 }
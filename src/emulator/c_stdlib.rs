@@ -2,105 +2,7 @@ use std::ffi::CString;
 
 use unicorn_engine::RegisterPPC;
 
-use super::{EmuState, EmuUC, FuncResult, UcResult, helpers::{ArgReader, UnicornExtras}};
-
-const QSORT_CODE: &[u32] = &[
-	// Offset 0
-	0x7C0802A6, // mflr     r0
-	0x90010008, // stw      r0,8(SP)
-	0x9421FFC0, // stwu     SP,-64(SP)
-	0x7C882378, // mr       r8,r4
-	0x7CA42B78, // mr       r4,r5
-	0x7CC73378, // mr       r7,r6
-	0x38C8FFFF, // subi     r6,r8,1
-	0x38A00000, // li       r5,0
-	0x48000015, // bl       ._qsort (+0x14)
-	0x80010048, // lwz      r0,72(SP)
-	0x38210040, // addi     SP,SP,64
-	0x7C0803A6, // mtlr     r0
-	0x4E800020, // blr
-	// Offset 0x34
-	0x7C0802A6, // mflr     r0
-	0xBEE1FFDC, // stmw     r23,-36(SP)
-	0x90010008, // stw      r0,8(SP)
-	0x9421FFA0, // stwu     SP,-96(SP)
-	0x7C7B1B78, // mr       r27,r3
-	0x7C9C2378, // mr       r28,r4
-	0x7CBD2B78, // mr       r29,r5
-	0x7CDE3378, // mr       r30,r6
-	0x7CFF3B78, // mr       r31,r7
-	0x2C1D0000, // cmpwi    r29,0
-	0x3B1DFFFF, // subi     r24,r29,1
-	0x418000E8, // blt      *+232
-	0x7C1DF000, // cmpw     r29,r30
-	0x408000E0, // bge      *+224
-	0x7FB7EB78, // mr       r23,r29
-	0x7F5EE1D6, // mullw    r26,r30,r28
-	0x48000060, // b        *+96
-	0x7F37E1D6, // mullw    r25,r23,r28
-	0x7FECFB78, // mr       r12,r31
-	0x7C7BCA14, // add      r3,r27,r25
-	0x7C9BD214, // add      r4,r27,r26
-	0x480000D5, // bl       .__ptr_glue (+0xD4)
-	0x80410014, // lwz      r2,20(SP)
-	0x2C030000, // cmpwi    r3,0
-	0x4181003C, // bgt      *+60
-	0x3B180001, // addi     r24,r24,1
-	0x7C18E1D6, // mullw    r0,r24,r28
-	0x7CDB0214, // add      r6,r27,r0
-	0x7CBBCA14, // add      r5,r27,r25
-	0x38600000, // li       r3,0
-	0x7F8903A6, // mtctr    r28
-	0x281C0000, // cmplwi   r28,$0000
-	0x4081001C, // ble      *+28
-	0x7C8618AE, // lbzx     r4,r6,r3
-	0x7C0518AE, // lbzx     r0,r5,r3
-	0x7C0619AE, // stbx     r0,r6,r3
-	0x7C8519AE, // stbx     r4,r5,r3
-	0x38630001, // addi     r3,r3,1
-	0x4200FFEC, // bdnz     *-20
-	0x3AF70001, // addi     r23,r23,1
-	0x7C17F040, // cmplw    r23,r30
-	0x4180FFA0, // blt      *-96
-	0x3B180001, // addi     r24,r24,1
-	0x7C78E1D6, // mullw    r3,r24,r28
-	0x7C1EE1D6, // mullw    r0,r30,r28
-	0x7CDB1A14, // add      r6,r27,r3
-	0x7CBB0214, // add      r5,r27,r0
-	0x38600000, // li       r3,0
-	0x7F8903A6, // mtctr    r28
-	0x281C0000, // cmplwi   r28,$0000
-	0x4081001C, // ble      *+28
-	0x7C8618AE, // lbzx     r4,r6,r3
-	0x7C0518AE, // lbzx     r0,r5,r3
-	0x7C0619AE, // stbx     r0,r6,r3
-	0x7C8519AE, // stbx     r4,r5,r3
-	0x38630001, // addi     r3,r3,1
-	0x4200FFEC, // bdnz     *-20
-	0x7F63DB78, // mr       r3,r27
-	0x7F84E378, // mr       r4,r28
-	0x7FA5EB78, // mr       r5,r29
-	0x7FE7FB78, // mr       r7,r31
-	0x38D8FFFF, // subi     r6,r24,1
-	0x4BFFFF09, // bl       ._qsort (-0xF8)
-	0x7F63DB78, // mr       r3,r27
-	0x7F84E378, // mr       r4,r28
-	0x7FC6F378, // mr       r6,r30
-	0x7FE7FB78, // mr       r7,r31
-	0x38B80001, // addi     r5,r24,1
-	0x4BFFFEF1, // bl       ._qsort (-0x110)
-	0x80010068, // lwz      r0,104(SP)
-	0x38210060, // addi     SP,SP,96
-	0x7C0803A6, // mtlr     r0
-	0xBAE1FFDC, // lmw      r23,-36(SP)
-	0x4E800020, // blr
-	// Offset 0x15C
-	0x800C0000, // lwz      r0,0(r12)
-	0x90410014, // stw      r2,20(SP)
-	0x7C0903A6, // mtctr    r0
-	0x804C0004, // lwz      r2,0(r12)
-	0x4E800420, // bctr
-];
+use super::{STDCLIB, EmuState, EmuUC, FuncResult, UcResult, helpers::{ArgReader, UnicornExtras}};
 
 fn atoi(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let s: CString = reader.read1(uc)?;
@@ -227,22 +129,142 @@ fn realloc(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> Func
 	}
 }
 
-fn atexit(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
-	let _func: u32 = reader.read1(uc)?;
-	// Not implemented, but we pretend it is to satisfy the program
+fn abort(uc: &mut EmuUC, state: &mut EmuState, _reader: &mut ArgReader) -> FuncResult {
+	info!(target: "stdlib", "abort()");
+	state.exit_status = Some(1);
+	state.quick_exit = true;
+	uc.emu_stop()?;
+
+	Ok(None)
+}
+
+fn atexit(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
+	let func: u32 = reader.read1(uc)?;
+	state.atexit_handlers.push(func);
 	Ok(Some(0))
 }
 
+/// Calls the functions registered with atexit(), most recent first.
+fn run_atexit_handlers(uc: &mut EmuUC, state: &mut EmuState) -> UcResult<()> {
+	while let Some(handler) = state.atexit_handlers.pop() {
+		debug!(target: "stdlib", "Calling atexit handler {handler:08X}");
+		state.call_guest(uc, handler, &[])?;
+		if state.exit_status.is_some() {
+			// it called exit(), which took care of the rest
+			break;
+		}
+	}
+	Ok(())
+}
+
 fn exit(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let status: i32 = reader.read1(uc)?;
 
 	info!(target: "stdlib", "exit({status})");
+	run_atexit_handlers(uc, state)?;
+	state.exit_status.get_or_insert(status);
+	uc.emu_stop()?;
+
+	Ok(None)
+}
+
+fn exit_immediately(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
+	let status: i32 = reader.read1(uc)?;
+
+	info!(target: "stdlib", "_exit({status})");
 	state.exit_status = Some(status);
+	state.quick_exit = true;
 	uc.emu_stop()?;
 
 	Ok(None)
 }
 
+/// Merge sort where the comparison can fail, so that a guest function can be
+/// used for it. `in_order` says whether the first item can go before the second.
+fn merge_sort<T: Copy>(items: &mut [T], in_order: &mut impl FnMut(T, T) -> UcResult<bool>) -> UcResult<()> {
+	if items.len() < 2 {
+		return Ok(());
+	}
+
+	let mid = items.len() / 2;
+	merge_sort(&mut items[..mid], in_order)?;
+	merge_sort(&mut items[mid..], in_order)?;
+
+	let mut merged = Vec::with_capacity(items.len());
+	let (mut i, mut j) = (0, mid);
+	while i < mid && j < items.len() {
+		if in_order(items[i], items[j])? {
+			merged.push(items[i]);
+			i += 1;
+		} else {
+			merged.push(items[j]);
+			j += 1;
+		}
+	}
+	merged.extend_from_slice(&items[i..mid]);
+	merged.extend_from_slice(&items[j..]);
+	items.copy_from_slice(&merged);
+	Ok(())
+}
+
+fn qsort(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
+	let (base, count, size, compare): (u32, u32, u32, u32) = reader.read4(uc)?;
+	let Some(length) = count.checked_mul(size).filter(|&length| base.checked_add(length).is_some()) else {
+		warn!(target: "stdlib", "qsort() of {count} elements of {size} bytes at {base:08X} runs off the end of memory");
+		return Ok(None);
+	};
+
+	// Sort the indices, so the comparator always sees the original array,
+	// then move everything into place at once. The whole array fits, so no
+	// element's address can overflow.
+	let mut order = (0..count).collect::<Vec<_>>();
+	merge_sort(&mut order, &mut |a, b| {
+		if state.exit_status.is_some() {
+			return Ok(true);
+		}
+		let result = state.call_guest(uc, compare, &[base + a * size, base + b * size])?;
+		Ok(result as i32 <= 0)
+	})?;
+
+	if state.exit_status.is_none() {
+		let original = uc.mem_read_as_vec(base.into(), length as usize)?;
+		let mut sorted = Vec::with_capacity(original.len());
+		for index in order {
+			let start = (index * size) as usize;
+			sorted.extend_from_slice(&original[start..start + size as usize]);
+		}
+		uc.mem_write(base.into(), &sorted)?;
+	}
+
+	Ok(None)
+}
+
+fn bsearch(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
+	let (key, base, count, size, compare): (u32, u32, u32, u32, u32) = reader.read5(uc)?;
+	if count.checked_mul(size).and_then(|length| base.checked_add(length)).is_none() {
+		warn!(target: "stdlib", "bsearch() of {count} elements of {size} bytes at {base:08X} runs off the end of memory");
+		return Ok(Some(0));
+	}
+
+	let (mut low, mut high) = (0, count);
+	while low < high {
+		let mid = low + (high - low) / 2;
+		let element = base + mid * size;
+		let result = state.call_guest(uc, compare, &[key, element])? as i32;
+		if state.exit_status.is_some() {
+			break;
+		}
+
+		match result.cmp(&0) {
+			std::cmp::Ordering::Less => high = mid,
+			std::cmp::Ordering::Greater => low = mid + 1,
+			std::cmp::Ordering::Equal => return Ok(Some(element))
+		}
+	}
+
+	Ok(Some(0))
+}
+
 fn getenv(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let name: CString = reader.read1(uc)?;
 	if let Ok(name) = name.into_string() {
@@ -324,55 +346,56 @@ pub(super) fn setup_environment(uc: &mut EmuUC, state: &mut EmuState, args: &[St
 	}
 
 	// Arguments
-	if let Some(int_env) = state.get_shim_addr(uc, "_IntEnv")? {
-		debug!(target: "stdlib", "_IntEnv ptr is at: {int_env:08X}");
+	let argv = state.heap.new_ptr(uc, (args.len() * 4) as u32)?;
+	for (i, arg) in args.iter().enumerate() {
+		let arg_ptr = state.heap.new_ptr(uc, arg.as_bytes().len() as u32 + 1)?;
 
-		let argv = state.heap.new_ptr(uc, (args.len() * 4) as u32)?;
-		uc.write_u32(int_env + 2, args.len() as u32)?;
-		uc.write_u32(int_env + 6, argv)?;
+		uc.write_u32(argv + (i as u32) * 4, arg_ptr)?;
+		uc.write_c_string(arg_ptr, arg.as_bytes())?;
+	}
+	state.argc = args.len() as u32;
+	state.argv = argv;
 
-		// _IntEnv also contains EnvP but I'm not sure what the format is for that yet
+	Ok(())
+}
 
-		for (i, arg) in args.iter().enumerate() {
-			let arg_ptr = state.heap.new_ptr(uc, arg.as_bytes().len() as u32 + 1)?;
+/// Size of the IntEnv structure
+pub(super) const INT_ENV_SIZE: u32 = 14;
 
-			uc.write_u32(argv + (i as u32) * 4, arg_ptr)?;
-			uc.write_c_string(arg_ptr, arg.as_bytes())?;
-		}
-	}
-
-	// Special setjmp
-	if let Some(target_for_exit) = state.get_shim_addr(uc, "__target_for_exit")? {
-		// we're ok to ignore this for now
-		// we just need the extra space allocated at this location
-		debug!(target: "stdlib", "__target_for_exit ptr is at {target_for_exit:08X}");
-	}
+pub(super) fn init_int_env(uc: &mut EmuUC, state: &mut EmuState, int_env: u32) -> UcResult<()> {
+	uc.write_u32(int_env + 2, state.argc)?;
+	uc.write_u32(int_env + 6, state.argv)?;
 
+	// _IntEnv also contains EnvP but I'm not sure what the format is for that yet
 	Ok(())
 }
 
-pub(super) fn install_shims(uc: &mut EmuUC, state: &mut EmuState) -> UcResult<()> {
+/// Size of a jmp_buf, which `__target_for_exit` is
+pub(super) const JMP_BUF_SIZE: u32 = 256;
+
+pub(super) fn install_shims(state: &mut EmuState) {
 	// atof
-	state.install_shim_function("atoi", atoi);
-	state.install_shim_function("atol", atoi);
+	state.install_shim_function(STDCLIB, "atoi", atoi);
+	state.install_shim_function(STDCLIB, "atol", atoi);
 	// strtod
-	state.install_shim_function("strtol", strtol);
+	state.install_shim_function(STDCLIB, "strtol", strtol);
 	// strtoul
     // strtoll (?)
 	// strtoull (?)
 	// rand
 	// srand
-	state.install_shim_function("calloc", calloc);
-	state.install_shim_function("free", free);
-	state.install_shim_function("malloc", malloc);
-	state.install_shim_function("realloc", realloc);
-	// abort
-	state.install_shim_function("atexit", atexit);
-	state.install_shim_function("exit", exit);
-	state.install_shim_function("getenv", getenv);
+	state.install_shim_function(STDCLIB, "calloc", calloc);
+	state.install_shim_function(STDCLIB, "free", free);
+	state.install_shim_function(STDCLIB, "malloc", malloc);
+	state.install_shim_function(STDCLIB, "realloc", realloc);
+	state.install_shim_function(STDCLIB, "abort", abort);
+	state.install_shim_function(STDCLIB, "atexit", atexit);
+	state.install_shim_function(STDCLIB, "exit", exit);
+	state.install_shim_function(STDCLIB, "_exit", exit_immediately);
+	state.install_shim_function(STDCLIB, "getenv", getenv);
 	// system
-	// bsearch
-	state.install_shim_function("abs", abs);
+	state.install_shim_function(STDCLIB, "bsearch", bsearch);
+	state.install_shim_function(STDCLIB, "abs", abs);
 	// labs
 	// div
 	// ldiv
@@ -384,21 +407,11 @@ pub(super) fn install_shims(uc: &mut EmuUC, state: &mut EmuState) -> UcResult<()
 
 	// This isn't actually in stdlib.h, but I didn't feel like creating
 	// c_signal.rs just for one stub. Fight me.
-	state.install_shim_function("signal", signal);
+	state.install_shim_function(STDCLIB, "signal", signal);
 
 	// ... Same for setjmp.h.
-	state.install_shim_function("__setjmp", setjmp);
-	state.install_shim_function("longjmp", longjmp);
-
-	// qsort() needs special handling.
-	// We implement it in PowerPC so it can invoke a callback function
-	if let Some(qsort) = state.get_shim_addr(uc, "qsort")? {
-		let qsort_code = state.heap.new_ptr(uc, (QSORT_CODE.len() * 4) as u32)?;
-		uc.write_u32(qsort, qsort_code)?;
-		for (i, insn) in QSORT_CODE.iter().enumerate() {
-			uc.write_u32(qsort_code + 4 * (i as u32), *insn)?;
-		}
-	}
+	state.install_shim_function(STDCLIB, "__setjmp", setjmp);
+	state.install_shim_function(STDCLIB, "longjmp", longjmp);
 
-	Ok(())
+	state.install_shim_function(STDCLIB, "qsort", qsort);
 }
@@ -1,6 +1,6 @@
 use std::ffi::CString;
 
-use super::{EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
+use super::{STDCLIB, EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
 
 fn memset(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let (ptr, byte, len): (u32, u8, u32) = reader.read3(uc)?;
@@ -199,27 +199,27 @@ fn strtok(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncR
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("memset", memset);
+	state.install_shim_function(STDCLIB, "memset", memset);
 	// memchr
-	state.install_shim_function("memcmp", memcmp);
-	state.install_shim_function("memcpy", memcpy);
-	state.install_shim_function("memmove", memmove);
-
-	state.install_shim_function("strlen", strlen);
-	state.install_shim_function("strcpy", strcpy);
-	state.install_shim_function("strncpy", strncpy);
-	state.install_shim_function("strcat", strcat);
+	state.install_shim_function(STDCLIB, "memcmp", memcmp);
+	state.install_shim_function(STDCLIB, "memcpy", memcpy);
+	state.install_shim_function(STDCLIB, "memmove", memmove);
+
+	state.install_shim_function(STDCLIB, "strlen", strlen);
+	state.install_shim_function(STDCLIB, "strcpy", strcpy);
+	state.install_shim_function(STDCLIB, "strncpy", strncpy);
+	state.install_shim_function(STDCLIB, "strcat", strcat);
 	// strncat
-	state.install_shim_function("strcmp", strcmp);
-	state.install_shim_function("strncmp", strncmp);
+	state.install_shim_function(STDCLIB, "strcmp", strcmp);
+	state.install_shim_function(STDCLIB, "strncmp", strncmp);
 	// strcoll
 	// strxfrm
-	state.install_shim_function("strchr", strchr);
-	state.install_shim_function("strrchr", strrchr);
+	state.install_shim_function(STDCLIB, "strchr", strchr);
+	state.install_shim_function(STDCLIB, "strrchr", strrchr);
 	// strpbrk
-	state.install_shim_function("strspn", strspn);
+	state.install_shim_function(STDCLIB, "strspn", strspn);
 	// strcspn
-	state.install_shim_function("strtok", strtok);
+	state.install_shim_function(STDCLIB, "strtok", strtok);
 	// strstr
 	// strerror
 	// strcasecmp
@@ -1,6 +1,6 @@
 use chrono::Utc;
 
-use super::{EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
+use super::{STDCLIB, EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
 
 fn time(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let time_ptr: u32 = reader.read1(uc)?;
@@ -12,5 +12,5 @@ fn time(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncRe
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("time", time);
+	state.install_shim_function(STDCLIB, "time", time);
 }
@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use crate::common::OSErr;
+
+use super::FuncResult;
+
+/// Settings for `--trace-calls`
+#[derive(Default)]
+pub struct CallTraceOptions {
+	/// File to write the trace to, or None for stderr
+	pub output: Option<PathBuf>,
+	/// Glob patterns for calls to trace. A pattern containing `::` is matched
+	/// against `Library::symbol`, anything else just against the symbol.
+	/// If there are none, everything is traced.
+	pub filters: Vec<String>
+}
+
+/// Writes a line for every HLE call that passes the filters.
+pub(super) struct CallTracer {
+	output: Box<dyn Write + Send>,
+	filters: Vec<String>
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+	match (pattern.first(), text.first()) {
+		(None, None) => true,
+		(Some(b'*'), _) => glob_match(&pattern[1..], text) || (!text.is_empty() && glob_match(pattern, &text[1..])),
+		(Some(b'?'), Some(_)) => glob_match(&pattern[1..], &text[1..]),
+		(Some(p), Some(t)) if p == t => glob_match(&pattern[1..], &text[1..]),
+		_ => false
+	}
+}
+
+/// Formats a shim's return value, naming it if it looks like an OSErr.
+fn describe_result(result: &FuncResult) -> String {
+	match result {
+		Ok(Some(value)) => {
+			let signed = *value as i32;
+			match OSErr::name_of(signed as i16).filter(|_| signed < 0 && signed >= i16::MIN.into()) {
+				Some(name) => format!("{signed} ({name})"),
+				None => format!("0x{value:X}")
+			}
+		}
+		Ok(None) => String::from("(nothing)"),
+		Err(e) => format!("error {e:?}")
+	}
+}
+
+impl CallTracer {
+	pub(super) fn new(options: &CallTraceOptions) -> io::Result<CallTracer> {
+		let output: Box<dyn Write + Send> = match &options.output {
+			Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+			None => Box::new(io::stderr())
+		};
+
+		Ok(CallTracer {
+			output,
+			filters: options.filters.clone()
+		})
+	}
+
+	/// Checks whether calls to this function should be traced.
+	pub(super) fn wants(&self, library: &str, name: &str) -> bool {
+		if self.filters.is_empty() {
+			return true;
+		}
+
+		let qualified = format!("{library}::{name}");
+		self.filters.iter().any(|filter| {
+			let text = if filter.contains("::") { qualified.as_str() } else { name };
+			glob_match(filter.as_bytes(), text.as_bytes())
+		})
+	}
+
+	pub(super) fn record(&mut self, qualified_name: &str, lr: u32, args: &[String], result: &FuncResult) {
+		let line = format!("[lr={lr:08X}] {qualified_name}({}) = {}", args.join(", "), describe_result(result));
+		self.write_line(&line);
+	}
+
+	pub(super) fn record_unimplemented(&mut self, qualified_name: &str, lr: u32) {
+		let line = format!("[lr={lr:08X}] {qualified_name}(...) = unimplemented");
+		self.write_line(&line);
+	}
+
+	fn write_line(&mut self, line: &str) {
+		if let Err(e) = writeln!(self.output, "{line}") {
+			warn!(target: "emulator", "Cannot write call trace: {e}");
+		}
+	}
+
+	pub(super) fn flush(&mut self) {
+		let _ = self.output.flush();
+	}
+}
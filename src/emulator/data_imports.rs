@@ -0,0 +1,74 @@
+use super::{EmuState, EmuUC, UcResult, c_ctype, c_stdio, c_stdlib, mac_quickdraw};
+
+type DataInit = fn(&mut EmuUC, &mut EmuState, u32) -> UcResult<()>;
+
+/// A data symbol that one of the emulated libraries exports
+struct DataImport {
+	name: &'static str,
+	size: u32,
+	/// Fills in the symbol's contents; if there isn't one, it's left zeroed
+	init: Option<DataInit>
+}
+
+const DATA_IMPORTS: &[DataImport] = &[
+	// StdCLib
+	DataImport { name: "_iob", size: c_stdio::IOB_SIZE, init: Some(c_stdio::init_iob) },
+	DataImport { name: "__p_CType", size: 4, init: Some(c_ctype::init_p_ctype) },
+	DataImport { name: "_IntEnv", size: c_stdlib::INT_ENV_SIZE, init: Some(c_stdlib::init_int_env) },
+	DataImport { name: "errno", size: 4, init: None },
+	DataImport { name: "__C_phase", size: 4, init: None },
+	DataImport { name: "__target_for_exit", size: c_stdlib::JMP_BUF_SIZE, init: None },
+	// InterfaceLib
+	DataImport { name: "qd", size: mac_quickdraw::QD_GLOBALS_SIZE, init: Some(mac_quickdraw::init_qd) }
+];
+
+fn find(name: &str) -> Option<&'static DataImport> {
+	DATA_IMPORTS.iter().find(|import| import.name == name)
+}
+
+/// Returns the real size of a known data symbol. The linker is given this
+/// so it can reserve the right amount of space.
+pub fn size_of(name: &str) -> Option<u32> {
+	find(name).map(|import| import.size)
+}
+
+/// Sets up the contents of every known data symbol that the executable imports,
+/// claiming them so that they aren't rebound to a shared library.
+pub(super) fn initialize(uc: &mut EmuUC, state: &mut EmuState) -> UcResult<()> {
+	for index in 0..state.imports.len() {
+		let symbol = &state.imports[index];
+		if !matches!(symbol.class, crate::pef::SymbolClass::Data | crate::pef::SymbolClass::TOC) {
+			continue;
+		}
+
+		let import = match find(&symbol.name) {
+			Some(import) => import,
+			None => continue
+		};
+		let addr = symbol.shim_address;
+		state.imports[index].claimed = true;
+
+		debug!(target: "emulator", "{} is at {addr:08X} ({} bytes)", import.name, import.size);
+		if let Some(init) = import.init {
+			init(uc, state, addr)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Creates a known data symbol that's being looked up at runtime rather than
+/// imported. Returns None if we don't know of it.
+pub(super) fn allocate(uc: &mut EmuUC, state: &mut EmuState, name: &str) -> UcResult<Option<u32>> {
+	let import = match find(name) {
+		Some(import) => import,
+		None => return Ok(None)
+	};
+
+	let addr = state.heap.new_ptr(uc, import.size)?;
+	debug!(target: "emulator", "{name} is at {addr:08X} ({} bytes, allocated dynamically)", import.size);
+	if let Some(init) = import.init {
+		init(uc, state, addr)?;
+	}
+	Ok(Some(addr))
+}
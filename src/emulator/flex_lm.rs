@@ -1,12 +1,28 @@
 use std::ffi::CString;
-use crate::emulator::{EmuState, EmuUC, FuncResult};
+use anyhow::Result;
+use crate::emulator::{ANY_LIBRARY, EmuState, EmuUC, FuncResult};
 use crate::emulator::helpers::{ArgReader, UnicornExtras};
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 
 pub(super) struct Checkout {
     feature: CString,
     version: CString
 }
 
+impl Checkout {
+    pub(super) fn save_snapshot(&self, w: &mut SnapshotWriter) {
+        w.bytes(self.feature.as_bytes());
+        w.bytes(self.version.as_bytes());
+    }
+
+    pub(super) fn restore_snapshot(r: &mut SnapshotReader) -> Result<Checkout> {
+        Ok(Checkout {
+            feature: CString::new(r.bytes()?)?,
+            version: CString::new(r.bytes()?)?
+        })
+    }
+}
+
 fn flex_init(_uc: &mut EmuUC, _state: &mut EmuState, _reader: &mut ArgReader) -> FuncResult {
     debug!(target: "FlexLM", "Flex_Init()");
     Ok(None)
@@ -38,7 +54,7 @@ fn lp_checkin(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> F
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-    state.install_shim_function("Flex_Init", flex_init);
-    state.install_shim_function("lp_checkout", lp_checkout);
-    state.install_shim_function("lp_checkin", lp_checkin);
+    state.install_shim_function(ANY_LIBRARY, "Flex_Init", flex_init);
+    state.install_shim_function(ANY_LIBRARY, "lp_checkout", lp_checkout);
+    state.install_shim_function(ANY_LIBRARY, "lp_checkin", lp_checkin);
 }
@@ -0,0 +1,396 @@
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use unicorn_engine::RegisterPPC;
+
+use super::EmuUC;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// How many instructions to run between checks for an interrupt from GDB
+const INTERRUPT_CHECK_INTERVAL: u32 = 0x10000;
+
+/// What GDB wants to happen after a stop
+enum Resume {
+	Continue,
+	Step,
+	Detach,
+	Kill
+}
+
+/// A GDB remote serial protocol stub. Register numbers follow GDB's
+/// powerpc:common layout: r0-r31, f0-f31, pc, msr, cr, lr, ctr, xer, fpscr.
+pub(super) struct GdbStub {
+	/// None once GDB has gone away
+	stream: Option<TcpStream>,
+	no_ack: bool,
+	breakpoints: HashSet<u32>,
+	stepping: bool,
+	/// Set while GDB is waiting for us to report a stop
+	running: bool,
+	last_signal: u8,
+	instruction_count: u32
+}
+
+fn register_info(number: usize) -> Option<(i32, usize)> {
+	match number {
+		0 ..= 31 => Some((RegisterPPC::R0 as i32 + number as i32, 4)),
+		32 ..= 63 => Some((RegisterPPC::FPR0 as i32 + (number - 32) as i32, 8)),
+		64 => Some((RegisterPPC::PC as i32, 4)),
+		65 => Some((RegisterPPC::MSR as i32, 4)),
+		66 => Some((RegisterPPC::CR as i32, 4)),
+		67 => Some((RegisterPPC::LR as i32, 4)),
+		68 => Some((RegisterPPC::CTR as i32, 4)),
+		69 => Some((RegisterPPC::XER as i32, 4)),
+		70 => Some((RegisterPPC::FPSCR as i32, 4)),
+		_ => None
+	}
+}
+
+const REGISTER_COUNT: usize = 71;
+
+fn hex_encode(data: &[u8]) -> String {
+	data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hex_decode(text: &str) -> Option<Vec<u8>> {
+	if text.len() % 2 != 0 {
+		return None;
+	}
+	(0 .. text.len()).step_by(2)
+		.map(|i| u8::from_str_radix(text.get(i .. i + 2)?, 16).ok())
+		.collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+	u32::from_str_radix(text, 16).ok()
+}
+
+fn read_register(uc: &EmuUC, number: usize) -> Option<Vec<u8>> {
+	let (reg, size) = register_info(number)?;
+	let value = uc.reg_read(reg).ok()?;
+	Some(if size == 8 { value.to_be_bytes().to_vec() } else { (value as u32).to_be_bytes().to_vec() })
+}
+
+fn write_register(uc: &mut EmuUC, number: usize, data: &[u8]) -> bool {
+	let (reg, size) = match register_info(number) {
+		Some(info) => info,
+		None => return false
+	};
+	if data.len() != size {
+		return false;
+	}
+	let value = data.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+	uc.reg_write(reg, value).is_ok()
+}
+
+impl GdbStub {
+	/// Waits for GDB to connect to the given port on localhost.
+	pub(super) fn listen(port: u16) -> io::Result<GdbStub> {
+		let listener = TcpListener::bind(("127.0.0.1", port))?;
+		eprintln!("Waiting for GDB to connect on 127.0.0.1:{port}");
+		let (stream, peer) = listener.accept()?;
+		stream.set_nodelay(true)?;
+		info!(target: "gdb", "Connection from {peer}");
+
+		Ok(GdbStub {
+			stream: Some(stream),
+			no_ack: false,
+			breakpoints: HashSet::new(),
+			// stop before the first instruction so breakpoints can be set
+			stepping: true,
+			running: false,
+			last_signal: SIGTRAP,
+			instruction_count: 0
+		})
+	}
+
+	fn disconnect(&mut self) {
+		self.stream = None;
+		self.breakpoints.clear();
+		self.stepping = false;
+	}
+
+	fn read_byte(&mut self) -> Option<u8> {
+		let mut byte = [0u8];
+		match self.stream.as_mut()?.read_exact(&mut byte) {
+			Ok(()) => Some(byte[0]),
+			Err(e) => {
+				warn!(target: "gdb", "Lost connection to GDB: {e}");
+				self.disconnect();
+				None
+			}
+		}
+	}
+
+	fn write_raw(&mut self, data: &[u8]) {
+		if let Some(stream) = self.stream.as_mut() {
+			if let Err(e) = stream.write_all(data) {
+				warn!(target: "gdb", "Lost connection to GDB: {e}");
+				self.disconnect();
+			}
+		}
+	}
+
+	fn read_packet(&mut self) -> Option<String> {
+		loop {
+			// skip acks and anything else until the start of a packet
+			while self.read_byte()? != b'$' {}
+
+			let mut data = Vec::new();
+			let mut checksum = 0u8;
+			loop {
+				let byte = self.read_byte()?;
+				if byte == b'#' {
+					break;
+				}
+				checksum = checksum.wrapping_add(byte);
+				data.push(byte);
+			}
+
+			let expected = [self.read_byte()?, self.read_byte()?];
+			let expected = std::str::from_utf8(&expected).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+			if self.no_ack {
+				// nothing to acknowledge
+			} else if expected == Some(checksum) {
+				self.write_raw(b"+");
+			} else {
+				self.write_raw(b"-");
+				continue;
+			}
+
+			let packet = String::from_utf8_lossy(&data).into_owned();
+			trace!(target: "gdb", "<- {packet}");
+			return Some(packet);
+		}
+	}
+
+	fn send_packet(&mut self, data: &str) {
+		trace!(target: "gdb", "-> {data}");
+		let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+		let packet = format!("${data}#{checksum:02x}");
+
+		for _ in 0..3 {
+			self.write_raw(packet.as_bytes());
+			if self.no_ack || self.stream.is_none() {
+				return;
+			}
+			match self.read_byte() {
+				Some(b'+') | None => return,
+				Some(_) => continue
+			}
+		}
+	}
+
+	/// Called before each instruction runs, stopping there if GDB asked for it.
+	pub(super) fn on_instruction(&mut self, uc: &mut EmuUC, addr: u32) {
+		if self.stream.is_none() {
+			return;
+		}
+
+		if self.stepping || self.breakpoints.contains(&addr) {
+			self.stop(uc, SIGTRAP);
+			return;
+		}
+
+		self.instruction_count = self.instruction_count.wrapping_add(1);
+		if self.instruction_count % INTERRUPT_CHECK_INTERVAL == 0 && self.interrupt_requested() {
+			self.stop(uc, SIGINT);
+		}
+	}
+
+	/// Checks whether GDB sent a break (Ctrl-C) while the guest was running.
+	fn interrupt_requested(&mut self) -> bool {
+		let stream = match self.stream.as_mut() {
+			Some(stream) => stream,
+			None => return false
+		};
+
+		let mut byte = [0u8];
+		let _ = stream.set_nonblocking(true);
+		let result = stream.read(&mut byte);
+		let _ = stream.set_nonblocking(false);
+		matches!(result, Ok(1)) && byte[0] == 0x03
+	}
+
+	fn stop(&mut self, uc: &mut EmuUC, signal: u8) {
+		match self.serve(uc, signal) {
+			Resume::Continue => self.stepping = false,
+			Resume::Step => self.stepping = true,
+			Resume::Detach => self.disconnect(),
+			Resume::Kill => {
+				self.disconnect();
+				if let Ok(mut state) = uc.get_data().try_borrow_mut() {
+					state.exit_status = Some(1);
+				}
+				let _ = uc.emu_stop();
+			}
+		}
+	}
+
+	/// Tells GDB that the guest crashed. Execution can't carry on from here,
+	/// but GDB can still look around before it goes away.
+	pub(super) fn report_fault(&mut self, uc: &mut EmuUC) {
+		if self.stream.is_none() {
+			return;
+		}
+
+		match self.serve(uc, SIGSEGV) {
+			Resume::Continue | Resume::Step => self.send_packet(&format!("X{SIGSEGV:02x}")),
+			Resume::Detach | Resume::Kill => {}
+		}
+		self.disconnect();
+	}
+
+	/// Tells GDB that the program has finished.
+	pub(super) fn report_exit(&mut self, code: i32) {
+		if self.stream.is_some() {
+			self.send_packet(&format!("W{:02x}", code as u8));
+			self.disconnect();
+		}
+	}
+
+	/// Handles requests from GDB until it tells us to resume.
+	fn serve(&mut self, uc: &mut EmuUC, signal: u8) -> Resume {
+		self.last_signal = signal;
+		if self.running {
+			self.running = false;
+			self.send_packet(&format!("S{signal:02x}"));
+		}
+
+		while let Some(packet) = self.read_packet() {
+			if let Some(resume) = self.handle_packet(uc, &packet) {
+				if matches!(resume, Resume::Continue | Resume::Step) {
+					self.running = true;
+				}
+				return resume;
+			}
+		}
+
+		// the connection dropped
+		Resume::Detach
+	}
+
+	fn handle_packet(&mut self, uc: &mut EmuUC, packet: &str) -> Option<Resume> {
+		let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+		let reply = match command {
+			"?" => format!("S{:02x}", self.last_signal),
+			"g" => {
+				let mut data = Vec::new();
+				for number in 0..REGISTER_COUNT {
+					data.extend(read_register(uc, number).unwrap_or_default());
+				}
+				hex_encode(&data)
+			}
+			"G" => {
+				let data = hex_decode(args).unwrap_or_default();
+				let mut pos = 0;
+				for number in 0..REGISTER_COUNT {
+					let size = register_info(number).map_or(0, |(_, size)| size);
+					if let Some(value) = data.get(pos .. pos + size) {
+						write_register(uc, number, value);
+					}
+					pos += size;
+				}
+				String::from("OK")
+			}
+			"p" => {
+				match usize::from_str_radix(args, 16).ok().and_then(|n| read_register(uc, n)) {
+					Some(value) => hex_encode(&value),
+					None => String::from("E01")
+				}
+			}
+			"P" => {
+				let written = args.split_once('=').and_then(|(number, value)| {
+					let number = usize::from_str_radix(number, 16).ok()?;
+					Some(write_register(uc, number, &hex_decode(value)?))
+				});
+				String::from(if written == Some(true) { "OK" } else { "E01" })
+			}
+			"m" => {
+				let request = args.split_once(',').and_then(|(addr, len)| Some((parse_hex(addr)?, parse_hex(len)?)));
+				match request.and_then(|(addr, len)| uc.mem_read_as_vec(addr.into(), len as usize).ok()) {
+					Some(data) => hex_encode(&data),
+					None => String::from("E14")
+				}
+			}
+			"M" => {
+				let request = args.split_once(':').and_then(|(range, data)| {
+					let (addr, _) = range.split_once(',')?;
+					Some((parse_hex(addr)?, hex_decode(data)?))
+				});
+				match request.map(|(addr, data)| uc.mem_write(addr.into(), &data)) {
+					Some(Ok(())) => String::from("OK"),
+					_ => String::from("E14")
+				}
+			}
+			"Z" | "z" => {
+				let mut fields = args.split(',');
+				let kind = fields.next();
+				match (kind, fields.next().and_then(parse_hex)) {
+					(Some("0"), Some(addr)) => {
+						if command == "Z" {
+							self.breakpoints.insert(addr);
+						} else {
+							self.breakpoints.remove(&addr);
+						}
+						String::from("OK")
+					}
+					// hardware breakpoints and watchpoints aren't supported
+					_ => String::new()
+				}
+			}
+			"c" | "s" => {
+				if let Some(addr) = parse_hex(args) {
+					let _ = uc.set_pc(addr.into());
+				}
+				return Some(if command == "c" { Resume::Continue } else { Resume::Step });
+			}
+			"v" => {
+				if args == "Cont?" {
+					String::from("vCont;c;C;s;S")
+				} else if let Some(actions) = args.strip_prefix("Cont;") {
+					// we only have the one thread, so the first action is all that matters
+					let action = actions.split(';').next().unwrap_or("");
+					return Some(if action.starts_with('s') || action.starts_with('S') { Resume::Step } else { Resume::Continue });
+				} else {
+					String::new()
+				}
+			}
+			"D" => {
+				self.send_packet("OK");
+				return Some(Resume::Detach);
+			}
+			"k" => return Some(Resume::Kill),
+			"H" | "T" => String::from("OK"),
+			"Q" if packet == "QStartNoAckMode" => {
+				// the reply still gets acknowledged, so only switch after sending it
+				self.send_packet("OK");
+				self.no_ack = true;
+				return None;
+			}
+			"q" | "Q" => self.handle_query(packet),
+			_ => String::new()
+		};
+
+		self.send_packet(&reply);
+		None
+	}
+
+	fn handle_query(&mut self, packet: &str) -> String {
+		let name = packet.split([':', ',']).next().unwrap_or(packet);
+		match name {
+			"qSupported" => String::from("PacketSize=4000;QStartNoAckMode+"),
+			"qAttached" => String::from("1"),
+			"qC" => String::from("QC1"),
+			"qfThreadInfo" => String::from("m1"),
+			"qsThreadInfo" => String::from("l"),
+			"qOffsets" => String::from("Text=0;Data=0;Bss=0"),
+			_ => String::new()
+		}
+	}
+}
@@ -1,7 +1,13 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::Result;
+
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
+
 use super::{EmuUC, UcResult, helpers::UnicornExtras};
 
 use bitvec::prelude::*;
-use unicorn_engine::unicorn_const::Permission;
+use unicorn_engine::{RegisterPPC, unicorn_const::Permission};
 
 const FREE_FLAG: u32 = 0x80000000;
 const SIZE_OF_HEADER: u32 = 0x10;
@@ -11,6 +17,14 @@ const HDR_BLOCK_SIZE: u32 = 4;
 const HDR_PREV: u32 = 8;
 const HDR_NEXT: u32 = 0xC;
 
+/// Guard bytes on each side of an allocation when sanitizing
+const RED_ZONE_SIZE: u32 = 0x10;
+const RED_ZONE_BYTE: u8 = 0xFD;
+const FREED_BYTE: u8 = 0xDD;
+/// How much freed memory is held back from reuse before the oldest block
+/// is really freed
+const QUARANTINE_LIMIT: u32 = 1024 * 1024;
+
 pub struct Heap {
 	used_handles: BitVec,
 	region_start: u32,
@@ -20,7 +34,64 @@ pub struct Heap {
 	arena_start: u32,
 	arena_size: u32,
 	first_block: u32,
-	last_block: u32
+	last_block: u32,
+	/// Bytes currently handed out, not counting headers
+	allocated: u32,
+	sanitizer: Option<Sanitizer>
+}
+
+#[derive(Clone, Copy)]
+struct Allocation {
+	size: u32,
+	/// Where the guest asked for it
+	lr: u32
+}
+
+#[derive(Clone, Copy)]
+struct Freed {
+	allocation: Allocation,
+	free_lr: u32
+}
+
+/// Extra bookkeeping for catching heap misuse. With this enabled, every
+/// pointer block is surrounded by red zones, and freed blocks are poisoned
+/// and kept out of circulation for a while so stray writes show up.
+#[derive(Default)]
+struct Sanitizer {
+	live: HashMap<u32, Allocation>,
+	/// Freed pointers that haven't been handed back to the allocator yet,
+	/// oldest first
+	quarantine: VecDeque<u32>,
+	quarantined_bytes: u32,
+	freed: HashMap<u32, Freed>,
+	disposed_handles: HashMap<u32, Freed>,
+	problems: u32
+}
+
+impl Sanitizer {
+	fn report(&mut self, message: String) {
+		self.problems += 1;
+		error!(target: "heap", "{message}");
+	}
+}
+
+fn caller(uc: &EmuUC) -> u32 {
+	uc.reg_read(RegisterPPC::LR).unwrap_or(0) as u32
+}
+
+/// Writes the guard bytes before `ptr` and after its `size` bytes, up to
+/// the end of the block.
+fn fill_red_zones(uc: &mut EmuUC, block: u32, ptr: u32, size: u32) -> UcResult<()> {
+	let block_end = block + uc.read_u32(block + HDR_BLOCK_SIZE)?;
+	uc.mem_write((block + SIZE_OF_HEADER).into(), &[RED_ZONE_BYTE; RED_ZONE_SIZE as usize])?;
+	uc.mem_write((ptr + size).into(), &vec![RED_ZONE_BYTE; (block_end - ptr - size) as usize])?;
+	Ok(())
+}
+
+/// Returns true if anything in the range isn't `expected`.
+fn is_damaged(uc: &EmuUC, start: u32, end: u32, expected: u8) -> UcResult<bool> {
+	let data = uc.mem_read_as_vec(start.into(), (end - start) as usize)?;
+	Ok(data.iter().any(|&b| b != expected))
 }
 
 impl Heap {
@@ -36,10 +107,31 @@ impl Heap {
 			arena_start: region_start + handles_size,
 			arena_size: region_size - handles_size,
 			first_block: 0,
-			last_block: 0
+			last_block: 0,
+			allocated: 0,
+			sanitizer: None
 		}
 	}
 
+	pub(super) fn allocated(&self) -> u32 {
+		self.allocated
+	}
+
+	/// Turns on red zones, quarantine and checks on every free. Must be
+	/// called before anything is allocated.
+	pub(super) fn enable_sanitizer(&mut self) {
+		assert_eq!(self.first_block, 0, "heap is already in use");
+		self.sanitizer = Some(Sanitizer::default());
+	}
+
+	fn red_zone(&self) -> u32 {
+		if self.sanitizer.is_some() { RED_ZONE_SIZE } else { 0 }
+	}
+
+	fn block_for_ptr(&self, ptr: u32) -> u32 {
+		ptr - SIZE_OF_HEADER - self.red_zone()
+	}
+
 	pub(super) fn init(&mut self, uc: &mut EmuUC) -> UcResult<()> {
 		self.used_handles.clear();
 		self.used_handles.resize(self.handle_count as usize, false);
@@ -59,7 +151,33 @@ impl Heap {
 		Ok(())
 	}
 
-	fn get_handle_index_if_valid(&self, uc: &EmuUC, handle: u32) -> Option<usize> {
+	/// Records the heap's bookkeeping; the blocks themselves live in guest memory.
+	pub(super) fn save_snapshot(&self, w: &mut SnapshotWriter) {
+		w.u32(self.region_start);
+		w.u32(self.region_size);
+		w.u32(self.handle_count);
+		w.count(self.used_handles.len());
+		for used in self.used_handles.iter() {
+			w.bool(*used);
+		}
+		w.u32(self.first_block);
+		w.u32(self.last_block);
+		w.u32(self.allocated);
+	}
+
+	pub(super) fn restore_snapshot(r: &mut SnapshotReader) -> Result<Heap> {
+		let (region_start, region_size, handle_count) = (r.u32()?, r.u32()?, r.u32()?);
+		let mut heap = Heap::new(region_start, region_size, handle_count);
+		for _ in 0..r.count()? {
+			heap.used_handles.push(r.bool()?);
+		}
+		heap.first_block = r.u32()?;
+		heap.last_block = r.u32()?;
+		heap.allocated = r.u32()?;
+		Ok(heap)
+	}
+
+	fn handle_index(&self, handle: u32) -> Option<usize> {
 		if handle >= self.handles_start && handle < (self.handles_start + self.handle_count * 4) {
 			if (handle & 3) == 0 {
 				let handle_index = (handle - self.handles_start) as usize / 4;
@@ -68,12 +186,18 @@ impl Heap {
 				}
 			}
 		}
-
-		let lr = uc.reg_read(unicorn_engine::RegisterPPC::LR).unwrap();
-		error!(target: "heap", "Invalid handle {handle:08X}! LR={lr:08X}");
 		None
 	}
 
+	fn get_handle_index_if_valid(&self, uc: &EmuUC, handle: u32) -> Option<usize> {
+		let handle_index = self.handle_index(handle);
+		if handle_index.is_none() {
+			let lr = caller(uc);
+			error!(target: "heap", "Invalid handle {handle:08X}! LR={lr:08X}");
+		}
+		handle_index
+	}
+
 	pub(super) fn new_handle(&mut self, uc: &mut EmuUC, size: u32) -> UcResult<u32> {
 		let handle_index = match self.used_handles.first_zero() {
 			Some(i) => i,
@@ -90,6 +214,9 @@ impl Heap {
 		}
 
 		self.used_handles.set(handle_index, true);
+		if let Some(sanitizer) = self.sanitizer.as_mut() {
+			sanitizer.disposed_handles.remove(&handle);
+		}
 
 		uc.write_u32(handle, backing_ptr)?;
 		Ok(handle)
@@ -98,8 +225,27 @@ impl Heap {
 	pub(super) fn dispose_handle(&mut self, uc: &mut EmuUC, handle: u32) -> UcResult<()> {
 		if handle == 0 { return Ok(()); }
 
+		let is_handle = self.handle_index(handle).is_some();
+		if let Some(sanitizer) = self.sanitizer.as_mut().filter(|_| !is_handle) {
+			let lr = caller(uc);
+			let message = match sanitizer.disposed_handles.get(&handle) {
+				Some(freed) => format!(
+					"DisposeHandle({handle:08X}) at LR={lr:08X} on a handle that was already disposed at LR={:08X} (allocated at LR={:08X})",
+					freed.free_lr, freed.allocation.lr),
+				None => format!("DisposeHandle({handle:08X}) at LR={lr:08X} on something that isn't a handle (no allocation site)")
+			};
+			sanitizer.report(message);
+			return Ok(());
+		}
+
 		let handle_index = self.get_handle_index_if_valid(uc, handle).expect("disposing invalid handle");
 		let backing_ptr = uc.read_u32(handle)?;
+		if let Some(sanitizer) = self.sanitizer.as_mut() {
+			if let Some(&allocation) = sanitizer.live.get(&backing_ptr) {
+				let freed = Freed { allocation, free_lr: caller(uc) };
+				sanitizer.disposed_handles.insert(handle, freed);
+			}
+		}
 		self.dispose_ptr(uc, backing_ptr)?;
 		uc.write_u32(handle, 0)?;
 		self.used_handles.set(handle_index, false);
@@ -151,17 +297,29 @@ impl Heap {
 	}
 
 	pub(super) fn new_ptr(&mut self, uc: &mut EmuUC, size: u32) -> UcResult<u32> {
-		let aligned_size = (size + 0xF) & !0xF;
+		// When sanitizing, the header's size also covers the red zones
+		let stored_size = size + 2 * self.red_zone();
+		let aligned_size = (stored_size + 0xF) & !0xF;
+
+		let mut found = self.find_free_block(uc, aligned_size)?;
+		if found.is_none() && self.drain_quarantine(uc)? {
+			found = self.find_free_block(uc, aligned_size)?;
+		}
 
-		if let Some(block) = self.find_free_block(uc, aligned_size)? {
-			let ptr = block + SIZE_OF_HEADER;
-			uc.write_u32(block + HDR_USER_SIZE, size)?;
+		if let Some(block) = found {
+			let ptr = block + SIZE_OF_HEADER + self.red_zone();
+			uc.write_u32(block + HDR_USER_SIZE, stored_size)?;
 			self.shrink_used_block_by_splitting(uc, block)?;
 
 			for i in 0..size {
 				uc.write_u8(ptr + i, 0)?;
 			}
 
+			self.allocated += size;
+			if let Some(sanitizer) = self.sanitizer.as_mut() {
+				fill_red_zones(uc, block, ptr, size)?;
+				sanitizer.live.insert(ptr, Allocation { size, lr: caller(uc) });
+			}
 			Ok(ptr)
 		} else {
 			error!(target: "heap", "Failed to allocate {size} bytes!");
@@ -196,7 +354,20 @@ impl Heap {
 	}
 
 	pub(super) fn dispose_ptr(&mut self, uc: &mut EmuUC, ptr: u32) -> UcResult<()> {
+		if self.sanitizer.is_some() {
+			return self.quarantine_ptr(uc, ptr);
+		}
+
 		let block = ptr - SIZE_OF_HEADER;
+		let user_size = uc.read_u32(block + HDR_USER_SIZE)?;
+		if (user_size & FREE_FLAG) == 0 {
+			self.allocated -= user_size;
+		}
+		self.release_block(uc, block)
+	}
+
+	/// Returns a block to the allocator, merging it with free neighbours.
+	fn release_block(&mut self, uc: &mut EmuUC, block: u32) -> UcResult<()> {
 		let prev = uc.read_u32(block + HDR_PREV)?;
 		let next = uc.read_u32(block + HDR_NEXT)?;
 
@@ -213,18 +384,23 @@ impl Heap {
 	}
 
 	pub(super) fn get_ptr_size(&self, uc: &EmuUC, ptr: u32) -> UcResult<u32> {
-		let block = ptr - SIZE_OF_HEADER;
+		let block = self.block_for_ptr(ptr);
 		let size = uc.read_u32(block + HDR_USER_SIZE)?;
-		Ok(size)
+		Ok(size - 2 * self.red_zone())
 	}
 
 	pub(super) fn set_ptr_size(&mut self, uc: &mut EmuUC, ptr: u32, new_size: u32) -> UcResult<bool> {
-		let block = ptr - SIZE_OF_HEADER;
-		let current_size = uc.read_u32(block + HDR_USER_SIZE)?;
+		let block = self.block_for_ptr(ptr);
+		let overhead = 2 * self.red_zone();
+		let current_size = uc.read_u32(block + HDR_USER_SIZE)? - overhead;
 
 		// The simplest option
 		if new_size == current_size { return Ok(true); }
 
+		if self.sanitizer.is_some() {
+			self.check_red_zones(uc, ptr, "resized")?;
+		}
+
 		// Occupy all room up to the next used block
 		let next = uc.read_u32(block + HDR_NEXT)?;
 		if next != 0 && self.is_block_free(uc, next)? {
@@ -233,8 +409,9 @@ impl Heap {
 
 		// Can we fit the desired size in?
 		let max_size = uc.read_u32(block + HDR_BLOCK_SIZE)?;
-		let success = if new_size < (max_size - SIZE_OF_HEADER) {
-			uc.write_u32(block + HDR_USER_SIZE, new_size)?;
+		let success = if new_size + overhead < (max_size - SIZE_OF_HEADER) {
+			uc.write_u32(block + HDR_USER_SIZE, new_size + overhead)?;
+			self.allocated = self.allocated - current_size + new_size;
 
 			if new_size > current_size {
 				for i in current_size..new_size {
@@ -250,9 +427,156 @@ impl Heap {
 		// Give space back
 		self.shrink_used_block_by_splitting(uc, block)?;
 
+		if let Some(sanitizer) = self.sanitizer.as_mut() {
+			// The block may have grown or shrunk, so the trailing red zone moves
+			let size = if success { new_size } else { current_size };
+			fill_red_zones(uc, block, ptr, size)?;
+			if let Some(allocation) = sanitizer.live.get_mut(&ptr) {
+				allocation.size = size;
+			}
+		}
+
 		Ok(success)
 	}
 
+	/// Sanitizing version of `dispose_ptr`: checks the pointer and its red
+	/// zones, then poisons the block and holds on to it for a while.
+	fn quarantine_ptr(&mut self, uc: &mut EmuUC, ptr: u32) -> UcResult<()> {
+		if ptr == 0 { return Ok(()); }
+
+		let lr = caller(uc);
+		let in_arena = ptr >= self.arena_start && ptr < self.arena_start + self.arena_size;
+		let sanitizer = self.sanitizer.as_mut().unwrap();
+		if !sanitizer.live.contains_key(&ptr) {
+			let message = match sanitizer.freed.get(&ptr) {
+				Some(freed) => format!(
+					"DisposePtr({ptr:08X}) at LR={lr:08X} on a block that was already freed at LR={:08X} (allocated at LR={:08X})",
+					freed.free_lr, freed.allocation.lr),
+				None if !in_arena => format!("DisposePtr({ptr:08X}) at LR={lr:08X} on an address outside the heap (no allocation site)"),
+				None => format!("DisposePtr({ptr:08X}) at LR={lr:08X} on an address that isn't the start of a block (no allocation site)")
+			};
+			sanitizer.report(message);
+			return Ok(());
+		}
+
+		self.check_red_zones(uc, ptr, "freed")?;
+
+		let block = self.block_for_ptr(ptr);
+		let block_size = uc.read_u32(block + HDR_BLOCK_SIZE)?;
+		let poison = vec![FREED_BYTE; (block_size - SIZE_OF_HEADER) as usize];
+		uc.mem_write((block + SIZE_OF_HEADER).into(), &poison)?;
+
+		// The block stays marked as used, so the allocator leaves it alone
+		let sanitizer = self.sanitizer.as_mut().unwrap();
+		let allocation = sanitizer.live.remove(&ptr).unwrap();
+		self.allocated -= allocation.size;
+		sanitizer.freed.insert(ptr, Freed { allocation, free_lr: lr });
+		sanitizer.quarantine.push_back(ptr);
+		sanitizer.quarantined_bytes += block_size;
+
+		while self.sanitizer.as_ref().unwrap().quarantined_bytes > QUARANTINE_LIMIT {
+			self.recycle_oldest(uc, "recycled")?;
+		}
+		Ok(())
+	}
+
+	/// Really frees the block that's been in quarantine longest, after
+	/// checking nothing wrote to it in the meantime.
+	fn recycle_oldest(&mut self, uc: &mut EmuUC, when: &str) -> UcResult<()> {
+		let block = {
+			let sanitizer = self.sanitizer.as_mut().unwrap();
+			let ptr = match sanitizer.quarantine.pop_front() {
+				Some(ptr) => ptr,
+				None => return Ok(())
+			};
+			sanitizer.freed.remove(&ptr);
+			ptr - SIZE_OF_HEADER - RED_ZONE_SIZE
+		};
+
+		self.check_poison(uc, block + SIZE_OF_HEADER + RED_ZONE_SIZE, when)?;
+		let block_size = uc.read_u32(block + HDR_BLOCK_SIZE)?;
+		self.sanitizer.as_mut().unwrap().quarantined_bytes -= block_size;
+		self.release_block(uc, block)
+	}
+
+	/// Frees everything in quarantine. Returns false if there was nothing.
+	fn drain_quarantine(&mut self, uc: &mut EmuUC) -> UcResult<bool> {
+		let mut drained = false;
+		while self.sanitizer.as_ref().is_some_and(|s| !s.quarantine.is_empty()) {
+			self.recycle_oldest(uc, "recycled")?;
+			drained = true;
+		}
+		Ok(drained)
+	}
+
+	fn check_red_zones(&mut self, uc: &EmuUC, ptr: u32, when: &str) -> UcResult<()> {
+		let block = self.block_for_ptr(ptr);
+		let block_end = block + uc.read_u32(block + HDR_BLOCK_SIZE)?;
+		let sanitizer = self.sanitizer.as_mut().unwrap();
+		let allocation = match sanitizer.live.get(&ptr) {
+			Some(&allocation) => allocation,
+			None => return Ok(())
+		};
+
+		let size = allocation.size;
+		let damage = [
+			("before", is_damaged(uc, block + SIZE_OF_HEADER, ptr, RED_ZONE_BYTE)?),
+			("after", is_damaged(uc, ptr + size, block_end, RED_ZONE_BYTE)?)
+		];
+		for (side, damaged) in damage {
+			if damaged {
+				let lr = caller(uc);
+				sanitizer.report(format!(
+					"Memory just {side} block {ptr:08X} ({size} bytes, allocated at LR={:08X}) was overwritten; noticed when {when} at LR={lr:08X}",
+					allocation.lr));
+			}
+		}
+		Ok(())
+	}
+
+	fn check_poison(&mut self, uc: &EmuUC, ptr: u32, when: &str) -> UcResult<()> {
+		let block = ptr - SIZE_OF_HEADER - RED_ZONE_SIZE;
+		let block_end = block + uc.read_u32(block + HDR_BLOCK_SIZE)?;
+		if is_damaged(uc, block + SIZE_OF_HEADER, block_end, FREED_BYTE)? {
+			let sanitizer = self.sanitizer.as_mut().unwrap();
+			let freed = sanitizer.freed.get(&ptr).copied();
+			let message = match freed {
+				Some(freed) => format!(
+					"Block {ptr:08X} ({} bytes, allocated at LR={:08X}) was written to after being freed at LR={:08X}; noticed when {when}",
+					freed.allocation.size, freed.allocation.lr, freed.free_lr),
+				None => format!("Block {ptr:08X} was written to after being freed; noticed when {when}")
+			};
+			sanitizer.report(message);
+		}
+		Ok(())
+	}
+
+	/// Checks every live block's red zones and everything in quarantine,
+	/// then says how many problems the sanitizer found over the run.
+	pub(super) fn check_at_exit(&mut self, uc: &EmuUC) -> UcResult<()> {
+		let (mut live, quarantine) = match self.sanitizer.as_ref() {
+			Some(sanitizer) => (
+				sanitizer.live.keys().copied().collect::<Vec<_>>(),
+				sanitizer.quarantine.iter().copied().collect::<Vec<_>>()
+			),
+			None => return Ok(())
+		};
+
+		live.sort_unstable();
+		for ptr in live {
+			self.check_red_zones(uc, ptr, "exiting")?;
+		}
+		for ptr in quarantine {
+			self.check_poison(uc, ptr, "exiting")?;
+		}
+
+		match self.sanitizer.as_ref().unwrap().problems {
+			0 => info!(target: "heap", "Heap sanitizer found no problems"),
+			n => error!(target: "heap", "Heap sanitizer found {n} problem(s)")
+		}
+		Ok(())
+	}
+
 	fn is_block_free(&self, uc: &EmuUC, block: u32) -> UcResult<bool> {
 		let user_size = uc.read_u32(block + HDR_USER_SIZE)?;
 		Ok((user_size & FREE_FLAG) == FREE_FLAG)
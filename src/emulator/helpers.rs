@@ -9,7 +9,9 @@ use super::{EmuUC, UcResult};
 pub(super) struct ArgReader {
 	use_pascal_strings: bool,
 	gpr_id: i32,
-	va_list_position: u32
+	va_list_position: u32,
+	/// Descriptions of the arguments read so far, if we're tracing this call
+	recorded: Option<Vec<String>>
 }
 
 impl ArgReader {
@@ -17,7 +19,16 @@ impl ArgReader {
 		ArgReader {
 			use_pascal_strings: false,
 			gpr_id: RegisterPPC::R3.into(),
-			va_list_position: 0
+			va_list_position: 0,
+			recorded: None
+		}
+	}
+
+	/// Creates a reader that remembers every argument it decodes.
+	pub fn new_recording() -> Self {
+		ArgReader {
+			recorded: Some(Vec::new()),
+			..ArgReader::new()
 		}
 	}
 
@@ -25,73 +36,86 @@ impl ArgReader {
 		ArgReader {
 			use_pascal_strings: false,
 			gpr_id: -1,
-			va_list_position: va_list
+			va_list_position: va_list,
+			recorded: None
 		}
 	}
 
+	pub fn take_recorded(&mut self) -> Vec<String> {
+		self.recorded.take().unwrap_or_default()
+	}
+
+	fn get<T: ReadableArg>(&mut self, uc: &EmuUC) -> UcResult<T> {
+		let value = T::get_from_reader(self, uc, self.use_pascal_strings)?;
+		if let Some(recorded) = &mut self.recorded {
+			recorded.push(value.describe());
+		}
+		Ok(value)
+	}
+
 	pub fn pstr(&mut self) -> &mut Self {
 		self.use_pascal_strings = true;
 		self
 	}
 
 	pub(super) fn read1<T: ReadableArg>(&mut self, uc: &EmuUC) -> UcResult<T> {
-		T::get_from_reader(self, uc, self.use_pascal_strings)
+		self.get::<T>(uc)
 	}
 	pub(super) fn read2<T1: ReadableArg, T2: ReadableArg>(&mut self, uc: &EmuUC) -> UcResult<(T1, T2)> {
-		let a = T1::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let b = T2::get_from_reader(self, uc, self.use_pascal_strings)?;
+		let a = self.get::<T1>(uc)?;
+		let b = self.get::<T2>(uc)?;
 		Ok((a, b))
 	}
 	pub(super) fn read3<T1: ReadableArg, T2: ReadableArg, T3: ReadableArg>(&mut self, uc: &EmuUC) -> UcResult<(T1, T2, T3)> {
-		let a = T1::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let b = T2::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let c = T3::get_from_reader(self, uc, self.use_pascal_strings)?;
+		let a = self.get::<T1>(uc)?;
+		let b = self.get::<T2>(uc)?;
+		let c = self.get::<T3>(uc)?;
 		Ok((a, b, c))
 	}
 	pub(super) fn read4<T1: ReadableArg, T2: ReadableArg, T3: ReadableArg, T4: ReadableArg>(&mut self, uc: &EmuUC) -> UcResult<(T1, T2, T3, T4)> {
-		let a = T1::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let b = T2::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let c = T3::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let d = T4::get_from_reader(self, uc, self.use_pascal_strings)?;
+		let a = self.get::<T1>(uc)?;
+		let b = self.get::<T2>(uc)?;
+		let c = self.get::<T3>(uc)?;
+		let d = self.get::<T4>(uc)?;
 		Ok((a, b, c, d))
 	}
 	pub(super) fn read5<T1: ReadableArg, T2: ReadableArg, T3: ReadableArg, T4: ReadableArg, T5: ReadableArg>(&mut self, uc: &EmuUC) -> UcResult<(T1, T2, T3, T4, T5)> {
-		let a = T1::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let b = T2::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let c = T3::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let d = T4::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let e = T5::get_from_reader(self, uc, self.use_pascal_strings)?;
+		let a = self.get::<T1>(uc)?;
+		let b = self.get::<T2>(uc)?;
+		let c = self.get::<T3>(uc)?;
+		let d = self.get::<T4>(uc)?;
+		let e = self.get::<T5>(uc)?;
 		Ok((a, b, c, d, e))
 	}
 	#[allow(dead_code)]
 	pub(super) fn read6<T1: ReadableArg, T2: ReadableArg, T3: ReadableArg, T4: ReadableArg, T5: ReadableArg, T6: ReadableArg>(&mut self, uc: &EmuUC) -> UcResult<(T1, T2, T3, T4, T5, T6)> {
-		let a = T1::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let b = T2::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let c = T3::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let d = T4::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let e = T5::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let f = T6::get_from_reader(self, uc, self.use_pascal_strings)?;
+		let a = self.get::<T1>(uc)?;
+		let b = self.get::<T2>(uc)?;
+		let c = self.get::<T3>(uc)?;
+		let d = self.get::<T4>(uc)?;
+		let e = self.get::<T5>(uc)?;
+		let f = self.get::<T6>(uc)?;
 		Ok((a, b, c, d, e, f))
 	}
 	pub(super) fn read7<T1: ReadableArg, T2: ReadableArg, T3: ReadableArg, T4: ReadableArg, T5: ReadableArg, T6: ReadableArg, T7: ReadableArg>(&mut self, uc: &EmuUC) -> UcResult<(T1, T2, T3, T4, T5, T6, T7)> {
-		let a = T1::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let b = T2::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let c = T3::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let d = T4::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let e = T5::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let f = T6::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let g = T7::get_from_reader(self, uc, self.use_pascal_strings)?;
+		let a = self.get::<T1>(uc)?;
+		let b = self.get::<T2>(uc)?;
+		let c = self.get::<T3>(uc)?;
+		let d = self.get::<T4>(uc)?;
+		let e = self.get::<T5>(uc)?;
+		let f = self.get::<T6>(uc)?;
+		let g = self.get::<T7>(uc)?;
 		Ok((a, b, c, d, e, f, g))
 	}
 	pub(super) fn read8<T1: ReadableArg, T2: ReadableArg, T3: ReadableArg, T4: ReadableArg, T5: ReadableArg, T6: ReadableArg, T7: ReadableArg, T8: ReadableArg>(&mut self, uc: &EmuUC) -> UcResult<(T1, T2, T3, T4, T5, T6, T7, T8)> {
-		let a = T1::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let b = T2::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let c = T3::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let d = T4::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let e = T5::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let f = T6::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let g = T7::get_from_reader(self, uc, self.use_pascal_strings)?;
-		let h = T8::get_from_reader(self, uc, self.use_pascal_strings)?;
+		let a = self.get::<T1>(uc)?;
+		let b = self.get::<T2>(uc)?;
+		let c = self.get::<T3>(uc)?;
+		let d = self.get::<T4>(uc)?;
+		let e = self.get::<T5>(uc)?;
+		let f = self.get::<T6>(uc)?;
+		let g = self.get::<T7>(uc)?;
+		let h = self.get::<T8>(uc)?;
 		Ok((a, b, c, d, e, f, g, h))
 	}
 
@@ -108,8 +132,13 @@ impl ArgReader {
 	}
 }
 
-pub(super) trait ReadableArg {
+pub(super) trait ReadableArg: std::fmt::Debug {
 	fn get_from_reader(reader: &mut ArgReader, uc: &EmuUC, pstr_flag: bool) -> UcResult<Self> where Self: Sized;
+
+	/// Formats the argument for call traces
+	fn describe(&self) -> String {
+		format!("{self:?}")
+	}
 }
 
 impl ReadableArg for bool {
@@ -131,6 +160,11 @@ impl ReadableArg for u32 {
 	fn get_from_reader(reader: &mut ArgReader, uc: &EmuUC, _pstr_flag: bool) -> UcResult<Self> {
 		reader.read_gpr(uc)
 	}
+
+	// these are usually pointers
+	fn describe(&self) -> String {
+		format!("0x{self:X}")
+	}
 }
 impl ReadableArg for u64 {
 	fn get_from_reader(reader: &mut ArgReader, uc: &EmuUC, _pstr_flag: bool) -> UcResult<Self> {
@@ -1,22 +1,40 @@
 use std::ffi::CString;
 use crate::common::{four_cc, FourCC, OSErr};
-use super::{EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
+use super::{INTERFACE_LIB, STDCLIB, EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
 
 const STDCLIB_ID: u32 = 100;
+/// Connections to shared libraries loaded from disk are this plus the fragment index
+const FRAGMENT_ID_BASE: u32 = 0x200;
 
-fn get_shared_library(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
+fn get_shared_library(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
     let (lib_name, arch_type, load_flags, conn_id, main_addr, err_message):
         (CString, FourCC, u32, u32, u32, u32) = reader.pstr().read6(uc)?;
 
     debug!(target: "InterfaceLib",
         "GetSharedLibrary(libName={lib_name:?}, archType={arch_type:?}, loadFlags={load_flags}, connID={conn_id:08X}, mainAddr={main_addr:08X}, errMessage={err_message:08X})");
 
-    if arch_type == four_cc(*b"pwpc") && lib_name.as_bytes() == b"StdCLib" {
+    if arch_type != four_cc(*b"pwpc") {
+        return Ok(Some(OSErr::CFragNoLibrary.to_u32()));
+    }
+
+    if lib_name.as_bytes() == b"StdCLib" {
         uc.write_u32(conn_id, STDCLIB_ID)?;
-        Ok(Some(0))
-    } else {
-        // not sure what the error code should be
-        Ok(Some(OSErr::BadName.to_u32()))
+        return Ok(Some(0));
+    }
+
+    let name = lib_name.to_string_lossy();
+    match state.fragments.iter().skip(1).position(|f| f.name == name) {
+        Some(index) => {
+            uc.write_u32(conn_id, FRAGMENT_ID_BASE + 1 + index as u32)?;
+            if main_addr != 0 {
+                uc.write_u32(main_addr, 0)?;
+            }
+            Ok(Some(0))
+        }
+        None => {
+            warn!(target: "InterfaceLib", "GetSharedLibrary: {name} was not loaded from the library path");
+            Ok(Some(OSErr::CFragNoLibrary.to_u32()))
+        }
     }
 }
 
@@ -27,19 +45,40 @@ fn find_symbol(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) ->
     debug!(target: "InterfaceLib",
         "FindSymbol(connID={conn_id:08X}, symName={sym_name:?}, symAddr={sym_addr:08X}, symClass={sym_class:08X})");
 
+    let name = sym_name.to_str().unwrap();
+
     if conn_id == STDCLIB_ID {
         // should probably do something with symClass...?
-        let stub = state.find_stub(uc, "StdCLib", sym_name.to_str().unwrap())?;
+        let stub = state.find_stub(uc, STDCLIB, name)?;
         uc.write_u32(sym_addr, stub)?;
         debug!(target: "InterfaceLib", "returned stub: {stub:08X}");
+        return Ok(Some(0));
+    }
+
+    let fragment = match conn_id.checked_sub(FRAGMENT_ID_BASE).and_then(|index| state.fragments.get(index as usize)) {
+        Some(fragment) => fragment,
+        None => return Ok(Some(OSErr::CFragNoLibrary.to_u32()))
+    };
+
+    if state.find_hle_function(&fragment.name, name).is_some() {
+        let lib_name = fragment.name.clone();
+        let stub = state.find_stub(uc, &lib_name, name)?;
+        uc.write_u32(sym_addr, stub)?;
+        debug!(target: "InterfaceLib", "returned stub: {stub:08X}");
+        Ok(Some(0))
+    } else if let Some((address, class)) = fragment.find_export(name) {
+        uc.write_u32(sym_addr, address)?;
+        if sym_class != 0 {
+            uc.write_u8(sym_class, class as u8)?;
+        }
+        debug!(target: "InterfaceLib", "returned {class:?} export from {}: {address:08X}", fragment.name);
         Ok(Some(0))
     } else {
-        // not sure what the error code should be
-        Ok(Some(OSErr::BadName.to_u32()))
+        Ok(Some(OSErr::CFragNoSymbol.to_u32()))
     }
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-    state.install_shim_function("GetSharedLibrary", get_shared_library);
-    state.install_shim_function("FindSymbol", find_symbol);
+    state.install_shim_function(INTERFACE_LIB, "GetSharedLibrary", get_shared_library);
+    state.install_shim_function(INTERFACE_LIB, "FindSymbol", find_symbol);
 }
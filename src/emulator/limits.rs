@@ -0,0 +1,116 @@
+use std::{io::Write, rc::Rc, time::{Duration, Instant}};
+
+use unicorn_engine::RegisterPPC;
+
+use crate::linker;
+
+use super::{EmuState, EmuUC, UcResult, backtrace};
+
+/// Exit status when the time limit runs out, as with timeout(1)
+pub const TIMEOUT_STATUS: i32 = 124;
+pub const INSTRUCTION_LIMIT_STATUS: i32 = 123;
+pub const HEAP_LIMIT_STATUS: i32 = 122;
+
+/// Bounds on how much a run can do before it's stopped
+#[derive(Clone, Default)]
+pub struct Limits {
+	/// Wall-clock time, counting from when the emulator starts running code
+	pub timeout: Option<Duration>,
+	/// Guest instructions, counted a basic block at a time
+	pub max_instructions: Option<u64>,
+	/// Bytes the tool can have allocated on the heap at once
+	pub max_heap: Option<u32>
+}
+
+/// Checking the clock is slow, so only do it every so many blocks
+const BLOCKS_PER_CLOCK_CHECK: u32 = 0x1000;
+
+#[derive(Clone, Copy)]
+pub(super) enum Limit {
+	Timeout(Duration),
+	Instructions(u64),
+	Heap(u32)
+}
+
+impl Limit {
+	fn exit_status(self) -> i32 {
+		match self {
+			Limit::Timeout(_) => TIMEOUT_STATUS,
+			Limit::Instructions(_) => INSTRUCTION_LIMIT_STATUS,
+			Limit::Heap(_) => HEAP_LIMIT_STATUS
+		}
+	}
+
+	fn describe(self) -> String {
+		match self {
+			Limit::Timeout(timeout) => format!("time limit of {timeout:?}"),
+			Limit::Instructions(count) => format!("limit of {count} instructions"),
+			Limit::Heap(size) => format!("heap limit of {size} bytes")
+		}
+	}
+}
+
+/// Ends the run like abort() would, remembering why and where.
+pub(super) fn stop(uc: &mut EmuUC, state: &mut EmuState, limit: Limit, pc: u32) {
+	if state.limit_hit.is_none() {
+		state.limit_hit = Some((limit, pc));
+		state.exit_status = Some(limit.exit_status());
+		state.quick_exit = true;
+	}
+	if let Err(e) = uc.emu_stop() {
+		warn!(target: "emulator", "Couldn't stop after hitting the {}: {e:?}", limit.describe());
+	}
+}
+
+/// Stops the run if a shim has left the heap bigger than allowed.
+pub(super) fn check_heap(uc: &mut EmuUC, state: &mut EmuState) {
+	if let Some(max_heap) = state.max_heap {
+		if state.heap.allocated() > max_heap {
+			let lr = uc.reg_read(RegisterPPC::LR).unwrap_or_else(|e| {
+				warn!(target: "emulator", "Couldn't read LR while checking the heap: {e:?}");
+				0
+			});
+			stop(uc, state, Limit::Heap(max_heap), lr as u32);
+		}
+	}
+}
+
+/// Counts instructions and watches the clock, if there are limits on either.
+pub(super) fn install(uc: &mut EmuUC, limits: &Limits) -> UcResult<()> {
+	if limits.timeout.is_none() && limits.max_instructions.is_none() {
+		return Ok(());
+	}
+
+	let (timeout, max_instructions) = (limits.timeout, limits.max_instructions);
+	let start = Instant::now();
+	let mut instructions = 0u64;
+	let mut blocks = 0u32;
+
+	uc.add_block_hook(move |uc, addr, size| {
+		instructions += u64::from(size / 4);
+		blocks = blocks.wrapping_add(1);
+
+		let limit = match (max_instructions, timeout) {
+			(Some(max), _) if instructions > max => Limit::Instructions(max),
+			(_, Some(timeout)) if blocks % BLOCKS_PER_CLOCK_CHECK == 0 && start.elapsed() > timeout => Limit::Timeout(timeout),
+			_ => return
+		};
+
+		let state = Rc::clone(uc.get_data());
+		stop(uc, &mut state.borrow_mut(), limit, addr as u32);
+	})?;
+
+	Ok(())
+}
+
+/// Explains why the run was cut short, if it was.
+pub(super) fn report(uc: &EmuUC, state: &mut EmuState, exe: &linker::Executable) {
+	if let Some((limit, pc)) = state.limit_hit {
+		let message = format!(
+			"Stopped after hitting the {} at {pc:08X} ({})",
+			limit.describe(), backtrace::symbolize(uc, state, exe, pc));
+		error!(target: "emulator", "{message}");
+		// like crash reports, this goes wherever the tool's errors do
+		let _ = writeln!(state.console.stderr, "{message}");
+	}
+}
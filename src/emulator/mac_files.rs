@@ -1,4 +1,4 @@
-use std::{ffi::CString, rc::Rc, cell::RefCell};
+use std::ffi::CString;
 
 // const ATTRIB_LOCKED: u8 = 1;
 // const ATTRIB_RESOURCE_FORK_OPEN: u8 = 4;
@@ -6,16 +6,34 @@ use std::{ffi::CString, rc::Rc, cell::RefCell};
 const ATTRIB_DIRECTORY: u8 = 0x10;
 // const ATTRIB_ANY_FORK_OPEN: u8 = 0x80;
 
-use crate::{common::{OSErr, FourCC, system_time_to_mac_time}, filesystem::{MacFile, Fork}, mac_roman};
+use anyhow::Result;
 
-use super::{EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
+use crate::{common::{OSErr, FourCC, Shared, system_time_to_mac_time}, filesystem::{FileSystem, MacFile, Fork}, mac_roman, snapshot::{SnapshotReader, SnapshotWriter}};
+
+use super::{INTERFACE_LIB, EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
 
 pub(super) struct FileHandle {
-	file: Rc<RefCell<MacFile>>,
+	file: Shared<MacFile>,
 	position: usize,
 	fork: Fork
 }
 
+impl FileHandle {
+	pub(super) fn save_snapshot(&self, w: &mut SnapshotWriter) {
+		w.path(&self.file.borrow().path);
+		w.count(self.position);
+		w.bool(self.fork == Fork::Resource);
+	}
+
+	pub(super) fn restore_snapshot(r: &mut SnapshotReader, filesystem: &mut FileSystem) -> Result<FileHandle> {
+		Ok(FileHandle {
+			file: filesystem.get_file(&r.path()?)?,
+			position: r.count()?,
+			fork: if r.bool()? { Fork::Resource } else { Fork::Data }
+		})
+	}
+}
+
 fn nice_error(error: anyhow::Error) -> u32 {
 	if let Some(io) = error.downcast_ref::<std::io::Error>() {
 		if io.kind() == std::io::ErrorKind::NotFound {
@@ -870,39 +888,39 @@ fn resolve_alias_file(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgRead
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("PBOpenRFSync", pb_open_rf_sync);
+	state.install_shim_function(INTERFACE_LIB, "PBOpenRFSync", pb_open_rf_sync);
 	// PBCloseSync
 	// PBReadSync
-	state.install_shim_function("PBWriteSync", pb_write_sync);
-	state.install_shim_function("FSClose", fs_close);
-	state.install_shim_function("FSRead", fs_read);
-	state.install_shim_function("FSWrite", fs_write);
-	state.install_shim_function("GetVInfo", get_v_info);
-	state.install_shim_function("GetEOF", get_eof);
-	state.install_shim_function("SetEOF", set_eof);
-	state.install_shim_function("GetFPos", get_f_pos);
-	state.install_shim_function("SetFPos", set_f_pos);
-	state.install_shim_function("PBGetCatInfoSync", pb_get_cat_info_sync);
-	state.install_shim_function("PBHOpenSync", pb_h_open_sync);
-	state.install_shim_function("PBHGetFInfoSync", pb_h_get_f_info_sync);
-	state.install_shim_function("PBHSetFInfoSync", pb_h_set_f_info_sync);
-	state.install_shim_function("HOpen", h_open);
-	state.install_shim_function("HOpenDF", h_open);
-	state.install_shim_function("HOpenRF", h_open_rf);
-	state.install_shim_function("HCreate", h_create);
-	state.install_shim_function("HDelete", h_delete);
-	state.install_shim_function("HGetFInfo", h_get_f_info);
-	state.install_shim_function("FSMakeFSSpec", fs_make_fs_spec);
-	state.install_shim_function("FSpOpenDF", fsp_open_df);
-	state.install_shim_function("FSpCreate", fsp_create);
-	state.install_shim_function("FSpDelete", fsp_delete);
-	state.install_shim_function("FSpGetFInfo", fsp_get_f_info);
+	state.install_shim_function(INTERFACE_LIB, "PBWriteSync", pb_write_sync);
+	state.install_shim_function(INTERFACE_LIB, "FSClose", fs_close);
+	state.install_shim_function(INTERFACE_LIB, "FSRead", fs_read);
+	state.install_shim_function(INTERFACE_LIB, "FSWrite", fs_write);
+	state.install_shim_function(INTERFACE_LIB, "GetVInfo", get_v_info);
+	state.install_shim_function(INTERFACE_LIB, "GetEOF", get_eof);
+	state.install_shim_function(INTERFACE_LIB, "SetEOF", set_eof);
+	state.install_shim_function(INTERFACE_LIB, "GetFPos", get_f_pos);
+	state.install_shim_function(INTERFACE_LIB, "SetFPos", set_f_pos);
+	state.install_shim_function(INTERFACE_LIB, "PBGetCatInfoSync", pb_get_cat_info_sync);
+	state.install_shim_function(INTERFACE_LIB, "PBHOpenSync", pb_h_open_sync);
+	state.install_shim_function(INTERFACE_LIB, "PBHGetFInfoSync", pb_h_get_f_info_sync);
+	state.install_shim_function(INTERFACE_LIB, "PBHSetFInfoSync", pb_h_set_f_info_sync);
+	state.install_shim_function(INTERFACE_LIB, "HOpen", h_open);
+	state.install_shim_function(INTERFACE_LIB, "HOpenDF", h_open);
+	state.install_shim_function(INTERFACE_LIB, "HOpenRF", h_open_rf);
+	state.install_shim_function(INTERFACE_LIB, "HCreate", h_create);
+	state.install_shim_function(INTERFACE_LIB, "HDelete", h_delete);
+	state.install_shim_function(INTERFACE_LIB, "HGetFInfo", h_get_f_info);
+	state.install_shim_function(INTERFACE_LIB, "FSMakeFSSpec", fs_make_fs_spec);
+	state.install_shim_function(INTERFACE_LIB, "FSpOpenDF", fsp_open_df);
+	state.install_shim_function(INTERFACE_LIB, "FSpCreate", fsp_create);
+	state.install_shim_function(INTERFACE_LIB, "FSpDelete", fsp_delete);
+	state.install_shim_function(INTERFACE_LIB, "FSpGetFInfo", fsp_get_f_info);
 
 	// not actually in Files.h but we'll let it slide.
 	// Aliases.h
-	state.install_shim_function("ResolveAliasFile", resolve_alias_file);
+	state.install_shim_function(INTERFACE_LIB, "ResolveAliasFile", resolve_alias_file);
 
 	// these are from MPW itself
-	state.install_shim_function("MakeResolvedPath", mpw_make_resolved_path);
-	state.install_shim_function("MakeResolvedFSSpec", mpw_make_resolved_fs_spec);
+	state.install_shim_function(INTERFACE_LIB, "MakeResolvedPath", mpw_make_resolved_path);
+	state.install_shim_function(INTERFACE_LIB, "MakeResolvedFSSpec", mpw_make_resolved_fs_spec);
 }
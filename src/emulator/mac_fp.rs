@@ -1,6 +1,6 @@
 use unicorn_engine::RegisterPPC;
 
-use super::{EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
+use super::{MATH_LIB, EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
 
 fn dec2num(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let ptr: u32 = reader.read1(uc)?;
@@ -27,5 +27,5 @@ fn dec2num(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> Fun
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("dec2num", dec2num);
+	state.install_shim_function(MATH_LIB, "dec2num", dec2num);
 }
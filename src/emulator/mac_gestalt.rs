@@ -1,6 +1,6 @@
 use crate::common::{FourCC, OSErr, four_cc};
 
-use super::{EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
+use super::{INTERFACE_LIB, EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
 
 fn build_response(selector: FourCC) -> Option<u32> {
 	let response = if selector == four_cc(*b"alis") {
@@ -30,5 +30,5 @@ fn gestalt(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> Fun
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("Gestalt", gestalt);
+	state.install_shim_function(INTERFACE_LIB, "Gestalt", gestalt);
 }
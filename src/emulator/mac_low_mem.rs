@@ -1,6 +1,6 @@
 use std::time::SystemTime;
 
-use super::{EmuState, EmuUC, FuncResult, helpers::ArgReader};
+use super::{INTERFACE_LIB, EmuState, EmuUC, FuncResult, helpers::ArgReader};
 
 fn lm_get_ticks(_uc: &mut EmuUC, state: &mut EmuState, _reader: &mut ArgReader) -> FuncResult {
 	let duration = (state.start_time.elapsed().as_millis() * 60) / 1000;
@@ -23,9 +23,9 @@ fn lm_get_mem_err(_uc: &mut EmuUC, state: &mut EmuState, _reader: &mut ArgReader
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("LMGetTicks", lm_get_ticks);
-	state.install_shim_function("LMGetTime", lm_get_time);
-	state.install_shim_function("LMGetBootDrive", lm_get_boot_drive);
-	state.install_shim_function("LMGetMemErr", lm_get_mem_err);
+	state.install_shim_function(INTERFACE_LIB, "LMGetTicks", lm_get_ticks);
+	state.install_shim_function(INTERFACE_LIB, "LMGetTime", lm_get_time);
+	state.install_shim_function(INTERFACE_LIB, "LMGetBootDrive", lm_get_boot_drive);
+	state.install_shim_function(INTERFACE_LIB, "LMGetMemErr", lm_get_mem_err);
 }
 
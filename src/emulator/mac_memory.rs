@@ -1,6 +1,6 @@
 use crate::common::OSErr;
 
-use super::{EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
+use super::{INTERFACE_LIB, EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
 
 fn stub_return_void(_uc: &mut EmuUC, _state: &mut EmuState, _reader: &mut ArgReader) -> FuncResult {
 	Ok(None)
@@ -147,27 +147,27 @@ fn hand_and_hand(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("MemError", mem_error);
-	state.install_shim_function("NewHandle", new_handle);
-	state.install_shim_function("NewHandleClear", new_handle);
-	state.install_shim_function("NewPtr", new_ptr);
-	state.install_shim_function("NewPtrClear", new_ptr);
-	state.install_shim_function("HLock", stub_return_void);
-	state.install_shim_function("HUnlock", stub_return_void);
-	state.install_shim_function("HLockHi", stub_return_void);
-	state.install_shim_function("MoveHHi", stub_return_void);
-	state.install_shim_function("DisposePtr", dispose_ptr);
-	state.install_shim_function("GetPtrSize", get_ptr_size);
-	state.install_shim_function("SetPtrSize", set_ptr_size);
-	state.install_shim_function("DisposeHandle", dispose_handle);
-	state.install_shim_function("GetHandleSize", get_handle_size);
-	state.install_shim_function("SetHandleSize", set_handle_size);
-	state.install_shim_function("BlockMoveData", block_move_data);
-	state.install_shim_function("HGetState", h_get_state);
-	state.install_shim_function("HSetState", stub_return_void);
-	state.install_shim_function("BlockMove", block_move);
-	state.install_shim_function("PtrAndHand", ptr_and_hand);
-	state.install_shim_function("HandAndHand", hand_and_hand);
-
-	state.install_shim_function("TempNewHandle", temp_new_handle);
+	state.install_shim_function(INTERFACE_LIB, "MemError", mem_error);
+	state.install_shim_function(INTERFACE_LIB, "NewHandle", new_handle);
+	state.install_shim_function(INTERFACE_LIB, "NewHandleClear", new_handle);
+	state.install_shim_function(INTERFACE_LIB, "NewPtr", new_ptr);
+	state.install_shim_function(INTERFACE_LIB, "NewPtrClear", new_ptr);
+	state.install_shim_function(INTERFACE_LIB, "HLock", stub_return_void);
+	state.install_shim_function(INTERFACE_LIB, "HUnlock", stub_return_void);
+	state.install_shim_function(INTERFACE_LIB, "HLockHi", stub_return_void);
+	state.install_shim_function(INTERFACE_LIB, "MoveHHi", stub_return_void);
+	state.install_shim_function(INTERFACE_LIB, "DisposePtr", dispose_ptr);
+	state.install_shim_function(INTERFACE_LIB, "GetPtrSize", get_ptr_size);
+	state.install_shim_function(INTERFACE_LIB, "SetPtrSize", set_ptr_size);
+	state.install_shim_function(INTERFACE_LIB, "DisposeHandle", dispose_handle);
+	state.install_shim_function(INTERFACE_LIB, "GetHandleSize", get_handle_size);
+	state.install_shim_function(INTERFACE_LIB, "SetHandleSize", set_handle_size);
+	state.install_shim_function(INTERFACE_LIB, "BlockMoveData", block_move_data);
+	state.install_shim_function(INTERFACE_LIB, "HGetState", h_get_state);
+	state.install_shim_function(INTERFACE_LIB, "HSetState", stub_return_void);
+	state.install_shim_function(INTERFACE_LIB, "BlockMove", block_move);
+	state.install_shim_function(INTERFACE_LIB, "PtrAndHand", ptr_and_hand);
+	state.install_shim_function(INTERFACE_LIB, "HandAndHand", hand_and_hand);
+
+	state.install_shim_function(INTERFACE_LIB, "TempNewHandle", temp_new_handle);
 }
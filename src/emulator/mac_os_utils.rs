@@ -2,7 +2,7 @@ use chrono::prelude::*;
 
 use crate::common::get_mac_time;
 
-use super::{EmuState, EmuUC, FuncResult, helpers::ArgReader};
+use super::{INTERFACE_LIB, EmuState, EmuUC, FuncResult, helpers::ArgReader};
 
 fn get_date_time(_uc: &mut EmuUC, _state: &mut EmuState, _reader: &mut ArgReader) -> FuncResult {
 	Ok(Some(get_mac_time(Local::now())))
@@ -26,11 +26,11 @@ fn trap_available(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader)
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("GetDateTime", get_date_time);
+	state.install_shim_function(INTERFACE_LIB, "GetDateTime", get_date_time);
 
 	// this is actually in Events.h but shhh
-	state.install_shim_function("TickCount", tick_count);
+	state.install_shim_function(INTERFACE_LIB, "TickCount", tick_count);
 
 	// not sure where this lies...?
-	state.install_shim_function("TrapAvailable", trap_available);
+	state.install_shim_function(INTERFACE_LIB, "TrapAvailable", trap_available);
 }
@@ -1,4 +1,4 @@
-use super::{EmuState, EmuUC, FuncResult, helpers::ArgReader};
+use super::{INTERFACE_LIB, EmuState, EmuUC, FuncResult, UcResult, helpers::{ArgReader, UnicornExtras}};
 
 fn get_cursor(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncResult {
 	let id: i16 = reader.read1(uc)?;
@@ -24,8 +24,34 @@ fn set_cursor(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) ->
 	Ok(None)
 }
 
+/// Size of QDGlobals: privates[76], randSeed, screenBits, arrow, the five
+/// standard patterns and thePort
+pub(super) const QD_GLOBALS_SIZE: u32 = 206;
+
+pub(super) fn init_qd(uc: &mut EmuUC, _state: &mut EmuState, qd: u32) -> UcResult<()> {
+	uc.write_u32(qd + 76, 1)?; // randSeed
+
+	// screenBits.bounds: pretend to have a 640x480 screen
+	uc.write_u16(qd + 80 + 4, 640 / 8)?; // rowBytes
+	uc.write_u16(qd + 80 + 10, 480)?;
+	uc.write_u16(qd + 80 + 12, 640)?;
+
+	let patterns: [u64; 5] = [
+		0x77DD77DD77DD77DD, // dkGray
+		0x8822882288228822, // ltGray
+		0xAA55AA55AA55AA55, // gray
+		0xFFFFFFFFFFFFFFFF, // black
+		0x0000000000000000 // white
+	];
+	for (i, pattern) in patterns.iter().enumerate() {
+		uc.write_u64(qd + 162 + (i as u32) * 8, *pattern)?;
+	}
+
+	Ok(())
+}
+
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("GetCursor", get_cursor);
-	state.install_shim_function("InitGraf", init_graf);
-	state.install_shim_function("SetCursor", set_cursor);
+	state.install_shim_function(INTERFACE_LIB, "GetCursor", get_cursor);
+	state.install_shim_function(INTERFACE_LIB, "InitGraf", init_graf);
+	state.install_shim_function(INTERFACE_LIB, "SetCursor", set_cursor);
 }
@@ -2,7 +2,10 @@ use std::ffi::CString;
 
 use crate::{common::{FourCC, OSErr, four_cc}, resources};
 
-use super::{EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}, UcResult};
+use super::{INTERFACE_LIB, EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}, UcResult};
+
+/// Reference number of the tool's own resource fork
+pub(super) const APPLICATION_RES_FILE: u16 = 3;
 
 fn update_res_file_internal(uc: &mut EmuUC, state: &mut EmuState, ref_num: u16) -> UcResult<bool> {
 	let resources = state.resource_files.get_mut(&ref_num).unwrap();
@@ -352,44 +355,57 @@ fn f_sp_open_res_file(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgRead
 	Ok(Some(rf_id as u32))
 }
 
+/// Writes back every resource file the program opened, as happens when it
+/// quits. The tool's own resources are left alone.
+pub(super) fn update_all(uc: &mut EmuUC, state: &mut EmuState) -> UcResult<()> {
+	let mut ref_nums = state.resource_files.keys().copied().filter(|&r| r != APPLICATION_RES_FILE).collect::<Vec<_>>();
+	ref_nums.sort();
+
+	for ref_num in ref_nums {
+		trace!(target: "resources", "Updating resource file {ref_num} on exit");
+		update_res_file_internal(uc, state, ref_num)?;
+	}
+	Ok(())
+}
+
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("CloseResFile", close_res_file);
-	state.install_shim_function("ResError", res_error);
-	state.install_shim_function("CurResFile", cur_res_file);
+	state.install_shim_function(INTERFACE_LIB, "CloseResFile", close_res_file);
+	state.install_shim_function(INTERFACE_LIB, "ResError", res_error);
+	state.install_shim_function(INTERFACE_LIB, "CurResFile", cur_res_file);
 	// short HomeResFile(Handle theResource)
 	// void CreateResFile(ConstStr255Param fileName)
 	// short OpenResFile(ConstStr255Param fileName)
-	state.install_shim_function("UseResFile", use_res_file);
+	state.install_shim_function(INTERFACE_LIB, "UseResFile", use_res_file);
 	// short CountTypes()
 	// short Count1Types()
 	// void GetIndType(ResType *theType, short index)
 	// void Get1IndType(ResType *theType, short index)
-	state.install_shim_function("SetResLoad", set_res_load);
+	state.install_shim_function(INTERFACE_LIB, "SetResLoad", set_res_load);
 	// short CountResources(ResType theType)
 	// short Count1Resources(ResType theType)
 	// Handle GetIndResource(ResType theType, short index)
 	// Handle Get1IndResource(ResType theType, short index)
-	state.install_shim_function("GetResource", get_resource);
-	state.install_shim_function("Get1Resource", get_resource);
+	state.install_shim_function(INTERFACE_LIB, "GetResource", get_resource);
+	state.install_shim_function(INTERFACE_LIB, "Get1Resource", get_resource);
 	// Handle GetNamedResource(ResType theType, ConstStr255Param name)
 	// Handle Get1NamedResource(ResType theType, ConstStr255Param name)
 	// void LoadResource(Handle theResource)
-	state.install_shim_function("ReleaseResource", release_resource);
-	state.install_shim_function("DetachResource", detach_resource);
+	state.install_shim_function(INTERFACE_LIB, "ReleaseResource", release_resource);
+	state.install_shim_function(INTERFACE_LIB, "DetachResource", detach_resource);
 	// short UniqueID(ResType theType)
 	// short Unique1ID(ResType theType)
 	// short GetResAttrs(Handle theResource)
 	// void GetResInfo(Handle theResource, short *theID, ResType *theType, Str255 name)
 	// void SetResInfo(Handle theResource, short theID, ConstStr255Param name)
-	state.install_shim_function("AddResource", add_resource);
+	state.install_shim_function(INTERFACE_LIB, "AddResource", add_resource);
 	// long GetResourceSizeOnDisk(Handle theResource)
 	// long GetMaxResourceSize(Handle theResource)
 	// long RsrcMapEntry(Handle theResource)
 	// void SetResAttrs(Handle theResource, short attrs)
 	// void ChangedResource(Handle theResource)
-	state.install_shim_function("RemoveResource", remove_resource);
-	state.install_shim_function("UpdateResFile", update_res_file);
+	state.install_shim_function(INTERFACE_LIB, "RemoveResource", remove_resource);
+	state.install_shim_function(INTERFACE_LIB, "UpdateResFile", update_res_file);
 	// void WriteResource(Handle theResource)
-	state.install_shim_function("HCreateResFile", h_create_res_file);
-	state.install_shim_function("FSpOpenResFile", f_sp_open_res_file);
+	state.install_shim_function(INTERFACE_LIB, "HCreateResFile", h_create_res_file);
+	state.install_shim_function(INTERFACE_LIB, "FSpOpenResFile", f_sp_open_res_file);
 }
@@ -1,6 +1,6 @@
 use crate::common::{four_cc, parse_mac_time};
 
-use super::{EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
+use super::{INTERFACE_LIB, EmuState, EmuUC, FuncResult, helpers::{ArgReader, UnicornExtras}};
 
 fn generic_get_ind_string(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader, pascal: bool) -> FuncResult {
 	let (ptr, table_id, mut str_id): (u32, i16, i16) = reader.read3(uc)?;
@@ -115,11 +115,11 @@ fn p2cstr(uc: &mut EmuUC, _state: &mut EmuState, reader: &mut ArgReader) -> Func
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-	state.install_shim_function("GetIndString", pascal_get_ind_string);
-	state.install_shim_function("getindstring", c_get_ind_string);
-	state.install_shim_function("numtostring", numtostring);
-	state.install_shim_function("iudatestring", iudatestring);
-	state.install_shim_function("iutimestring", iutimestring);
-	state.install_shim_function("c2pstr", c2pstr);
-	state.install_shim_function("p2cstr", p2cstr);
+	state.install_shim_function(INTERFACE_LIB, "GetIndString", pascal_get_ind_string);
+	state.install_shim_function(INTERFACE_LIB, "getindstring", c_get_ind_string);
+	state.install_shim_function(INTERFACE_LIB, "numtostring", numtostring);
+	state.install_shim_function(INTERFACE_LIB, "iudatestring", iudatestring);
+	state.install_shim_function(INTERFACE_LIB, "iutimestring", iutimestring);
+	state.install_shim_function(INTERFACE_LIB, "c2pstr", c2pstr);
+	state.install_shim_function(INTERFACE_LIB, "p2cstr", p2cstr);
 }
@@ -1,28 +1,40 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::time::Instant;
 
 use anyhow::Result;
 use bimap::BiHashMap;
 use unicorn_engine::{Unicorn, RegisterPPC};
-use unicorn_engine::unicorn_const::{Arch, HookType, Mode, Permission};
+use unicorn_engine::unicorn_const::{Arch, HookType, MemType, Mode, Permission, uc_error};
 
-use crate::common::{FourCC, OSErr};
+use crate::common::{FourCC, OSErr, Shared};
 use crate::{linker, filesystem, pef};
 use crate::emulator::helpers::UnicornExtras;
 use crate::resources::Resources;
 
+pub use call_trace::CallTraceOptions;
+pub use data_imports::size_of as data_import_size;
+pub use limits::{Limits, HEAP_LIMIT_STATUS, INSTRUCTION_LIMIT_STATUS, TIMEOUT_STATUS};
+pub use snapshot::SnapshotOptions;
+
+mod backtrace;
 mod c_ctype;
 mod c_fenv;
 mod c_stdio;
 mod c_stdlib;
 mod c_string;
 mod c_time;
+mod call_trace;
+mod data_imports;
 mod flex_lm;
+mod gdb;
 mod heap;
 mod helpers;
 mod interface_lib;
+mod limits;
 mod mac_files;
 mod mac_fp;
 mod mac_gestalt;
@@ -32,34 +44,119 @@ mod mac_os_utils;
 mod mac_quickdraw;
 mod mac_resources;
 mod mac_text_utils;
+mod snapshot;
 mod std_c_lib;
 
-type UcResult<T> = Result<T, unicorn_engine::unicorn_const::uc_error>;
+type UcResult<T> = Result<T, uc_error>;
 
 type LibraryShim = fn(&mut EmuUC, &mut EmuState, &mut helpers::ArgReader) -> UcResult<Option<u32>>;
 
+/// Where the tool's standard input, output and error go
+pub struct Console {
+	pub stdin: Box<dyn Read + Send>,
+	pub stdout: Box<dyn Write + Send>,
+	pub stderr: Box<dyn Write + Send>
+}
+
+impl Default for Console {
+	/// The emulator's own standard streams
+	fn default() -> Self {
+		Console {
+			stdin: Box::new(std::io::stdin()),
+			stdout: Box::new(std::io::stdout()),
+			stderr: Box::new(std::io::stderr())
+		}
+	}
+}
+
+/// Settings for a run of the emulator
+#[derive(Default)]
+pub struct Options {
+	/// Port to wait for a GDB connection on
+	pub gdb_port: Option<u16>,
+	pub trace_calls: Option<CallTraceOptions>,
+	pub snapshot: Option<SnapshotOptions>,
+	/// Snapshot to carry on from instead of starting afresh
+	pub resume: Option<PathBuf>,
+	pub limits: Limits,
+	/// Guard heap blocks and check frees, reporting any misuse
+	pub sanitize_heap: bool,
+	pub console: Console,
+	/// Directory that relative paths are resolved against, if not the
+	/// emulator's own
+	pub current_dir: Option<PathBuf>
+}
+
+/// Arguments for one run of the executable's main entry point
+pub struct Job {
+	pub args: Vec<String>,
+	pub env_vars: Vec<(String, String)>,
+	/// Overrides the working directory the libraries were initialised in
+	pub current_dir: Option<PathBuf>
+}
+
+const STDCLIB: &str = "StdCLib";
+const INTERFACE_LIB: &str = "InterfaceLib";
+const MATH_LIB: &str = "MathLib";
+/// Shims registered for this library are used for any library that doesn't
+/// have its own version of the function
+const ANY_LIBRARY: &str = "*";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Binding {
+	/// References point at the shim, so calls are handled by `func`
+	Shim,
+	/// References were redirected to an export of a shared library
+	Library(u32),
+	/// A weak import that nothing provides, left as kUnresolvedCFragSymbolAddress
+	Unresolved
+}
+
 struct ShimSymbol {
 	shim_address: u32,
 	class: pef::SymbolClass,
 	library_name: String,
 	name: String,
-	func: Option<LibraryShim>
+	func: Option<LibraryShim>,
+	/// Set when the HLE code takes the shim's address for its own purposes
+	claimed: bool,
+	binding: Binding
 }
 
+/// Registers that guest code doesn't have to preserve, which a callback must
+/// put back before the shim returns
+const VOLATILE_REGISTERS: &[RegisterPPC] = &[
+	RegisterPPC::R0, RegisterPPC::R1, RegisterPPC::R2, RegisterPPC::R3,
+	RegisterPPC::R4, RegisterPPC::R5, RegisterPPC::R6, RegisterPPC::R7,
+	RegisterPPC::R8, RegisterPPC::R9, RegisterPPC::R10, RegisterPPC::R11,
+	RegisterPPC::R12,
+	RegisterPPC::FPR0, RegisterPPC::FPR1, RegisterPPC::FPR2, RegisterPPC::FPR3,
+	RegisterPPC::FPR4, RegisterPPC::FPR5, RegisterPPC::FPR6, RegisterPPC::FPR7,
+	RegisterPPC::FPR8, RegisterPPC::FPR9, RegisterPPC::FPR10, RegisterPPC::FPR11,
+	RegisterPPC::FPR12, RegisterPPC::FPR13,
+	RegisterPPC::LR, RegisterPPC::CTR, RegisterPPC::XER, RegisterPPC::CR, RegisterPPC::FPSCR
+];
+
 struct EmuState {
 	start_time: Instant,
-	hle_functions: HashMap<String, LibraryShim>,
-	dyn_stubs: HashMap<String, u32>,
-	dyn_functions: Vec<LibraryShim>,
+	/// HLE functions, keyed by library and symbol name
+	hle_functions: HashMap<(String, String), LibraryShim>,
+	dyn_stubs: HashMap<(String, String), u32>,
+	/// Library, name and implementation of functions looked up with FindSymbol
+	dyn_functions: Vec<(String, String, LibraryShim)>,
 	missing_dyn_functions: Vec<(String, String)>,
 	sc_thunk_addr: u32,
+	return_addr: u32,
 	imports: Vec<ShimSymbol>,
+	fragments: Vec<linker::LoadedFragment>,
 	dummy_cursor_handle: Option<u32>,
 	resource_files: HashMap<u16, Resources>,
 	active_resource_file: u16,
 	next_resource_file: u16,
 	loaded_resources: BiHashMap<(u16, FourCC, i16), u32>,
 	env_var_map: HashMap<String, u32>,
+	argc: u32,
+	argv: u32,
 	strtok_state: u32,
 	stdio_files: HashMap<u32, c_stdio::CFile>,
 	file_handles: HashMap<u16, mac_files::FileHandle>,
@@ -67,28 +164,65 @@ struct EmuState {
 	next_checkout: u32,
 	checkouts: HashMap<u32, flex_lm::Checkout>,
 	exit_status: Option<i32>,
+	/// Set by abort() and _exit(), which skip atexit handlers, stdio cleanup and
+	/// saving files
+	quick_exit: bool,
+	/// Functions registered with atexit(), in the order they were added
+	atexit_handlers: Vec<u32>,
+	/// Set when guest code called back from a shim crashes, so that the
+	/// outermost run can report it
+	callback_fault: Option<uc_error>,
+	/// How many calls from shims back into guest code are in progress
+	callback_depth: u32,
+	/// Placeholder that `call_guest` leaves in place of this state while guest
+	/// code runs, kept so it's only built once
+	spare_state: Option<Box<EmuState>>,
+	/// Which limit stopped the run, and where
+	limit_hit: Option<(limits::Limit, u32)>,
+	max_heap: Option<u32>,
 	heap: heap::Heap,
+	gdb: Option<Shared<gdb::GdbStub>>,
+	/// Return address and target of the last few shim calls
+	recent_calls: VecDeque<(u32, backtrace::ShimCall)>,
+	call_tracer: Option<call_trace::CallTracer>,
+	console: Console,
 	filesystem: filesystem::FileSystem,
 	mem_error: OSErr,
 	res_error: OSErr
 }
 
+// Everything a run owns can be sent to another thread, so runs can be handed
+// out to workers. The Unicorn engine can't be, which is why each run creates
+// its own on the thread that carries it out.
+const _: () = {
+	fn assert_send<T: Send>() {}
+	let _ = assert_send::<EmuState>;
+	let _ = assert_send::<Options>;
+	let _ = assert_send::<linker::Executable>;
+	let _ = assert_send::<Resources>;
+};
+
 impl EmuState {
-	fn new(exe: &linker::Executable, resources: Resources) -> Self {
-		let mut state = EmuState {
+	/// State with nothing loaded
+	fn empty() -> Self {
+		EmuState {
 			start_time: Instant::now(),
 			hle_functions: HashMap::new(),
 			dyn_stubs: HashMap::new(),
 			dyn_functions: Vec::new(),
 			missing_dyn_functions: Vec::new(),
-			sc_thunk_addr: exe.sc_thunk_addr,
+			sc_thunk_addr: 0,
+			return_addr: 0,
 			imports: Vec::new(),
+			fragments: Vec::new(),
 			dummy_cursor_handle: None,
 			resource_files: HashMap::new(),
-			active_resource_file: 3,
+			active_resource_file: mac_resources::APPLICATION_RES_FILE,
 			next_resource_file: 4,
 			loaded_resources: BiHashMap::new(),
 			env_var_map: HashMap::new(),
+			argc: 0,
+			argv: 0,
 			strtok_state: 0,
 			stdio_files: HashMap::new(),
 			file_handles: HashMap::new(),
@@ -96,69 +230,175 @@ impl EmuState {
 			next_checkout: 0x10000000,
 			checkouts: HashMap::new(),
 			exit_status: None,
+			quick_exit: false,
+			atexit_handlers: Vec::new(),
+			callback_fault: None,
+			callback_depth: 0,
+			spare_state: None,
+			limit_hit: None,
+			max_heap: None,
 			heap: heap::Heap::new(0x30000000, 1024 * 1024 * 32, 512),
-			filesystem: filesystem::FileSystem::new(),
+			gdb: None,
+			recent_calls: VecDeque::with_capacity(backtrace::RECENT_CALL_COUNT),
+			call_tracer: None,
+			console: Console::default(),
+			filesystem: filesystem::FileSystem::new(PathBuf::new()),
 			mem_error: OSErr::NoError,
 			res_error: OSErr::NoError
+		}
+	}
+
+	fn new(exe: &linker::Executable, resources: Resources) -> Self {
+		let mut state = EmuState {
+			sc_thunk_addr: exe.sc_thunk_addr,
+			return_addr: exe.return_addr,
+			fragments: exe.fragments.clone(),
+			..EmuState::empty()
 		};
 
 		state.resource_files.insert(state.active_resource_file, resources);
 
-		for (import, shim_address) in exe.imports.iter().zip(&exe.shim_addrs) {
-			if import.class == pef::SymbolClass::Data {
-				trace!(target: "emulator", "(!) Data import: {}", import.name);
-			}
-
+		for import in &exe.imports {
 			state.imports.push(ShimSymbol {
-				shim_address: *shim_address,
+				shim_address: import.shim_addr,
 				class: import.class,
 				library_name: exe.libraries[import.library].clone(),
 				name: import.name.clone(),
-				func: None
+				func: None,
+				claimed: false,
+				binding: Binding::Shim
 			});
 		}
 
 		state
 	}
 
-	fn get_shim_addr(&mut self, uc: &mut EmuUC, name: &str) -> UcResult<Option<u32>> {
-		for import in &self.imports {
-			if import.name == name {
-				return Ok(Some(import.shim_address));
-			}
+	fn install_shim_function(&mut self, library: &str, name: &str, func: LibraryShim) {
+		self.hle_functions.insert((String::from(library), String::from(name)), func);
+	}
+
+	/// Looks up the HLE version of a function, preferring one that was
+	/// registered for this specific library over a wildcard.
+	fn find_hle_function(&self, library: &str, name: &str) -> Option<LibraryShim> {
+		let name = String::from(name);
+		self.hle_functions.get(&(String::from(library), name.clone()))
+			.or_else(|| self.hle_functions.get(&(String::from(ANY_LIBRARY), name)))
+			.copied()
+	}
+
+	/// Points every import at the HLE function registered for it, if any.
+	fn resolve_shims(&mut self) {
+		for index in 0..self.imports.len() {
+			let import = &self.imports[index];
+			self.imports[index].func = self.find_hle_function(&import.library_name, &import.name);
 		}
+	}
 
-		// just allocate some space
-		let addr = self.heap.new_ptr(uc, 0x1000)?;
-		self.dyn_stubs.insert(String::from(name), addr);
-		Ok(Some(addr))
+	/// Library and symbol name of the function behind a shim call
+	fn shim_call_names(&self, call: backtrace::ShimCall) -> (&str, &str) {
+		match call {
+			backtrace::ShimCall::Import(index) => {
+				let import = &self.imports[index];
+				(&import.library_name, &import.name)
+			}
+			backtrace::ShimCall::Dynamic(index) => {
+				let (library, name, _) = &self.dyn_functions[index];
+				(library, name)
+			}
+		}
 	}
 
-	fn install_shim_function(&mut self, name: &str, func: LibraryShim) {
-		for import in &mut self.imports {
-			if import.name == name {
-				import.func = Some(func);
+	/// Calls a guest function through its transition vector from inside a
+	/// shim, and returns whatever it leaves in r3. The guest function can call
+	/// other shims (including this one), and the calling shim can still read
+	/// its own arguments afterwards.
+	fn call_guest(&mut self, uc: &mut EmuUC, tvect: u32, args: &[u32]) -> UcResult<u32> {
+		let pc = uc.pc_read()?;
+		let saved = VOLATILE_REGISTERS.iter()
+			.map(|&reg| uc.reg_read(reg).map(|value| (reg, value)))
+			.collect::<UcResult<Vec<_>>>()?;
+
+		let code = uc.read_u32(tvect)?;
+		let rtoc = uc.read_u32(tvect + 4)?;
+		let return_address = self.return_addr;
+
+		// give the callee a frame of its own below the shim's caller, with a
+		// parameter area in case it has more arguments than registers
+		let sp = uc.reg_read(RegisterPPC::R1)? as u32;
+		let frame_size = (24 + 4 * args.len().max(8) as u32 + 15) & !15;
+		let frame = (sp - frame_size) & !15;
+		uc.write_u32(frame, sp)?;
+		for (i, arg) in args.iter().enumerate() {
+			if i < 8 {
+				uc.reg_write(RegisterPPC::R3 as i32 + i as i32, (*arg).into())?;
 			}
+			uc.write_u32(frame + 24 + 4 * (i as u32), *arg)?;
+		}
+
+		uc.reg_write(RegisterPPC::R1, frame.into())?;
+		uc.reg_write(RegisterPPC::R2, rtoc.into())?;
+		uc.reg_write(RegisterPPC::R12, tvect.into())?;
+		uc.reg_write(RegisterPPC::LR, return_address.into())?;
+
+		// The hook that called this shim borrowed the state through its own
+		// clone of the Rc, and keeps that borrow until we return. Hooks in the
+		// nested run fetch the Rc from Unicorn afresh, so we hand them a cell
+		// of their own with the state moved into it. Only the placeholder sits in the
+		// outer cell meanwhile, and nothing can look at it before we swap back.
+		self.callback_depth += 1;
+		let placeholder = self.spare_state.take().map_or_else(EmuState::empty, |spare| *spare);
+		let nested = Rc::new(RefCell::new(std::mem::replace(self, placeholder)));
+		let outer = std::mem::replace(uc.get_data_mut(), Rc::clone(&nested));
+		let result = uc.emu_start(code.into(), return_address.into(), 0, 0);
+		*uc.get_data_mut() = outer;
+		std::mem::swap(self, &mut nested.borrow_mut());
+		if let Ok(placeholder) = Rc::try_unwrap(nested) {
+			self.spare_state.get_or_insert_with(|| Box::new(placeholder.into_inner()));
 		}
+		self.callback_depth -= 1;
 
-		self.hle_functions.insert(String::from(name), func);
+		if let Err(e) = result {
+			// leave the registers as they are so the crash can be reported
+			error!(target: "emulator", "Callback to {tvect:08X} failed: {e:?}");
+			self.callback_fault = Some(e);
+			uc.emu_stop()?;
+			return Err(e);
+		}
+
+		let value = uc.reg_read(RegisterPPC::R3)? as u32;
+		if self.exit_status.is_some() {
+			// exit() only stops the innermost run
+			uc.emu_stop()?;
+		}
+
+		for (reg, value) in saved {
+			uc.reg_write(reg, value)?;
+		}
+		uc.set_pc(pc)?;
+		Ok(value)
 	}
 
 	fn find_stub(&mut self, uc: &mut EmuUC, lib_name: &str, func_name: &str) -> UcResult<u32> {
-		if let Some(stub) = self.dyn_stubs.get(func_name) {
+		let key = (String::from(lib_name), String::from(func_name));
+		if let Some(stub) = self.dyn_stubs.get(&key) {
 			return Ok(*stub);
 		}
 
+		if let Some(addr) = data_imports::allocate(uc, self, func_name)? {
+			self.dyn_stubs.insert(key, addr);
+			return Ok(addr);
+		}
+
 		let stub = self.heap.new_ptr(uc, 12)?;
 		uc.write_u32(stub.into(), self.sc_thunk_addr)?;
-		self.dyn_stubs.insert(String::from(func_name), stub);
+		self.dyn_stubs.insert(key, stub);
 
-		if let Some(func) = self.hle_functions.get(func_name) {
+		if let Some(func) = self.find_hle_function(lib_name, func_name) {
 			let id = self.dyn_functions.len() as u32;
 			uc.write_u32((stub + 4).into(), id)?;
 			uc.write_u32((stub + 8).into(), 101)?;
 
-			self.dyn_functions.push(*func);
+			self.dyn_functions.push((String::from(lib_name), String::from(func_name), func));
 		} else {
 			warn!("Executable dynamically imports missing function from {lib_name}: {func_name}");
 			let id = self.missing_dyn_functions.len() as u32;
@@ -178,9 +418,43 @@ type EmuUC<'a> = Unicorn<'a, Rc<RefCell<EmuState>>>;
 fn code_hook(_uc: &mut EmuUC, _addr: u64, _size: u32) {
 }
 
+/// Runs an HLE function and passes its result back to the guest, tracing the
+/// call if that was asked for.
+fn dispatch_shim(uc: &mut EmuUC, state: &mut EmuState, call: backtrace::ShimCall, func: LibraryShim, lr: u32) {
+	let tracing = match &state.call_tracer {
+		Some(tracer) => {
+			let (library, name) = state.shim_call_names(call);
+			tracer.wants(library, name)
+		}
+		None => false
+	};
+
+	let mut arg_reader = if tracing { helpers::ArgReader::new_recording() } else { helpers::ArgReader::new() };
+	let result = func(uc, state, &mut arg_reader);
+
+	if tracing {
+		let (library, name) = state.shim_call_names(call);
+		let qualified_name = format!("{library}::{name}");
+		if let Some(tracer) = state.call_tracer.as_mut() {
+			tracer.record(&qualified_name, lr, &arg_reader.take_recorded(), &result);
+		}
+	}
+
+	match result {
+		Ok(Some(result)) => uc.reg_write(RegisterPPC::R3, result.into()).unwrap(),
+		Ok(None) => {},
+		Err(e) => {
+			let (library, name) = state.shim_call_names(call);
+			error!(target: "emulator", "Error {e:?} while executing {library}::{name} (lr={lr:08x})");
+		}
+	}
+}
+
 fn intr_hook(uc: &mut EmuUC, _number: u32) {
 	let tvect = uc.reg_read(RegisterPPC::R12).unwrap();
-	let rtoc = uc.reg_read(RegisterPPC::R2).unwrap();
+	// the shim's "TOC" is its index; read it from the vector rather than r2, as
+	// code-class imports don't go through glue that would load it
+	let rtoc = uc.read_u32((tvect + 4) as u32).unwrap();
 	let lr = uc.reg_read(RegisterPPC::LR).unwrap();
 	let pc = uc.pc_read().unwrap();
 	let code = uc.read_u32((tvect + 8) as u32).unwrap();
@@ -195,37 +469,34 @@ fn intr_hook(uc: &mut EmuUC, _number: u32) {
 		return;
 	}
 
+	let call = match code {
+		100 => Some(backtrace::ShimCall::Import(rtoc as usize)),
+		101 => Some(backtrace::ShimCall::Dynamic(rtoc as usize)),
+		_ => None
+	};
+	if let Some(call) = call {
+		if state.recent_calls.len() == backtrace::RECENT_CALL_COUNT {
+			state.recent_calls.pop_front();
+		}
+		state.recent_calls.push_back((lr as u32, call));
+	}
+
 	match code {
 		100 => match state.imports[rtoc as usize].func {
-			Some(func) => {
-				let mut arg_reader = helpers::ArgReader::new();
-				match func(uc, &mut state, &mut arg_reader) {
-					Ok(Some(result)) => uc.reg_write(RegisterPPC::R3, result.into()).unwrap(),
-					Ok(None) => {},
-					Err(e) => {
-						error!(target: "emulator", "Error {e:?} while executing {} (lr={lr:08x})", state.imports[rtoc as usize].name);
-					}
-				}
-			}
+			Some(func) => dispatch_shim(uc, &mut state, backtrace::ShimCall::Import(rtoc as usize), func, lr as u32),
 			None => {
-				error!(
-					target: "emulator",
-					"Unimplemented call to {}::{} @{lr:08X}",
-					state.imports[rtoc as usize].library_name,
-					state.imports[rtoc as usize].name
-				);
+				let state = &mut *state;
+				let symbol = &state.imports[rtoc as usize];
+				error!(target: "emulator", "Unimplemented call to {}::{} @{lr:08X}", symbol.library_name, symbol.name);
+
+				if let Some(tracer) = state.call_tracer.as_mut().filter(|t| t.wants(&symbol.library_name, &symbol.name)) {
+					tracer.record_unimplemented(&format!("{}::{}", symbol.library_name, symbol.name), lr as u32);
+				}
 			}
 		}
 		101 => {
-			let func = state.dyn_functions[rtoc as usize];
-			let mut arg_reader = helpers::ArgReader::new();
-			match func(uc, &mut state, &mut arg_reader) {
-				Ok(Some(result)) => uc.reg_write(RegisterPPC::R3, result.into()).unwrap(),
-				Ok(None) => {},
-				Err(e) => {
-					error!(target: "emulator", "Error {e:?} while executing {} (lr={lr:08x})", state.imports[rtoc as usize].name);
-				}
-			}
+			let func = state.dyn_functions[rtoc as usize].2;
+			dispatch_shim(uc, &mut state, backtrace::ShimCall::Dynamic(rtoc as usize), func, lr as u32);
 		}
 		404 => {
 			error!(
@@ -241,34 +512,353 @@ fn intr_hook(uc: &mut EmuUC, _number: u32) {
 		)
 	}
 
-	// NOTE: next unicorn will not need this i think?
-	uc.set_pc(pc + 4).unwrap();
+	limits::check_heap(uc, &mut state);
+
+	// a crashed callback left the registers pointing at the crash
+	if state.callback_fault.is_none() {
+		// NOTE: next unicorn will not need this i think?
+		uc.set_pc(pc + 4).unwrap();
+	}
 }
 
 
 type FuncResult = UcResult<Option<u32>>;
 
-fn dump_context(uc: &EmuUC) {
-	println!("  PC: {:08x} / LR: {:08x}", uc.pc_read().unwrap(), uc.reg_read(RegisterPPC::LR).unwrap());
-	println!("  R00: {:08x} / R08: {:08x} / R16: {:08x} / R24: {:08x}", uc.reg_read(RegisterPPC::R0).unwrap(), uc.reg_read(RegisterPPC::R8).unwrap(), uc.reg_read(RegisterPPC::R16).unwrap(), uc.reg_read(RegisterPPC::R24).unwrap());
-	println!("  R01: {:08x} / R09: {:08x} / R17: {:08x} / R25: {:08x}", uc.reg_read(RegisterPPC::R1).unwrap(), uc.reg_read(RegisterPPC::R9).unwrap(), uc.reg_read(RegisterPPC::R17).unwrap(), uc.reg_read(RegisterPPC::R25).unwrap());
-	println!("  R02: {:08x} / R10: {:08x} / R18: {:08x} / R26: {:08x}", uc.reg_read(RegisterPPC::R2).unwrap(), uc.reg_read(RegisterPPC::R10).unwrap(), uc.reg_read(RegisterPPC::R18).unwrap(), uc.reg_read(RegisterPPC::R26).unwrap());
-	println!("  R03: {:08x} / R11: {:08x} / R19: {:08x} / R27: {:08x}", uc.reg_read(RegisterPPC::R3).unwrap(), uc.reg_read(RegisterPPC::R11).unwrap(), uc.reg_read(RegisterPPC::R19).unwrap(), uc.reg_read(RegisterPPC::R27).unwrap());
-	println!("  R04: {:08x} / R12: {:08x} / R20: {:08x} / R28: {:08x}", uc.reg_read(RegisterPPC::R4).unwrap(), uc.reg_read(RegisterPPC::R12).unwrap(), uc.reg_read(RegisterPPC::R20).unwrap(), uc.reg_read(RegisterPPC::R28).unwrap());
-	println!("  R05: {:08x} / R13: {:08x} / R21: {:08x} / R29: {:08x}", uc.reg_read(RegisterPPC::R5).unwrap(), uc.reg_read(RegisterPPC::R13).unwrap(), uc.reg_read(RegisterPPC::R21).unwrap(), uc.reg_read(RegisterPPC::R29).unwrap());
-	println!("  R06: {:08x} / R14: {:08x} / R22: {:08x} / R30: {:08x}", uc.reg_read(RegisterPPC::R6).unwrap(), uc.reg_read(RegisterPPC::R14).unwrap(), uc.reg_read(RegisterPPC::R22).unwrap(), uc.reg_read(RegisterPPC::R30).unwrap());
-	println!("  R07: {:08x} / R15: {:08x} / R23: {:08x} / R31: {:08x}", uc.reg_read(RegisterPPC::R7).unwrap(), uc.reg_read(RegisterPPC::R15).unwrap(), uc.reg_read(RegisterPPC::R23).unwrap(), uc.reg_read(RegisterPPC::R31).unwrap());
+fn dump_context(out: &mut dyn Write, uc: &EmuUC) -> std::io::Result<()> {
+	writeln!(out, "  PC: {:08x} / LR: {:08x}", uc.pc_read().unwrap(), uc.reg_read(RegisterPPC::LR).unwrap())?;
+	writeln!(out, "  R00: {:08x} / R08: {:08x} / R16: {:08x} / R24: {:08x}", uc.reg_read(RegisterPPC::R0).unwrap(), uc.reg_read(RegisterPPC::R8).unwrap(), uc.reg_read(RegisterPPC::R16).unwrap(), uc.reg_read(RegisterPPC::R24).unwrap())?;
+	writeln!(out, "  R01: {:08x} / R09: {:08x} / R17: {:08x} / R25: {:08x}", uc.reg_read(RegisterPPC::R1).unwrap(), uc.reg_read(RegisterPPC::R9).unwrap(), uc.reg_read(RegisterPPC::R17).unwrap(), uc.reg_read(RegisterPPC::R25).unwrap())?;
+	writeln!(out, "  R02: {:08x} / R10: {:08x} / R18: {:08x} / R26: {:08x}", uc.reg_read(RegisterPPC::R2).unwrap(), uc.reg_read(RegisterPPC::R10).unwrap(), uc.reg_read(RegisterPPC::R18).unwrap(), uc.reg_read(RegisterPPC::R26).unwrap())?;
+	writeln!(out, "  R03: {:08x} / R11: {:08x} / R19: {:08x} / R27: {:08x}", uc.reg_read(RegisterPPC::R3).unwrap(), uc.reg_read(RegisterPPC::R11).unwrap(), uc.reg_read(RegisterPPC::R19).unwrap(), uc.reg_read(RegisterPPC::R27).unwrap())?;
+	writeln!(out, "  R04: {:08x} / R12: {:08x} / R20: {:08x} / R28: {:08x}", uc.reg_read(RegisterPPC::R4).unwrap(), uc.reg_read(RegisterPPC::R12).unwrap(), uc.reg_read(RegisterPPC::R20).unwrap(), uc.reg_read(RegisterPPC::R28).unwrap())?;
+	writeln!(out, "  R05: {:08x} / R13: {:08x} / R21: {:08x} / R29: {:08x}", uc.reg_read(RegisterPPC::R5).unwrap(), uc.reg_read(RegisterPPC::R13).unwrap(), uc.reg_read(RegisterPPC::R21).unwrap(), uc.reg_read(RegisterPPC::R29).unwrap())?;
+	writeln!(out, "  R06: {:08x} / R14: {:08x} / R22: {:08x} / R30: {:08x}", uc.reg_read(RegisterPPC::R6).unwrap(), uc.reg_read(RegisterPPC::R14).unwrap(), uc.reg_read(RegisterPPC::R22).unwrap(), uc.reg_read(RegisterPPC::R30).unwrap())?;
+	writeln!(out, "  R07: {:08x} / R15: {:08x} / R23: {:08x} / R31: {:08x}", uc.reg_read(RegisterPPC::R7).unwrap(), uc.reg_read(RegisterPPC::R15).unwrap(), uc.reg_read(RegisterPPC::R23).unwrap(), uc.reg_read(RegisterPPC::R31).unwrap())?;
+	Ok(())
+}
+
+/// Maps the linked image, giving each page the permissions of the regions that
+/// overlap it.
+fn map_executable(uc: &mut EmuUC, exe: &linker::Executable) -> UcResult<()> {
+	let page_count = (exe.memory.len() + linker::PAGE_SIZE - 1) / linker::PAGE_SIZE;
+	let mut pages = vec![Permission::NONE; page_count];
+
+	for region in exe.regions.iter().filter(|r| r.size > 0) {
+		let permission = match region.protection {
+			linker::Protection::ReadOnly => Permission::READ,
+			linker::Protection::ReadWrite => Permission::READ | Permission::WRITE,
+			linker::Protection::ReadExecute => Permission::READ | Permission::EXEC,
+			linker::Protection::ReadWriteExecute => Permission::ALL
+		};
+		let start = (region.address - exe.memory_base) as usize;
+		let end = start + region.size as usize;
+		for page in &mut pages[start / linker::PAGE_SIZE .. (end - 1) / linker::PAGE_SIZE + 1] {
+			*page |= permission;
+		}
+	}
+
+	let mut first = 0;
+	while first < page_count {
+		let mut last = first;
+		while last + 1 < page_count && pages[last + 1] == pages[first] {
+			last += 1;
+		}
+
+		let address = exe.memory_base as u64 + (first * linker::PAGE_SIZE) as u64;
+		uc.mem_map(address, (last + 1 - first) * linker::PAGE_SIZE, pages[first])?;
+		first = last + 1;
+	}
+
+	uc.mem_write(exe.memory_base as u64, &exe.memory)
+}
+
+/// Registers every HLE function and matches them up with the imports.
+fn install_all_shims(state: &mut EmuState) {
+	c_ctype::install_shims(state);
+	c_fenv::install_shims(state);
+	c_stdio::install_shims(state);
+	c_stdlib::install_shims(state);
+	c_string::install_shims(state);
+	c_time::install_shims(state);
+	flex_lm::install_shims(state);
+	interface_lib::install_shims(state);
+	mac_files::install_shims(state);
+	mac_fp::install_shims(state);
+	mac_gestalt::install_shims(state);
+	mac_low_mem::install_shims(state);
+	mac_memory::install_shims(state);
+	mac_os_utils::install_shims(state);
+	mac_quickdraw::install_shims(state);
+	mac_resources::install_shims(state);
+	mac_text_utils::install_shims(state);
+	std_c_lib::install_shims(state);
+	state.resolve_shims();
+}
+
+/// Points imports that have no HLE implementation at the shared library that
+/// defines them, or at 0 if they're weak and nothing defines them, by
+/// rewriting every word that was relocated against the shim.
+fn bind_imports(uc: &mut EmuUC, state: &mut EmuState, exe: &linker::Executable) -> UcResult<()> {
+	for (symbol, import) in state.imports.iter_mut().zip(&exe.imports) {
+		if symbol.func.is_some() || symbol.claimed {
+			if import.definition.is_some() {
+				debug!(target: "emulator", "Using HLE version of {}::{} instead of the shared library", symbol.library_name, symbol.name);
+			}
+			continue;
+		}
+
+		let (target, binding) = match import.definition {
+			Some(definition) => (definition, Binding::Library(definition)),
+			None if import.weak => {
+				debug!(target: "emulator", "Weak import {}::{} is unresolved", symbol.library_name, symbol.name);
+				(0, Binding::Unresolved)
+			}
+			None => continue
+		};
+
+		for &site in &import.sites {
+			let value = uc.read_u32(site)?;
+			uc.write_u32(site, value.wrapping_sub(import.shim_addr).wrapping_add(target))?;
+		}
+		symbol.binding = binding;
+	}
+
+	Ok(())
+}
+
+/// Builds a CFragSystem7InitBlock describing a fragment, for passing to its
+/// initialization routine.
+fn build_init_block(uc: &mut EmuUC, state: &mut EmuState, fragment: &linker::LoadedFragment) -> UcResult<u32> {
+	let block = state.heap.new_ptr(uc, 0x24)?;
+
+	// contextID, closureID and connectionID are left as zero, nobody should
+	// be looking at them
+
+	// fragLocator: we pretend everything was loaded from the data fork
+	uc.write_u32(block + 0xC, 1)?; // kDataForkCFragLocator
+
+	let path = std::fs::canonicalize(&fragment.path).ok();
+	if let Some(info) = path.as_ref().and_then(|p| state.filesystem.spec(p).ok()) {
+		let spec = state.heap.new_ptr(uc, 70)?;
+		uc.write_i16(spec, info.volume_ref)?;
+		uc.write_i32(spec + 2, info.parent_id)?;
+		uc.write_pascal_string(spec + 6, &info.node_name)?;
+		uc.write_u32(block + 0x10, spec)?;
+
+		let lib_name = state.heap.new_ptr(uc, info.node_name.len() as u32 + 1)?;
+		uc.write_pascal_string(lib_name, &info.node_name)?;
+		uc.write_u32(block + 0x1C, lib_name)?;
+	}
+
+	// offset and length stay at 0, which means the whole fork
+
+	Ok(block)
+}
+
+/// Calls a guest function through its transition vector, with a fresh stack,
+/// and returns whatever it leaves in r3.
+fn call_transition_vector(uc: &mut EmuUC, exe: &linker::Executable, tvect: u32, args: &[u32]) -> UcResult<u32> {
+	let code = uc.read_u32(tvect)?;
+	let rtoc = uc.read_u32(tvect + 4)?;
+	let return_address = exe.return_addr;
+
+	uc.reg_write(RegisterPPC::R1, (exe.stack_addr + exe.stack_size - 0x20).into())?;
+	uc.reg_write(RegisterPPC::R2, rtoc.into())?;
+	uc.reg_write(RegisterPPC::LR, return_address.into())?;
+	for (i, arg) in args.iter().enumerate() {
+		uc.reg_write(RegisterPPC::R3 as i32 + i as i32, (*arg).into())?;
+	}
+
+	uc.emu_start(code.into(), return_address.into(), 0, 0)?;
+	Ok(uc.reg_read(RegisterPPC::R3)? as u32)
+}
+
+/// Runs one of the executable's entry points. Returns None if the program
+/// called exit() while it was running.
+fn run_entry_point(uc: &mut EmuUC, state: &RefCell<EmuState>, exe: &linker::Executable, what: &str, tvect: u32, args: &[u32]) -> UcResult<Option<u32>> {
+	let result = call_transition_vector(uc, exe, tvect, args);
+	finish_entry_point(uc, state, exe, what, result)
+}
+
+/// Carries on with an entry point from wherever a snapshot left it.
+fn resume_entry_point(uc: &mut EmuUC, state: &RefCell<EmuState>, exe: &linker::Executable, what: &str) -> UcResult<Option<u32>> {
+	let pc = uc.pc_read()?;
+	let result = uc.emu_start(pc, exe.return_addr.into(), 0, 0)
+		.and_then(|_| Ok(uc.reg_read(RegisterPPC::R3)? as u32));
+	finish_entry_point(uc, state, exe, what, result)
+}
+
+fn finish_entry_point(uc: &mut EmuUC, state: &RefCell<EmuState>, exe: &linker::Executable, what: &str, result: UcResult<u32>) -> UcResult<Option<u32>> {
+	let result = result.and_then(|value| {
+		match state.borrow_mut().callback_fault.take() {
+			Some(e) => Err(e),
+			None => Ok(value)
+		}
+	});
+
+	match result {
+		Ok(result) => {
+			if state.borrow().exit_status.is_some() {
+				Ok(None)
+			} else {
+				Ok(Some(result))
+			}
+		}
+		Err(e) => {
+			if state.borrow().exit_status.is_some() {
+				Ok(None)
+			} else {
+				error!(target: "emulator", "{what} execution failed: {e:?}");
+				// crash reports go wherever the tool's errors do
+				let mut report = Vec::new();
+				let _ = dump_context(&mut report, uc)
+					.and_then(|()| backtrace::print(&mut report, uc, &state.borrow(), exe));
+				let _ = state.borrow_mut().console.stderr.write_all(&report);
+				let gdb = state.borrow().gdb.clone();
+				if let Some(gdb) = gdb {
+					gdb.borrow_mut().report_fault(uc);
+				}
+				Err(e)
+			}
+		}
+	}
+}
+
+/// How an import would be satisfied if the executable were run
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum ImportStatus {
+	/// There's an HLE implementation
+	Implemented,
+	/// A known data symbol that we set up ourselves
+	Data,
+	/// Defined by a shared library found on the library path
+	Library,
+	/// Nothing provides it, but it's weak so the executable should cope
+	Weak,
+	/// Nothing provides it, so using it will fail
+	Missing
+}
+
+/// Works out what would happen to each of the executable's imports, in the
+/// same order as `exe.imports`, without running anything.
+pub fn import_coverage(exe: &linker::Executable, resources: Resources) -> Vec<ImportStatus> {
+	let mut state = EmuState::new(exe, resources);
+	install_all_shims(&mut state);
+
+	state.imports.iter().zip(&exe.imports).map(|(symbol, import)| {
+		let is_data = matches!(symbol.class, pef::SymbolClass::Data | pef::SymbolClass::TOC);
+		if symbol.func.is_some() {
+			ImportStatus::Implemented
+		} else if is_data && data_imports::size_of(&symbol.name).is_some() {
+			ImportStatus::Data
+		} else if import.definition.is_some() {
+			ImportStatus::Library
+		} else if import.weak {
+			ImportStatus::Weak
+		} else {
+			ImportStatus::Missing
+		}
+	}).collect()
+}
+
+/// Maps the heap, binds the imports and runs every fragment's initialisation
+/// routine. Returns false if one of them failed.
+fn start_afresh(uc: &mut EmuUC, state: &RefCell<EmuState>, exe: &linker::Executable) -> UcResult<bool> {
+	{
+		let mut state = state.borrow_mut();
+
+		state.heap.init(uc)?;
+
+		// inject shim functions
+		install_all_shims(&mut state);
+
+		data_imports::initialize(uc, &mut state)?;
+		bind_imports(uc, &mut state, exe)?;
+
+		let mut missing = 0;
+		for symbol in &state.imports {
+			let is_function = matches!(symbol.class, pef::SymbolClass::TVect | pef::SymbolClass::Code | pef::SymbolClass::Glue);
+			if is_function && symbol.func.is_none() && symbol.binding == Binding::Shim {
+				warn!(target: "emulator", "Executable imports unimplemented function from {}: {}", symbol.library_name, symbol.name);
+				missing += 1;
+			}
+
+			let is_data = matches!(symbol.class, pef::SymbolClass::Data | pef::SymbolClass::TOC);
+			if is_data && !symbol.claimed && symbol.binding == Binding::Shim {
+				warn!(target: "emulator", "Executable imports unknown data from {}: {} (given {} blank bytes)", symbol.library_name, symbol.name, linker::UNKNOWN_DATA_SIZE);
+			}
+		}
+		if missing > 0 {
+			warn!(target: "emulator", "{missing} imported functions are unimplemented, calling any of them will fail");
+		}
+	}
+
+	// Shared libraries are always loaded after whatever imports them, so
+	// initialise them in reverse order
+	for fragment in exe.fragments.iter().rev() {
+		if fragment.init_vector == 0 || state.borrow().exit_status.is_some() {
+			continue;
+		}
+
+		let init_block = build_init_block(uc, &mut state.borrow_mut(), fragment)?;
+		debug!(target: "emulator", "Init {}: tvect={:08X}, init_block={init_block:08X}", fragment.name, fragment.init_vector);
+
+		if let Some(result) = run_entry_point(uc, state, exe, "Init", fragment.init_vector, &[init_block])? {
+			let err = result as u16 as i16;
+			if err != 0 {
+				error!(target: "emulator", "Initialization routine for {} failed with error {err}", fragment.name);
+				return Ok(false);
+			}
+		}
+	}
+
+	Ok(true)
+}
+
+/// Saves a snapshot, complaining if it can't.
+fn take_snapshot(uc: &EmuUC, state: &EmuState, exe: &linker::Executable, phase: snapshot::Phase, path: &std::path::Path) {
+	match snapshot::save(uc, state, exe, phase, path) {
+		Ok(()) => info!(target: "emulator", "Saved snapshot to {path:?}"),
+		Err(e) => error!(target: "emulator", "Cannot save snapshot to {path:?}: {e:?}")
+	}
+}
+
+pub fn emulate(exe: &linker::Executable, resources: Resources, args: &[String], env_vars: &[(String, String)], options: Options) -> UcResult<i32> {
+	emulate_after_init(exe, resources, options, || Some(Job {
+		args: args.to_vec(),
+		env_vars: env_vars.to_vec(),
+		current_dir: None
+	}))
 }
 
-pub fn emulate(exe: &linker::Executable, resources: Resources, args: &[String], env_vars: &[(String, String)]) -> UcResult<i32> {
+/// Like `emulate`, but only asks for the arguments once the libraries have
+/// been initialised, so a server can fork at that point. If `next_job`
+/// returns None, main isn't run.
+pub fn emulate_after_init(exe: &linker::Executable, resources: Resources, mut options: Options, next_job: impl FnOnce() -> Option<Job>) -> UcResult<i32> {
 	let state = Rc::new(RefCell::new(EmuState::new(exe, resources)));
+	{
+		let mut state = state.borrow_mut();
+		state.console = std::mem::take(&mut options.console);
+		let current_dir = options.current_dir.take()
+			.or_else(|| std::env::current_dir().ok())
+			.unwrap_or_default();
+		state.filesystem.set_current_dir(current_dir);
+		state.max_heap = options.limits.max_heap;
+		if options.sanitize_heap {
+			// Snapshots don't record the sanitizer's bookkeeping
+			if options.resume.is_some() || options.snapshot.is_some() {
+				warn!(target: "emulator", "The heap sanitizer can't be used with snapshots, so it's off for this run");
+			} else {
+				state.heap.enable_sanitizer();
+			}
+		}
+	}
 	let mut uc = Unicorn::new_with_data(Arch::PPC, Mode::BIG_ENDIAN | Mode::PPC32, Rc::clone(&state))?;
 
 	// place some garbage at 0 because DeRez derefs a null pointer
 	uc.mem_map(0, 0x1000, Permission::READ)?;
 
-	uc.mem_map(exe.memory_base as u64, (exe.memory.len() + 0x3FFF) & !0x3FFF, Permission::ALL)?;
-	uc.mem_write(exe.memory_base as u64, &exe.memory)?;
+	map_executable(&mut uc, exe)?;
 
 	// enable floating point
 	uc.reg_write(RegisterPPC::MSR, uc.reg_read(RegisterPPC::MSR)? | (1 << 13))?;
@@ -278,70 +868,189 @@ pub fn emulate(exe: &linker::Executable, resources: Resources, args: &[String],
 
 	// uc.add_code_hook(0, 0xFFFFFFFF, code_hook)?;
 	uc.add_intr_hook(intr_hook)?;
+	limits::install(&mut uc, &options.limits)?;
 
-	let exec_end_address = exe.memory_end_addr();
+	if let Some(trace_options) = &options.trace_calls {
+		match call_trace::CallTracer::new(trace_options) {
+			Ok(tracer) => state.borrow_mut().call_tracer = Some(tracer),
+			Err(e) => {
+				error!(target: "emulator", "Cannot open call trace output: {e}");
+				return Ok(1);
+			}
+		}
+	}
 
-	{
-		let mut state = state.borrow_mut();
+	if let Some(port) = options.gdb_port {
+		let stub = match gdb::GdbStub::listen(port) {
+			Ok(stub) => Shared::new(stub),
+			Err(e) => {
+				error!(target: "emulator", "Cannot start GDB server on port {port}: {e}");
+				return Ok(1);
+			}
+		};
+		state.borrow_mut().gdb = Some(stub.clone());
+		uc.add_code_hook(0, 0xFFFFFFFF, move |uc, addr, _size| {
+			stub.borrow_mut().on_instruction(uc, addr as u32);
+		})?;
+	}
 
-		state.heap.init(&mut uc)?;
+	uc.add_mem_hook(HookType::MEM_PROT, 0, u64::MAX, move |uc, access, address, size, _value| {
+		let what = match access {
+			MemType::WRITE_PROT => "Write to",
+			MemType::FETCH_PROT => "Execution of",
+			_ => "Read from"
+		};
+		let location = match exe.region_for(address as u32) {
+			Some(region) => format!("{} + {:X}", region.name, address as u32 - region.address),
+			None => String::from("outside any section")
+		};
+		error!(
+			target: "emulator",
+			"{what} protected memory at {address:08X} ({size} bytes, {location}), pc={:08X} lr={:08X}",
+			uc.pc_read().unwrap(), uc.reg_read(RegisterPPC::LR).unwrap());
+		false
+	})?;
 
-		// populate IntEnv
-		c_stdlib::setup_environment(&mut uc, &mut state, args, env_vars)?;
+	let phase = match &options.resume {
+		Some(path) => {
+			let mut state = state.borrow_mut();
+			install_all_shims(&mut state);
+			match snapshot::restore(&mut uc, &mut state, exe, path) {
+				Ok(phase) => {
+					info!(target: "emulator", "Resuming from snapshot {path:?}");
+					phase
+				}
+				Err(e) => {
+					error!(target: "emulator", "Cannot resume from snapshot {path:?}: {e:?}");
+					return Ok(1);
+				}
+			}
+		}
+		None => {
+			if !start_afresh(&mut uc, &state, exe)? {
+				return Ok(1);
+			}
+			snapshot::Phase::AfterInit
+		}
+	};
 
-		// inject shim functions
-		c_ctype::install_shims(&mut uc, &mut state)?;
-		c_fenv::install_shims(&mut state);
-		c_stdio::install_shims(&mut uc, &mut state)?;
-		c_stdlib::install_shims(&mut uc, &mut state)?;
-		c_string::install_shims(&mut state);
-		c_time::install_shims(&mut state);
-		flex_lm::install_shims(&mut state);
-		interface_lib::install_shims(&mut state);
-		mac_files::install_shims(&mut state);
-		mac_fp::install_shims(&mut state);
-		mac_gestalt::install_shims(&mut state);
-		mac_low_mem::install_shims(&mut state);
-		mac_memory::install_shims(&mut state);
-		mac_os_utils::install_shims(&mut state);
-		mac_quickdraw::install_shims(&mut state);
-		mac_resources::install_shims(&mut state);
-		mac_text_utils::install_shims(&mut state);
-		std_c_lib::install_shims(&mut state);
+	let snapshot_options = options.snapshot.as_ref();
+	let mut snapshot_hook = None;
+	if let Some(snapshot_options) = snapshot_options {
+		if snapshot_options.at == "init" {
+			if phase == snapshot::Phase::AfterInit {
+				take_snapshot(&uc, &state.borrow(), exe, snapshot::Phase::AfterInit, &snapshot_options.path);
+			}
+		} else {
+			let target = match snapshot::resolve_target(&uc, exe, &snapshot_options.at) {
+				Ok(target) => target,
+				Err(e) => {
+					error!(target: "emulator", "Cannot take a snapshot at {}: {e}", snapshot_options.at);
+					return Ok(1);
+				}
+			};
+			let path = snapshot_options.path.clone();
+			let mut taken = false;
+			snapshot_hook = Some(uc.add_code_hook(target.into(), target.into(), move |uc, _addr, _size| {
+				if taken {
+					return;
+				}
+				let state = Rc::clone(uc.get_data());
+				let state = state.borrow();
+				if state.callback_depth > 0 {
+					// the shim that made the callback can't be saved
+					warn!(target: "emulator", "Not taking a snapshot inside a callback");
+					return;
+				}
+				take_snapshot(uc, &state, exe, snapshot::Phase::InMain, &path);
+				taken = true;
+			})?);
+		}
+	}
 
-		for symbol in &state.imports {
-			if symbol.func.is_none() && symbol.class == pef::SymbolClass::TVect {
-				warn!(target: "emulator", "Executable imports unimplemented function from {}: {}", symbol.library_name, symbol.name);
+	if phase == snapshot::Phase::AfterInit {
+		let job = match next_job() {
+			Some(job) => job,
+			None => return Ok(0)
+		};
+
+		if let Some(current_dir) = job.current_dir {
+			state.borrow_mut().filesystem.set_current_dir(current_dir);
+		}
+
+		// set up argv and the environment
+		c_stdlib::setup_environment(&mut uc, &mut state.borrow_mut(), &job.args, &job.env_vars)?;
+	}
+
+	if exe.main_vector > 0 && state.borrow().exit_status.is_none() {
+		match phase {
+			snapshot::Phase::AfterInit => {
+				debug!(target: "emulator", "Main: tvect={:08X}", exe.main_vector);
+				run_entry_point(&mut uc, &state, exe, "Main", exe.main_vector, &[])?;
+			}
+			snapshot::Phase::InMain => {
+				debug!(target: "emulator", "Main: resuming at {:08X}", uc.pc_read()?);
+				resume_entry_point(&mut uc, &state, exe, "Main")?;
 			}
 		}
+
+		// returning from main is the same as calling exit()
+		while state.borrow().exit_status.is_none() {
+			let handler = match state.borrow_mut().atexit_handlers.pop() {
+				Some(handler) => handler,
+				None => break
+			};
+			run_entry_point(&mut uc, &state, exe, "atexit handler", handler, &[])?;
+		}
 	}
 
-	if exe.init_vector > 0 {
-		// TODO: C++ binaries will probably need this
-		let code = exe.get_u32(exe.init_vector);
-		let rtoc = exe.get_u32(exe.init_vector + 4);
-		warn!(target: "emulator", "Init: code={:08X}, rtoc={:08x}", code, rtoc);
-		warn!(target: "emulator", " !!! Not implemented !!!");
+	if let Some(hook) = snapshot_hook {
+		uc.remove_hook(hook)?;
 	}
 
-	if exe.main_vector > 0 {
-		let code = exe.get_u32(exe.main_vector);
-		let rtoc = exe.get_u32(exe.main_vector + 4);
-		debug!(target: "emulator", "Main: code={:08X}, rtoc={:08x}", code, rtoc);
+	if !state.borrow().quick_exit {
+		c_stdio::close_all(&mut state.borrow_mut());
+	}
 
-		uc.reg_write(RegisterPPC::R2, rtoc.into())?;
-		uc.reg_write(RegisterPPC::LR, exec_end_address.into())?; // LR
-
-		if let Err(e) = uc.emu_start(code.into(), exec_end_address.into(), 0, 0) {
-			let state = state.borrow();
-			if state.exit_status.is_none() {
-				error!(target: "emulator", "Main execution failed: {:?}", e);
-				dump_context(&uc);
-				return Err(e);
-			}
+	for fragment in &exe.fragments {
+		// a tool that ran out of time or memory can't be trusted to clean up
+		if fragment.term_vector == 0 || state.borrow().limit_hit.is_some() {
+			continue;
+		}
+
+		debug!(target: "emulator", "Term {}: tvect={:08X}", fragment.name, fragment.term_vector);
+
+		// exit() may have been called already, so hide that from the hooks
+		// while the termination routine runs
+		let exit_status = state.borrow_mut().exit_status.take();
+		if let Err(e) = run_entry_point(&mut uc, &state, exe, "Term", fragment.term_vector, &[]) {
+			warn!(target: "emulator", "Ignoring failure in termination routine for {}: {e:?}", fragment.name);
+		}
+
+		let mut state = state.borrow_mut();
+		if exit_status.is_some() {
+			state.exit_status = exit_status;
 		}
 	}
 
+	{
+		let mut state = state.borrow_mut();
+		// abort(), _exit() and hitting a limit leave unsaved files and resources
+		// as they are
+		if !state.quick_exit {
+			mac_resources::update_all(&mut uc, &mut state)?;
+			state.filesystem.save_all();
+		}
+		state.heap.check_at_exit(&uc)?;
+	}
+
+	limits::report(&uc, &mut state.borrow_mut(), exe);
 	let exit_status = state.borrow().exit_status.unwrap_or(0);
+	if let Some(tracer) = state.borrow_mut().call_tracer.as_mut() {
+		tracer.flush();
+	}
+	if let Some(gdb) = state.borrow().gdb.as_ref() {
+		gdb.borrow_mut().report_exit(exit_status);
+	}
 	Ok(exit_status)
 }
@@ -0,0 +1,352 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use unicorn_engine::RegisterPPC;
+use unicorn_engine::unicorn_const::{Permission, uc_error};
+
+use crate::{common::{FourCC, OSErr}, filesystem, linker, pef, resources, snapshot::{SnapshotReader, SnapshotWriter}};
+
+use super::{Binding, EmuState, EmuUC, c_stdio, flex_lm, heap, helpers::UnicornExtras, mac_files, mac_resources};
+
+/// Settings for `--snapshot-at`
+pub struct SnapshotOptions {
+	/// `init` for right after the libraries have been initialised, otherwise
+	/// the name or address of a function to stop at the first time it's called
+	pub at: String,
+	pub path: PathBuf
+}
+
+/// How far the run had got when a snapshot was taken
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) enum Phase {
+	/// The libraries are initialised but main hasn't started, so resuming
+	/// can use a new set of arguments
+	AfterInit,
+	/// Somewhere inside main
+	InMain
+}
+
+const PAGE_SIZE: usize = 0x1000;
+
+fn uc_err(e: uc_error) -> anyhow::Error {
+	anyhow!("unicorn error {e:?}")
+}
+
+/// Identifies the executable, so a snapshot isn't restored on top of another one
+fn fingerprint(exe: &linker::Executable) -> u32 {
+	crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&exe.memory) ^ exe.memory_base
+}
+
+fn registers() -> impl Iterator<Item = i32> {
+	let gprs = (0..32).map(|i| RegisterPPC::R0 as i32 + i);
+	let fprs = (0..32).map(|i| RegisterPPC::FPR0 as i32 + i);
+	let special = [RegisterPPC::PC, RegisterPPC::LR, RegisterPPC::CTR, RegisterPPC::XER, RegisterPPC::CR, RegisterPPC::MSR, RegisterPPC::FPSCR];
+	gprs.chain(fprs).chain(special.into_iter().map(|reg| reg as i32))
+}
+
+/// Works out where `--snapshot-at` refers to: a hex address, or something
+/// exported by one of the loaded fragments.
+pub(super) fn resolve_target(uc: &EmuUC, exe: &linker::Executable, at: &str) -> Result<u32> {
+	if let Some(hex) = at.strip_prefix("0x") {
+		return Ok(u32::from_str_radix(hex, 16)?);
+	}
+
+	for fragment in &exe.fragments {
+		match fragment.find_export(at) {
+			Some((address, pef::SymbolClass::TVect)) => return uc.read_u32(address).map_err(uc_err),
+			Some((address, pef::SymbolClass::Code)) => return Ok(address),
+			Some((_, class)) => return Err(anyhow!("{at} is {class:?}, not a function")),
+			None => {}
+		}
+	}
+	Err(anyhow!("no fragment exports {at}"))
+}
+
+fn save_memory(w: &mut SnapshotWriter, uc: &EmuUC) -> Result<()> {
+	let regions = uc.mem_regions().map_err(uc_err)?;
+	w.count(regions.len());
+
+	for region in regions {
+		let data = uc.mem_read_as_vec(region.begin, (region.end - region.begin + 1) as usize).map_err(uc_err)?;
+		w.u64(region.begin);
+		w.u64(region.end);
+		w.u32(region.perms.bits());
+
+		// most of the heap and stack is still zeroed, so leave that out
+		let pages = data.chunks(PAGE_SIZE).enumerate().filter(|(_, page)| page.iter().any(|&b| b != 0)).collect::<Vec<_>>();
+		w.count(pages.len());
+		for (index, page) in pages {
+			w.count(index);
+			w.bytes(page);
+		}
+	}
+
+	Ok(())
+}
+
+fn restore_memory(r: &mut SnapshotReader, uc: &mut EmuUC) -> Result<()> {
+	for region in uc.mem_regions().map_err(uc_err)? {
+		uc.mem_unmap(region.begin, (region.end - region.begin + 1) as usize).map_err(uc_err)?;
+	}
+
+	for _ in 0..r.count()? {
+		let (begin, end) = (r.u64()?, r.u64()?);
+		let perms = Permission::from_bits_truncate(r.u32()?);
+		uc.mem_map(begin, (end - begin + 1) as usize, perms).map_err(uc_err)?;
+
+		for _ in 0..r.count()? {
+			let offset = (r.count()? * PAGE_SIZE) as u64;
+			uc.mem_write(begin + offset, &r.bytes()?).map_err(uc_err)?;
+		}
+	}
+
+	Ok(())
+}
+
+fn save_state(w: &mut SnapshotWriter, state: &EmuState) {
+	// these come first, as files further down refer to them
+	state.heap.save_snapshot(w);
+	state.filesystem.save_snapshot(w);
+
+	w.count(state.dyn_stubs.len());
+	for ((library, name), &addr) in &state.dyn_stubs {
+		w.str(library);
+		w.str(name);
+		w.u32(addr);
+	}
+	w.count(state.dyn_functions.len());
+	for (library, name, _) in &state.dyn_functions {
+		w.str(library);
+		w.str(name);
+	}
+	w.count(state.missing_dyn_functions.len());
+	for (library, name) in &state.missing_dyn_functions {
+		w.str(library);
+		w.str(name);
+	}
+
+	w.count(state.imports.len());
+	for import in &state.imports {
+		w.bool(import.claimed);
+		match import.binding {
+			Binding::Shim => w.u8(0),
+			Binding::Library(address) => {
+				w.u8(1);
+				w.u32(address);
+			}
+			Binding::Unresolved => w.u8(2)
+		}
+	}
+
+	w.bool(state.dummy_cursor_handle.is_some());
+	w.u32(state.dummy_cursor_handle.unwrap_or(0));
+
+	w.count(state.resource_files.len());
+	for (&ref_num, resources) in &state.resource_files {
+		w.u16(ref_num);
+		// the tool's own resources come from the tool when we resume
+		if ref_num != mac_resources::APPLICATION_RES_FILE {
+			w.path(&resources.file.borrow().path);
+			w.bytes(&resources.pack());
+		}
+	}
+	w.u16(state.active_resource_file);
+	w.u16(state.next_resource_file);
+	w.count(state.loaded_resources.len());
+	for (&(ref_num, ty, id), &handle) in &state.loaded_resources {
+		w.u16(ref_num);
+		w.u32(ty.0);
+		w.i16(id);
+		w.u32(handle);
+	}
+
+	w.count(state.env_var_map.len());
+	for (name, &ptr) in &state.env_var_map {
+		w.str(name);
+		w.u32(ptr);
+	}
+	w.u32(state.argc);
+	w.u32(state.argv);
+	w.u32(state.strtok_state);
+
+	w.count(state.stdio_files.len());
+	for (&ptr, file) in &state.stdio_files {
+		w.u32(ptr);
+		file.save_snapshot(w);
+	}
+	w.count(state.file_handles.len());
+	for (&ref_num, handle) in &state.file_handles {
+		w.u16(ref_num);
+		handle.save_snapshot(w);
+	}
+	w.u16(state.next_file_handle);
+
+	w.u32(state.next_checkout);
+	w.count(state.checkouts.len());
+	for (&id, checkout) in &state.checkouts {
+		w.u32(id);
+		checkout.save_snapshot(w);
+	}
+
+	w.bool(state.exit_status.is_some());
+	w.i32(state.exit_status.unwrap_or(0));
+	w.bool(state.quick_exit);
+	w.count(state.atexit_handlers.len());
+	for &handler in &state.atexit_handlers {
+		w.u32(handler);
+	}
+
+	w.i16(state.mem_error as i16);
+	w.i16(state.res_error as i16);
+}
+
+fn restore_state(r: &mut SnapshotReader, state: &mut EmuState) -> Result<()> {
+	state.heap = heap::Heap::restore_snapshot(r)?;
+	let current_dir = state.filesystem.current_dir().to_path_buf();
+	state.filesystem = filesystem::FileSystem::restore_snapshot(r, current_dir)?;
+
+	state.dyn_stubs.clear();
+	for _ in 0..r.count()? {
+		let key = (r.str()?, r.str()?);
+		state.dyn_stubs.insert(key, r.u32()?);
+	}
+	state.dyn_functions.clear();
+	for _ in 0..r.count()? {
+		let (library, name) = (r.str()?, r.str()?);
+		let func = state.find_hle_function(&library, &name)
+			.ok_or_else(|| anyhow!("snapshot uses {library}::{name}, which isn't implemented any more"))?;
+		state.dyn_functions.push((library, name, func));
+	}
+	state.missing_dyn_functions.clear();
+	for _ in 0..r.count()? {
+		state.missing_dyn_functions.push((r.str()?, r.str()?));
+	}
+
+	if r.count()? != state.imports.len() {
+		return Err(anyhow!("snapshot has a different number of imports"));
+	}
+	for import in &mut state.imports {
+		import.claimed = r.bool()?;
+		import.binding = match r.u8()? {
+			0 => Binding::Shim,
+			1 => Binding::Library(r.u32()?),
+			2 => Binding::Unresolved,
+			kind => return Err(anyhow!("unknown import binding {kind}"))
+		};
+	}
+
+	let has_cursor = r.bool()?;
+	let cursor = r.u32()?;
+	state.dummy_cursor_handle = has_cursor.then_some(cursor);
+
+	let application = state.resource_files.remove(&mac_resources::APPLICATION_RES_FILE);
+	state.resource_files.clear();
+	for _ in 0..r.count()? {
+		let ref_num = r.u16()?;
+		let resources = if ref_num == mac_resources::APPLICATION_RES_FILE {
+			application.as_ref().map(|res| resources::Resources {
+				file: res.file.clone(),
+				attributes: res.attributes,
+				types: res.types.clone()
+			}).ok_or_else(|| anyhow!("the tool's resources are missing"))?
+		} else {
+			let file = state.filesystem.get_file(&r.path()?)?;
+			resources::parse_resource_fork(file, &r.bytes()?).map_err(|e| anyhow!("cannot parse resource file {ref_num}: {e:?}"))?
+		};
+		state.resource_files.insert(ref_num, resources);
+	}
+	state.active_resource_file = r.u16()?;
+	state.next_resource_file = r.u16()?;
+	state.loaded_resources.clear();
+	for _ in 0..r.count()? {
+		let key = (r.u16()?, FourCC(r.u32()?), r.i16()?);
+		state.loaded_resources.insert(key, r.u32()?);
+	}
+
+	state.env_var_map.clear();
+	for _ in 0..r.count()? {
+		let name = r.str()?;
+		state.env_var_map.insert(name, r.u32()?);
+	}
+	state.argc = r.u32()?;
+	state.argv = r.u32()?;
+	state.strtok_state = r.u32()?;
+
+	state.stdio_files.clear();
+	for _ in 0..r.count()? {
+		let ptr = r.u32()?;
+		let file = c_stdio::CFile::restore_snapshot(r, &mut state.filesystem)?;
+		state.stdio_files.insert(ptr, file);
+	}
+	state.file_handles.clear();
+	for _ in 0..r.count()? {
+		let ref_num = r.u16()?;
+		let handle = mac_files::FileHandle::restore_snapshot(r, &mut state.filesystem)?;
+		state.file_handles.insert(ref_num, handle);
+	}
+	state.next_file_handle = r.u16()?;
+
+	state.next_checkout = r.u32()?;
+	state.checkouts.clear();
+	for _ in 0..r.count()? {
+		let id = r.u32()?;
+		state.checkouts.insert(id, flex_lm::Checkout::restore_snapshot(r)?);
+	}
+
+	let exited = r.bool()?;
+	let exit_status = r.i32()?;
+	state.exit_status = exited.then_some(exit_status);
+	state.quick_exit = r.bool()?;
+	state.atexit_handlers.clear();
+	for _ in 0..r.count()? {
+		state.atexit_handlers.push(r.u32()?);
+	}
+
+	state.mem_error = OSErr::from_i16(r.i16()?).unwrap_or(OSErr::NoError);
+	state.res_error = OSErr::from_i16(r.i16()?).unwrap_or(OSErr::NoError);
+	Ok(())
+}
+
+/// Writes out guest memory, registers and everything in `state` that the
+/// guest could be relying on.
+pub(super) fn save(uc: &EmuUC, state: &EmuState, exe: &linker::Executable, phase: Phase, path: &Path) -> Result<()> {
+	let mut w = SnapshotWriter::new();
+	w.u32(fingerprint(exe));
+	w.u8(phase as u8);
+
+	save_memory(&mut w, uc)?;
+	for reg in registers() {
+		w.u64(uc.reg_read(reg).map_err(uc_err)?);
+	}
+	save_state(&mut w, state);
+
+	std::fs::write(path, w.into_bytes())?;
+	Ok(())
+}
+
+/// Puts everything back the way it was when the snapshot was taken. The
+/// shims have to be installed already.
+pub(super) fn restore(uc: &mut EmuUC, state: &mut EmuState, exe: &linker::Executable, path: &Path) -> Result<Phase> {
+	let data = std::fs::read(path)?;
+	let mut r = SnapshotReader::new(&data)?;
+
+	if r.u32()? != fingerprint(exe) {
+		return Err(anyhow!("snapshot was taken from a different executable"));
+	}
+	let phase = match r.u8()? {
+		0 => Phase::AfterInit,
+		1 => Phase::InMain,
+		phase => return Err(anyhow!("unknown phase {phase}"))
+	};
+
+	restore_memory(&mut r, uc)?;
+	for reg in registers() {
+		uc.reg_write(reg, r.u64()?).map_err(uc_err)?;
+	}
+	restore_state(&mut r, state)?;
+
+	if !r.is_empty() {
+		return Err(anyhow!("snapshot has data left over"));
+	}
+	Ok(phase)
+}
@@ -1,4 +1,4 @@
-use crate::emulator::{EmuState, EmuUC, FuncResult};
+use crate::emulator::{STDCLIB, EmuState, EmuUC, FuncResult};
 use crate::emulator::helpers::ArgReader;
 use crate::mac_roman;
 
@@ -9,9 +9,9 @@ fn write(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncRe
     match state.stdio_files.get_mut(&fildes) {
         Some(f) => {
             if f.is_terminal() {
-                Ok(Some(f.generic_write(&mac_roman::decode_buffer(&output, true))))
+                Ok(Some(f.generic_write(&mut state.console, &mac_roman::decode_buffer(&output, true))))
             } else {
-                Ok(Some(f.generic_write(&output)))
+                Ok(Some(f.generic_write(&mut state.console, &output)))
             }
         }
         None => {
@@ -23,5 +23,5 @@ fn write(uc: &mut EmuUC, state: &mut EmuState, reader: &mut ArgReader) -> FuncRe
 }
 
 pub(super) fn install_shims(state: &mut EmuState) {
-    state.install_shim_function("write", write);
+    state.install_shim_function(STDCLIB, "write", write);
 }
@@ -1,11 +1,11 @@
-use std::{cell::RefCell, collections::HashMap, path::{PathBuf, Path, Prefix}, io::{Read, Cursor, Write}, fs::File, ffi::OsString, rc::Rc};
+use std::{collections::HashMap, path::{PathBuf, Path, Prefix}, io::{Read, Cursor, Write}, fs::File, ffi::OsString};
 
 use anyhow::{anyhow, Result};
 use bimap::BiHashMap;
 use binread::{BinRead, BinReaderExt};
 use xattr::FileExt;
 
-use crate::{common::{FourCC, four_cc, lf_to_cr}, macbinary, mac_roman};
+use crate::{common::{FourCC, Shared, four_cc, lf_to_cr}, macbinary, mac_roman, snapshot::{SnapshotReader, SnapshotWriter}};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Fork {
@@ -217,6 +217,54 @@ impl MacFile {
 		}
 		Ok(())
 	}
+
+	/// Records the file. Only unsaved changes go into the snapshot; a clean
+	/// file is read from disk again on restore, so later edits are seen.
+	pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+		w.path(&self.path);
+		w.u8(match self.mode {
+			FileMode::Automatic => 0,
+			FileMode::MacBinary => 1,
+			FileMode::Native => 2
+		});
+		w.bool(self.dirty);
+		if self.dirty {
+			w.bytes(&self.file_info.pack());
+			w.bytes(&self.data_fork);
+			w.bytes(&self.resource_fork);
+		}
+	}
+
+	/// Returns None if a clean file has gone from disk since the snapshot.
+	pub fn restore_snapshot(r: &mut SnapshotReader) -> Result<Option<MacFile>> {
+		let path = r.path()?;
+		let mode = match r.u8()? {
+			0 => FileMode::Automatic,
+			1 => FileMode::MacBinary,
+			2 => FileMode::Native,
+			mode => return Err(anyhow!("unknown file mode {mode}"))
+		};
+
+		if !r.bool()? {
+			return match MacFile::open(&path) {
+				Ok(file) => Ok(Some(file)),
+				Err(e) => {
+					warn!(target: "files", "Cannot reopen {path:?} from the snapshot: {e:?}");
+					Ok(None)
+				}
+			};
+		}
+
+		let file_info = Cursor::new(r.bytes()?).read_be()?;
+		Ok(Some(MacFile {
+			path,
+			mode,
+			dirty: true,
+			file_info,
+			data_fork: r.bytes()?,
+			resource_fork: r.bytes()?
+		}))
+	}
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -273,6 +321,35 @@ impl Volume {
 		}
 	}
 
+	fn save_snapshot(&self, w: &mut SnapshotWriter) {
+		match self {
+			Volume::Root => w.u8(0),
+			Volume::Verbatim(a) => {
+				w.u8(1);
+				w.str(&a.to_string_lossy());
+			}
+			Volume::VerbatimUNC(a, b) => {
+				w.u8(2);
+				w.str(&a.to_string_lossy());
+				w.str(&b.to_string_lossy());
+			}
+			Volume::VerbatimDisk(letter) => {
+				w.u8(3);
+				w.u8(*letter);
+			}
+		}
+	}
+
+	fn restore_snapshot(r: &mut SnapshotReader) -> Result<Volume> {
+		match r.u8()? {
+			0 => Ok(Volume::Root),
+			1 => Ok(Volume::Verbatim(r.str()?.into())),
+			2 => Ok(Volume::VerbatimUNC(r.str()?.into(), r.str()?.into())),
+			3 => Ok(Volume::VerbatimDisk(r.u8()?)),
+			kind => Err(anyhow!("unknown volume kind {kind}"))
+		}
+	}
+
 	fn get_name(&self) -> Option<String> {
 		match self {
 			Volume::Root => Some(String::from("Root")),
@@ -286,18 +363,20 @@ impl Volume {
 
 
 pub struct FileSystem {
-	// should this store a Weak instead of Rc?
-	files: HashMap<PathBuf, Rc<RefCell<MacFile>>>,
+	// should this store a weak reference instead?
+	files: HashMap<PathBuf, Shared<MacFile>>,
 	nodes: BiHashMap<VolumeAndDir, PathBuf>,
 	next_node_id: DirID,
 	volume_names: BiHashMap<VolumeRef, String>,
 	volumes: BiHashMap<VolumeRef, Volume>,
 	default_volume: VolumeRef,
-	next_volume_ref: VolumeRef
+	next_volume_ref: VolumeRef,
+	/// Where relative paths start from
+	current_dir: PathBuf
 }
 
 impl FileSystem {
-	pub fn new() -> Self {
+	pub fn new(current_dir: PathBuf) -> Self {
 		FileSystem {
 			files: HashMap::new(),
 			nodes: BiHashMap::new(),
@@ -305,10 +384,19 @@ impl FileSystem {
 			volume_names: BiHashMap::new(),
 			volumes: BiHashMap::new(),
 			default_volume: -1,
-			next_volume_ref: -1
+			next_volume_ref: -1,
+			current_dir
 		}
 	}
 
+	pub fn current_dir(&self) -> &Path {
+		&self.current_dir
+	}
+
+	pub fn set_current_dir(&mut self, path: PathBuf) {
+		self.current_dir = path;
+	}
+
 	pub fn get_volume_info_by_drive_number(&self, drive_number: i16) -> Option<(String, i16)> {
 		// assume drive numbers are just volume numbers (but positive) for now
 		let volume_ref = if drive_number == 0 {
@@ -405,7 +493,7 @@ impl FileSystem {
 		} else if dir_id == 2 {
 			// Root directory
 			if volume_ref == 0 {
-				std::env::current_dir()?.ancestors().last().unwrap().to_path_buf()
+				self.current_dir.ancestors().last().unwrap().to_path_buf()
 			} else {
 				self.get_volume_by_ref(volume_ref)?.get_root()
 			}
@@ -414,7 +502,7 @@ impl FileSystem {
 			self.get_directory_by_id(volume_ref, dir_id)?
 		} else {
 			// Relative from current directory
-			std::env::current_dir()?
+			self.current_dir.clone()
 		};
 
 		// Apply what's left
@@ -493,17 +581,88 @@ impl FileSystem {
 		let mut file = MacFile::create(path, creator_id, type_id);
 		file.save_if_dirty()?;
 
-		self.files.insert(path.to_path_buf(), Rc::new(RefCell::new(file)));
+		self.files.insert(path.to_path_buf(), Shared::new(file));
 		Ok(())
 	}
 
-	pub fn get_file(&mut self, path: &Path) -> Result<Rc<RefCell<MacFile>>> {
+	/// Records the directory IDs and volumes that have been handed out, along
+	/// with every file we're holding onto.
+	pub fn save_snapshot(&self, w: &mut SnapshotWriter) {
+		w.count(self.files.len());
+		for file in self.files.values() {
+			file.borrow().save_snapshot(w);
+		}
+
+		w.count(self.nodes.len());
+		for ((volume, dir), path) in &self.nodes {
+			w.i16(*volume);
+			w.i32(*dir);
+			w.path(path);
+		}
+		w.i32(self.next_node_id);
+
+		w.count(self.volume_names.len());
+		for (volume, name) in &self.volume_names {
+			w.i16(*volume);
+			w.str(name);
+		}
+		w.count(self.volumes.len());
+		for (volume_ref, volume) in &self.volumes {
+			w.i16(*volume_ref);
+			volume.save_snapshot(w);
+		}
+		w.i16(self.default_volume);
+		w.i16(self.next_volume_ref);
+	}
+
+	/// Rebuilds the file system from a snapshot. The working directory isn't
+	/// part of it, as the resumed run may be somewhere else.
+	pub fn restore_snapshot(r: &mut SnapshotReader, current_dir: PathBuf) -> Result<FileSystem> {
+		let mut fs = FileSystem::new(current_dir);
+
+		for _ in 0..r.count()? {
+			if let Some(file) = MacFile::restore_snapshot(r)? {
+				fs.files.insert(file.path.clone(), Shared::new(file));
+			}
+		}
+
+		for _ in 0..r.count()? {
+			let key = (r.i16()?, r.i32()?);
+			fs.nodes.insert(key, r.path()?);
+		}
+		fs.next_node_id = r.i32()?;
+
+		for _ in 0..r.count()? {
+			let volume = r.i16()?;
+			fs.volume_names.insert(volume, r.str()?);
+		}
+		for _ in 0..r.count()? {
+			let volume_ref = r.i16()?;
+			fs.volumes.insert(volume_ref, Volume::restore_snapshot(r)?);
+		}
+		fs.default_volume = r.i16()?;
+		fs.next_volume_ref = r.i16()?;
+
+		Ok(fs)
+	}
+
+	/// Writes out every file that has unsaved changes.
+	pub fn save_all(&self) {
+		for file in self.files.values() {
+			let mut file = file.borrow_mut();
+			if let Err(e) = file.save_if_dirty() {
+				error!(target: "files", "Cannot save {:?}: {e:?}", file.path);
+			}
+		}
+	}
+
+	pub fn get_file(&mut self, path: &Path) -> Result<Shared<MacFile>> {
 		if let Some(file) = self.files.get(path) {
-			Ok(Rc::clone(file))
+			Ok(Shared::clone(file))
 		} else {
 			let file = MacFile::open(path)?;
-			let file = Rc::new(RefCell::new(file));
-			self.files.insert(path.to_path_buf(), Rc::clone(&file));
+			let file = Shared::new(file);
+			self.files.insert(path.to_path_buf(), Shared::clone(&file));
 			Ok(file)
 		}
 	}
@@ -0,0 +1,39 @@
+//! Runs classic Mac OS PowerPC MPW tools by emulating them.
+//!
+//! ```no_run
+//! let output = mpw_emu::Tool::new("MrC")
+//!     .args(["-o", "main.c.o", "main.c"])
+//!     .current_dir("project")
+//!     .run()?;
+//! println!("exited with {}", output.status);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+#[macro_use]
+extern crate log;
+
+mod cfrg;
+// The command line tool's subcommands; embedders should stick to `Tool`
+#[doc(hidden)]
+pub mod cli;
+mod common;
+mod emulator;
+mod filesystem;
+mod linker;
+mod macbinary;
+mod mac_roman;
+mod pef;
+mod resources;
+mod snapshot;
+mod tool;
+mod xcoff;
+
+pub use emulator::{Limits, HEAP_LIMIT_STATUS, INSTRUCTION_LIMIT_STATUS, TIMEOUT_STATUS};
+pub use tool::{Output, Tool};
+
+// A tool can be set up on one thread and run on another
+const _: () = {
+	fn assert_send<T: Send>() {}
+	let _ = assert_send::<Tool>;
+	let _ = assert_send::<Output>;
+};
@@ -1,18 +1,112 @@
-use super::pef;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use super::{cfrg, common::Shared, filesystem, pef, resources, xcoff};
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Space given to data imports we know nothing about, so that the loader
+/// still has something to point them at
+pub const UNKNOWN_DATA_SIZE: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protection {
+	ReadOnly,
+	ReadWrite,
+	ReadExecute,
+	ReadWriteExecute
+}
+
+impl Protection {
+	pub fn for_section(kind: pef::SectionType) -> Self {
+		match kind {
+			pef::SectionType::Code => Protection::ReadExecute,
+			pef::SectionType::Constant => Protection::ReadOnly,
+			pef::SectionType::ExecutableData => Protection::ReadWriteExecute,
+			_ => Protection::ReadWrite
+		}
+	}
+}
+
+/// A page-aligned chunk of the image, used for setting up memory permissions
+/// and for describing addresses
+#[derive(Clone, Debug)]
+pub struct MemoryRegion {
+	pub name: String,
+	pub address: u32,
+	pub size: u32,
+	pub protection: Protection
+}
+
+/// A PEF container that has been placed into memory. The first one is always
+/// the application, and any others are shared libraries.
+#[derive(Clone)]
+pub struct LoadedFragment {
+	pub name: String,
+	pub path: PathBuf,
+	/// Where each section was placed, or None if it isn't loaded into memory
+	pub sections: Vec<Option<u32>>,
+	pub init_vector: u32,
+	pub term_vector: u32,
+	pub exports: pef::ExportTable
+}
+
+impl LoadedFragment {
+	pub fn section_address(&self, index: usize) -> u32 {
+		match self.sections.get(index) {
+			Some(Some(address)) => *address,
+			_ => {
+				warn!(target: "linker", "Relocation refers to non-instantiated section {index} in {}", self.name);
+				0
+			}
+		}
+	}
+
+	/// Looks up one of this fragment's exports, returning its address and class.
+	pub fn find_export(&self, name: &str) -> Option<(u32, pef::SymbolClass)> {
+		let export = self.exports.find(name)?;
+		let address = match export.location() {
+			pef::ExportLocation::Section(section, offset) => self.section_address(section as usize).wrapping_add(offset),
+			pef::ExportLocation::Absolute(address) => address,
+			pef::ExportLocation::ReexportedImport(_) => {
+				warn!(target: "linker", "{} re-exports an import as {name}, which is not supported", self.name);
+				return None;
+			}
+		};
+		Some((address, export.class))
+	}
+}
+
+pub struct Import {
+	pub library: usize,
+	pub name: String,
+	pub class: pef::SymbolClass,
+	pub weak: bool,
+	pub shim_addr: u32,
+	/// Every word that was relocated against this import's shim
+	pub sites: Vec<u32>,
+	/// Address of this symbol within a loaded shared library, if one exports it
+	pub definition: Option<u32>
+}
 
 pub struct Executable {
 	pub memory: Vec<u8>,
 	pub memory_base: u32,
-	pub code_addr: u32,
-	pub data_addr: u32,
+	pub fragments: Vec<LoadedFragment>,
 	pub stack_addr: u32,
 	pub stack_size: u32,
-	pub init_vector: u32,
 	pub main_vector: u32,
 	pub sc_thunk_addr: u32,
-	pub shim_addrs: Vec<u32>,
-	pub imports: Vec<pef::ImportedSymbol>,
-	pub libraries: Vec<String>
+	/// Entry points return here, and emulation stops before it's executed
+	pub return_addr: u32,
+	pub imports: Vec<Import>,
+	pub libraries: Vec<String>,
+	pub library_path: Vec<PathBuf>,
+	pub regions: Vec<MemoryRegion>,
+	/// Real size of a data import, for the ones whoever runs the executable
+	/// knows about
+	pub data_import_sizes: fn(&str) -> Option<u32>
 }
 
 impl Executable {
@@ -20,16 +114,17 @@ impl Executable {
 		Executable {
 			memory: Vec::new(),
 			memory_base: 0x10000000,
-			code_addr: 0,
-			data_addr: 0,
+			fragments: Vec::new(),
 			stack_addr: 0,
 			stack_size: 0,
-			init_vector: 0,
 			main_vector: 0,
 			sc_thunk_addr: 0,
-			shim_addrs: Vec::new(),
+			return_addr: 0,
 			imports: Vec::new(),
-			libraries: Vec::new()
+			libraries: Vec::new(),
+			library_path: Vec::new(),
+			regions: Vec::new(),
+			data_import_sizes: |_| None
 		}
 	}
 
@@ -49,7 +144,79 @@ impl Executable {
 		}
 	}
 
-	pub fn load_pef(&mut self, pef: pef::PEF) {
+	/// Allocates memory starting on a fresh page, so that it can be given its
+	/// own permissions.
+	fn allocate_region(&mut self, name: String, size: usize, alignment: usize, protection: Protection) -> u32 {
+		self.align_memory_to(alignment.max(PAGE_SIZE));
+		let address = self.allocate_memory(size);
+		self.regions.push(MemoryRegion { name, address, size: size as u32, protection });
+		address
+	}
+
+	/// Finds the region that an address belongs to.
+	pub fn region_for(&self, address: u32) -> Option<&MemoryRegion> {
+		self.regions.iter().find(|r| address >= r.address && address - r.address < r.size)
+	}
+
+	fn reset(&mut self) {
+		self.memory.clear();
+		self.regions.clear();
+		self.fragments.clear();
+		self.imports.clear();
+		self.libraries.clear();
+		self.main_vector = 0;
+	}
+
+	fn create_stack_and_thunk(&mut self) {
+		// Create a stack
+		self.stack_size = 0x100000;
+		self.stack_addr = self.allocate_region("stack".to_string(), self.stack_size as usize, PAGE_SIZE, Protection::ReadWrite);
+
+		// Create the thunk that all import shims go through
+		self.sc_thunk_addr = self.allocate_region("import thunk".to_string(), 16, PAGE_SIZE, Protection::ReadExecute);
+		self.set_u32(self.sc_thunk_addr, 0x44000002); // sc
+		self.set_u32(self.sc_thunk_addr + 4, 0x4E800020); // blr
+		self.set_u32(self.sc_thunk_addr + 8, 0x4E800020); // double to work around unicorn merging https://github.com/unicorn-engine/unicorn/pull/1558
+		self.return_addr = self.sc_thunk_addr + 12;
+	}
+
+	/// Pads the image out to a whole number of pages once everything is loaded.
+	fn finish(&mut self) {
+		self.align_memory_to(PAGE_SIZE);
+	}
+
+	pub fn load_pef(&mut self, path: &Path, pef: pef::PEF) -> Result<()> {
+		self.reset();
+
+		let (fragment, loader) = self.place_fragment(&file_name(path), path, &pef)?;
+		self.create_stack_and_thunk();
+
+		if loader.main_section >= 0 {
+			self.main_vector = fragment.section_address(loader.main_section as usize).wrapping_add(loader.main_offset);
+		}
+
+		self.link_fragment(fragment, loader)?;
+		self.load_libraries();
+		self.resolve_imports();
+		self.finish();
+		Ok(())
+	}
+
+	pub fn load_xcoff(&mut self, path: &Path, xcoff: xcoff::XCOFF) -> Result<()> {
+		self.reset();
+		self.create_stack_and_thunk();
+
+		let main_vector = self.link_xcoff(&file_name(path), path, xcoff)?;
+		self.main_vector = main_vector.unwrap_or(0);
+
+		self.load_libraries();
+		self.resolve_imports();
+		self.finish();
+		Ok(())
+	}
+
+	/// Copies a container's sections into memory and finds its entry points.
+	fn place_fragment(&mut self, name: &str, path: &Path, pef: &pef::PEF) -> Result<(LoadedFragment, pef::Loader)> {
 		for section in &pef.sections {
 			debug!(
 				target: "linker",
@@ -59,74 +226,464 @@ impl Executable {
 				section.section_kind, section.share_kind, section.alignment);
 		}
 
-		self.memory.clear();
+		let mut loader = None;
+		let mut sections = Vec::new();
 
-		// Load code
-		self.code_addr = self.memory_end_addr();
-		self.memory.extend_from_slice(pef.sections[0].packed_contents.as_ref().unwrap());
-		self.align_memory_to(0x10);
+		for (index, section) in pef.sections.iter().enumerate() {
+			let contents = section.packed_contents.as_deref().unwrap_or(&[]);
 
-		// Load data
-		let data_start = self.memory.len();
-		self.data_addr = self.allocate_memory(pef.sections[1].total_size as usize);
-		let data_end = self.memory.len();
-		pef::unpack_pattern_data(pef.sections[1].packed_contents.as_ref().unwrap(), &mut self.memory[data_start .. data_end]);
+			let address = match section.section_kind {
+				pef::SectionType::Code | pef::SectionType::UnpackedData | pef::SectionType::Constant | pef::SectionType::ExecutableData => {
+					let address = self.allocate_section(name, index, section)?;
+					let start = (address - self.memory_base) as usize;
+					let amount = contents.len().min(section.total_size as usize);
+					self.memory[start .. start + amount].copy_from_slice(&contents[.. amount]);
+					Some(address)
+				}
+				pef::SectionType::PatternInitData => {
+					let address = self.allocate_section(name, index, section)?;
+					let start = (address - self.memory_base) as usize;
+					let end = start + section.total_size as usize;
+					pef::unpack_pattern_data(contents, &mut self.memory[start .. end]);
+					Some(address)
+				}
+				pef::SectionType::Loader => {
+					loader = Some(pef::parse_loader(contents)?);
+					None
+				}
+				_ => None
+			};
 
-		self.align_memory_to(0x10);
+			if let Some(address) = address {
+				debug!(target: "linker", "{:?} section {:?} of {name} placed at {address:08X}", section.section_kind, section.name);
+			}
+			sections.push(address);
+		}
 
-		// Create a stack
-		self.stack_size = 0x100000;
-		self.stack_addr = self.allocate_memory(self.stack_size as usize);
 
-		// Parse loader
-		let loader = pef::parse_loader(pef.sections[2].packed_contents.as_ref().unwrap()).unwrap();
-		if loader.init_section > 0 {
-			self.init_vector = self.data_addr + loader.init_offset;
+		let mut loader = loader.ok_or_else(|| anyhow!("PEF container has no loader section"))?;
+		let mut fragment = LoadedFragment {
+			name: name.to_string(),
+			path: path.to_path_buf(),
+			sections,
+			init_vector: 0,
+			term_vector: 0,
+			exports: std::mem::take(&mut loader.exports)
+		};
+
+		// Find entry points
+		if loader.init_section >= 0 {
+			fragment.init_vector = fragment.section_address(loader.init_section as usize).wrapping_add(loader.init_offset);
+		}
+		if loader.term_section >= 0 {
+			fragment.term_vector = fragment.section_address(loader.term_section as usize).wrapping_add(loader.term_offset);
+		}
+
+		Ok((fragment, loader))
+	}
+
+	/// Creates shims for a placed fragment's imports and applies its relocations.
+	fn link_fragment(&mut self, fragment: LoadedFragment, loader: pef::Loader) -> Result<()> {
+		let library_map: Vec<usize> = loader.imported_libraries.iter()
+			.map(|lib| self.library_index(&lib.name))
+			.collect();
+		let symbols = loader.imported_symbols.iter().map(|sym| {
+			let library = loader.imported_libraries.get(sym.library)
+				.ok_or_else(|| anyhow!("{} is imported from library #{}, which doesn't exist", sym.name, sym.library))?;
+			// Everything imported from a weak library is weak as well
+			let weak = sym.weak || library.is_weak;
+			Ok((library_map[sym.library], sym.name.as_str(), sym.class, weak))
+		}).collect::<Result<Vec<_>>>()?;
+		let import_base = self.add_imports(&fragment.name, &symbols);
+
+		for reloc_section in &loader.reloc_sections {
+			self.handle_reloc_section(&fragment, import_base, &loader, reloc_section)
+				.map_err(|e| anyhow!("Relocating section #{} of {}: {e}", reloc_section.section_index, fragment.name))?;
+		}
+
+		self.fragments.push(fragment);
+		Ok(())
+	}
+
+	/// Places an XCOFF file's sections, creates shims for its imports and applies
+	/// its relocations. Returns the address of the entry point's descriptor.
+	fn link_xcoff(&mut self, name: &str, path: &Path, xcoff: xcoff::XCOFF) -> Result<Option<u32>> {
+		let loader = xcoff.loader.ok_or_else(|| anyhow!("XCOFF file has no loader section"))?;
+		debug!(target: "linker", "XCOFF TOC anchor is at {:08X}", xcoff.toc_anchor);
+
+		let mut sections = Vec::new();
+		let mut data_placement = None;
+
+		for (index, section) in xcoff.sections.iter().enumerate() {
+			debug!(
+				target: "linker",
+				"Section: {:?} Kind={:?} VAddr={:X} Size={:X} Align={}",
+				section.name, section.kind, section.virtual_address, section.size, section.alignment);
+
+			let alignment = 1usize.checked_shl(section.alignment.into())
+				.filter(|&alignment| alignment <= PAGE_SIZE)
+				.ok_or_else(|| anyhow!("Section #{index} of {name} wants 2^{} byte alignment", section.alignment))?;
+
+			let address = match section.kind {
+				xcoff::SectionKind::Text | xcoff::SectionKind::Data => {
+					let protection = if section.kind == xcoff::SectionKind::Text { Protection::ReadExecute } else { Protection::ReadWrite };
+					if u64::from(self.memory_end_addr()) + (PAGE_SIZE as u64) + u64::from(section.size) > u64::from(u32::MAX) {
+						return Err(anyhow!("Section #{index} of {name} is too big ({:X} bytes)", section.size));
+					}
+					let address = self.allocate_region(
+						format!("{name} {} section #{index}", section.name),
+						section.size as usize,
+						alignment,
+						protection);
+					let start = (address - self.memory_base) as usize;
+					let amount = section.contents.len().min(section.size as usize);
+					self.memory[start .. start + amount].copy_from_slice(&section.contents[.. amount]);
+					if section.kind == xcoff::SectionKind::Data {
+						data_placement = Some((section.virtual_address, address));
+					}
+					Some(address)
+				}
+				xcoff::SectionKind::Bss => {
+					// .bss has to stay at the same distance from .data, since code
+					// may reach both through the TOC
+					let address = match data_placement {
+						Some((data_vaddr, data_address)) if section.virtual_address >= data_vaddr => {
+							data_address.checked_add(section.virtual_address - data_vaddr)
+								.ok_or_else(|| anyhow!("Section #{index} of {name} is too far from the data section"))?
+						}
+						_ => {
+							self.align_memory_to(alignment.max(PAGE_SIZE));
+							self.memory_end_addr()
+						}
+					};
+					let end = address.checked_add(section.size)
+						.ok_or_else(|| anyhow!("Section #{index} of {name} is too big ({:X} bytes)", section.size))?;
+					if end > self.memory_end_addr() {
+						self.allocate_memory((end - self.memory_end_addr()) as usize);
+					}
+					self.regions.push(MemoryRegion {
+						name: format!("{name} {} section #{index}", section.name),
+						address,
+						size: section.size,
+						protection: Protection::ReadWrite
+					});
+					Some(address)
+				}
+				_ => None
+			};
+
+			if let Some(address) = address {
+				debug!(target: "linker", "{:?} section {:?} of {name} placed at {address:08X}", section.kind, section.name);
+			}
+			sections.push(address);
+		}
+
+
+		// Translates an address from the file into where it ended up in memory
+		let translate = |vaddr: u32| -> Option<u32> {
+			xcoff.sections.iter().zip(&sections).find_map(|(section, loaded)| {
+				let loaded = (*loaded)?;
+				let offset = vaddr.checked_sub(section.virtual_address)?;
+				(offset < section.size).then(|| loaded + offset)
+			})
+		};
+		// How far a section (by 1-based number) moved from where it was linked
+		let section_delta = |number: usize| -> Option<u32> {
+			let section = xcoff.sections.get(number.checked_sub(1)?)?;
+			let loaded = (*sections.get(number - 1)?)?;
+			Some(loaded.wrapping_sub(section.virtual_address))
+		};
+		let kind_delta = |kind: xcoff::SectionKind| -> Option<u32> {
+			section_delta(xcoff.sections.iter().position(|s| s.kind == kind)? + 1)
+		};
+
+		// Imports and exports
+		let mut symbol_imports = Vec::new();
+		let mut pending_imports = Vec::new();
+		let mut exports = Vec::new();
+
+		for sym in &loader.symbols {
+			let class = match sym.storage_class {
+				xcoff::XMC_DS => pef::SymbolClass::TVect,
+				xcoff::XMC_PR => pef::SymbolClass::Code,
+				xcoff::XMC_GL => pef::SymbolClass::Glue,
+				xcoff::XMC_TC0 => pef::SymbolClass::TOC,
+				_ => pef::SymbolClass::Data
+			};
+
+			if sym.is_import {
+				let library_name = match loader.import_files.get(sym.import_file as usize) {
+					Some(file) => file.library_name().to_string(),
+					None => {
+						warn!(target: "linker", "Import {} refers to unknown import file {}", sym.name, sym.import_file);
+						String::new()
+					}
+				};
+				let library = self.library_index(&library_name);
+				symbol_imports.push(Some(pending_imports.len()));
+				pending_imports.push((library, sym.name.as_str(), class, sym.is_weak));
+			} else {
+				symbol_imports.push(None);
+
+				if sym.is_export && sym.section > 0 {
+					let index = sym.section as usize - 1;
+					let section_vaddr = xcoff.sections.get(index).map_or(0, |s| s.virtual_address);
+					exports.push(pef::ExportedSymbol {
+						name: sym.name.clone(),
+						class,
+						value: sym.value.wrapping_sub(section_vaddr),
+						section: index as i16,
+						key: pef::hash_name(sym.name.as_bytes())
+					});
+				}
+			}
 		}
-		if loader.main_section > 0 {
-			self.main_vector = self.data_addr + loader.main_offset;
+
+		let import_base = self.add_imports(name, &pending_imports);
+
+		// Relocations
+		for reloc in &loader.relocations {
+			let site = match section_delta(reloc.section.max(0) as usize) {
+				Some(delta) => Some(reloc.virtual_address.wrapping_add(delta)),
+				None => translate(reloc.virtual_address)
+			};
+			let site = match site {
+				Some(site) => site,
+				None => {
+					warn!(target: "linker", "Relocation at {:08X} is outside any loaded section", reloc.virtual_address);
+					continue;
+				}
+			};
+
+			if reloc.reloc_type != xcoff::R_POS || reloc.bit_length != 32 {
+				warn!(
+					target: "linker",
+					"Unsupported relocation at {:08X}: type {}, {} bits{}",
+					reloc.virtual_address, reloc.reloc_type, reloc.bit_length, if reloc.signed { ", signed" } else { "" });
+				continue;
+			}
+
+			let delta = match reloc.symbol_index {
+				0 => kind_delta(xcoff::SectionKind::Text),
+				1 => kind_delta(xcoff::SectionKind::Data),
+				2 => kind_delta(xcoff::SectionKind::Bss),
+				n => {
+					let symbol = (n - 3) as usize;
+					if let Some(&Some(import)) = symbol_imports.get(symbol) {
+						trace!(target: "linker", "{site:X} -> {}", self.imports[import_base + import].name);
+						self.relocate_by_import(site, import_base + import)?;
+						continue;
+					}
+					loader.symbols.get(symbol).and_then(|sym| section_delta(sym.section.max(0) as usize))
+				}
+			};
+
+			match delta {
+				Some(delta) => self.relocate_by(site, delta)?,
+				None => warn!(target: "linker", "Relocation at {:08X} refers to a section that wasn't loaded", reloc.virtual_address)
+			}
 		}
 
-		// Create shims for imported symbols
-		self.sc_thunk_addr = self.memory_end_addr();
-		self.memory.push(0x44);
-		self.memory.push(0);
-		self.memory.push(0);
-		self.memory.push(2);
-		self.memory.push(0x4E);
-		self.memory.push(0x80);
-		self.memory.push(0);
-		self.memory.push(0x20);
-		self.memory.push(0x4E); // double to work around unicorn merging https://github.com/unicorn-engine/unicorn/pull/1558
-		self.memory.push(0x80);
-		self.memory.push(0);
-		self.memory.push(0x20);
-
-		for (i, sym) in loader.imported_symbols.iter().enumerate() {
-			match sym.class {
+		let main_vector = xcoff.entry_point.and_then(translate);
+
+		// A single hash chain is enough for lookups to work
+		let export_count = exports.len() as u32;
+		self.fragments.push(LoadedFragment {
+			name: name.to_string(),
+			path: path.to_path_buf(),
+			sections,
+			init_vector: 0,
+			term_vector: 0,
+			exports: pef::ExportTable {
+				hash_table_power: 0,
+				hash_chains: vec![pef::HashChain { first_index: 0, count: export_count }],
+				symbols: exports
+			}
+		});
+
+		Ok(main_vector)
+	}
+
+	/// Creates shims for a fragment's imports (library index, name, class and
+	/// weak flag for each), returning the index of the first one. Function shims
+	/// and data shims go in separate regions, so that they can be protected.
+	fn add_imports(&mut self, owner: &str, symbols: &[(usize, &str, pef::SymbolClass, bool)]) -> usize {
+		let base = self.imports.len();
+
+		let mut code_size = 0;
+		let mut data_size = 0;
+		for &(_, name, class, _) in symbols {
+			match class {
+				pef::SymbolClass::TVect => code_size += 12,
+				pef::SymbolClass::Code | pef::SymbolClass::Glue => code_size += 12 + 20,
+				pef::SymbolClass::Data | pef::SymbolClass::TOC => data_size += self.data_import_space(name) as usize
+			}
+		}
+
+		let mut next_code = 0;
+		if code_size > 0 {
+			next_code = self.allocate_region(format!("{owner} import shims"), code_size, PAGE_SIZE, Protection::ReadExecute);
+		}
+		let mut next_data = 0;
+		if data_size > 0 {
+			next_data = self.allocate_region(format!("{owner} data imports"), data_size, PAGE_SIZE, Protection::ReadWrite);
+		}
+
+		for (i, &(library, name, class, weak)) in symbols.iter().enumerate() {
+			let index = (base + i) as u32;
+			let shim_addr = match class {
 				pef::SymbolClass::TVect => {
-					let shim = self.allocate_memory(12);
-					self.set_u32(shim, self.sc_thunk_addr);
-					self.set_u32(shim + 4, i as u32);
-					self.set_u32(shim + 8, 100);
-					self.shim_addrs.push(shim);
+					let tvect = next_code;
+					next_code += 12;
+					self.write_tvect_shim(tvect, index);
+					tvect
+				}
+				pef::SymbolClass::Code | pef::SymbolClass::Glue => {
+					// These are branched to directly rather than through glue, so
+					// give them a stub that loads a shim transition vector first
+					let tvect = next_code;
+					let code = next_code + 12;
+					next_code += 12 + 20;
+					self.write_tvect_shim(tvect, index);
+					self.set_u32(code, 0x3D800000 | (tvect >> 16)); // lis r12, tvect@h
+					self.set_u32(code + 4, 0x618C0000 | (tvect & 0xFFFF)); // ori r12, r12, tvect@l
+					self.set_u32(code + 8, 0x44000002); // sc
+					self.set_u32(code + 12, 0x4E800020); // blr
+					self.set_u32(code + 16, 0x4E800020); // blr, see sc_thunk_addr
+					code
 				}
-				pef::SymbolClass::Data => {
-					let shim = self.allocate_memory(1024);
-					self.shim_addrs.push(shim);
+				pef::SymbolClass::Data | pef::SymbolClass::TOC => {
+					let shim = next_data;
+					next_data += self.data_import_space(name);
+					shim
 				}
-				_ => panic!()
+			};
+
+			self.imports.push(Import {
+				library,
+				name: name.to_string(),
+				class,
+				weak,
+				shim_addr,
+				sites: Vec::new(),
+				definition: None
+			});
+		}
+
+		base
+	}
+
+	/// Space to reserve for a data import, keeping each one 8-byte aligned
+	fn data_import_space(&self, name: &str) -> u32 {
+		let size = (self.data_import_sizes)(name).unwrap_or(UNKNOWN_DATA_SIZE);
+		(size + 7) & !7
+	}
+
+	fn write_tvect_shim(&mut self, shim: u32, index: u32) {
+		self.set_u32(shim, self.sc_thunk_addr);
+		self.set_u32(shim + 4, index);
+		self.set_u32(shim + 8, 100);
+	}
+
+	fn library_index(&mut self, name: &str) -> usize {
+		match self.libraries.iter().position(|lib| lib == name) {
+			Some(index) => index,
+			None => {
+				self.libraries.push(name.to_string());
+				self.libraries.len() - 1
 			}
 		}
+	}
 
-		for reloc_section in &loader.reloc_sections {
-			self.handle_reloc_section(&loader, reloc_section);
+	/// Loads every imported library that can be found on the library path,
+	/// including ones that are only imported by other libraries.
+	fn load_libraries(&mut self) {
+		let mut index = 0;
+		while index < self.libraries.len() {
+			let name = self.libraries[index].clone();
+			index += 1;
+
+			if self.fragments.iter().any(|f| f.name == name) {
+				continue;
+			}
+
+			let path = self.library_path.iter().map(|dir| dir.join(&name)).find(|path| path.is_file());
+			match path {
+				Some(path) => {
+					info!(target: "linker", "Loading shared library {name} from {path:?}");
+					if let Err(e) = self.load_library(&name, &path) {
+						warn!(target: "linker", "Failed to load shared library {name} from {path:?}: {e}");
+					}
+				}
+				None => debug!(target: "linker", "Shared library {name} not found on the library path")
+			}
+		}
+	}
+
+	fn load_library(&mut self, name: &str, path: &Path) -> Result<()> {
+		let file = Shared::new(filesystem::MacFile::open(path)?);
+		let res = resources::parse_resources(Shared::clone(&file)).ok();
+
+		let pef = {
+			let file = file.borrow();
+			let container = match res.as_ref().map(cfrg::get_fragments).transpose()?.flatten() {
+				Some(fragments) => {
+					let fragment = cfrg::select_fragment(&fragments, Some(name))
+						.or_else(|| cfrg::select_fragment(&fragments, None))
+						.ok_or_else(|| anyhow!("no PowerPC fragment in cfrg resource"))?;
+					fragment.slice(&file.data_fork).ok_or_else(|| anyhow!("fragment lies outside the data fork"))?
+				}
+				None => &file.data_fork[..]
+			};
+			if xcoff::is_xcoff(container) {
+				let xcoff = xcoff::read_xcoff(container)?;
+				self.link_xcoff(name, path, xcoff)?;
+				return Ok(());
+			}
+			pef::read_pef(container)?
+		};
+
+		let (fragment, loader) = self.place_fragment(name, path, &pef)?;
+		self.link_fragment(fragment, loader)
+	}
+
+	/// Finds definitions for imports in whichever shared libraries were loaded.
+	fn resolve_imports(&mut self) {
+		for import in &mut self.imports {
+			let library = &self.libraries[import.library];
+			let fragment = match self.fragments.iter().skip(1).find(|f| &f.name == library) {
+				Some(f) => f,
+				None => continue
+			};
+
+			match fragment.find_export(&import.name) {
+				Some((address, class)) => {
+					if class != import.class {
+						warn!(target: "linker", "{library}::{} is imported as {:?} but exported as {class:?}", import.name, import.class);
+					}
+					trace!(target: "linker", "{library}::{} defined at {address:08X}", import.name);
+					import.definition = Some(address);
+				}
+				None if import.weak => debug!(target: "linker", "{library} does not export weak import {}", import.name),
+				None => warn!(target: "linker", "{library} does not export {}", import.name)
+			}
 		}
+	}
 
-		self.imports = loader.imported_symbols;
-		for lib in loader.imported_libraries {
-			self.libraries.push(lib.name);
+	fn allocate_section(&mut self, fragment_name: &str, index: usize, section: &pef::Section) -> Result<u32> {
+		// sections start on a fresh page anyway, so anything up to that is fine
+		let alignment = 1usize.checked_shl(section.alignment.into())
+			.filter(|&alignment| alignment <= PAGE_SIZE)
+			.ok_or_else(|| anyhow!("Section #{index} of {fragment_name} wants 2^{} byte alignment", section.alignment))?;
+		if u64::from(self.memory_end_addr()) + (PAGE_SIZE as u64) + u64::from(section.total_size) > u64::from(u32::MAX) {
+			return Err(anyhow!("Section #{index} of {fragment_name} is too big ({:X} bytes)", section.total_size));
 		}
+
+		Ok(self.allocate_region(
+			format!("{fragment_name} {:?} section #{index}", section.section_kind),
+			section.total_size as usize,
+			alignment,
+			Protection::for_section(section.section_kind)))
 	}
 
 	pub fn get_u32(&self, address: u32) -> u32 {
@@ -140,170 +697,342 @@ impl Executable {
 		self.memory[offset .. offset + 4].copy_from_slice(&value.to_be_bytes());
 	}
 
-	fn handle_reloc_section(&mut self, loader: &pef::Loader, relocs: &pef::RelocSection) {
+	fn relocate_by(&mut self, address: u32, base: u32) -> Result<()> {
+		let in_image = address.checked_sub(self.memory_base)
+			.is_some_and(|offset| offset as usize + 4 <= self.memory.len());
+		if !in_image {
+			return Err(anyhow!("relocation at {address:08X} is outside the image"));
+		}
+		self.set_u32(address, base.wrapping_add(self.get_u32(address)));
+		Ok(())
+	}
+
+	fn relocate_by_import(&mut self, address: u32, index: usize) -> Result<()> {
+		self.relocate_by(address, self.imports[index].shim_addr)?;
+		self.imports[index].sites.push(address);
+		Ok(())
+	}
+
+	fn handle_reloc_section(&mut self, fragment: &LoadedFragment, import_base: usize, loader: &pef::Loader, relocs: &pef::RelocSection) -> Result<()> {
 		let mut next_block = 0;
-		let mut reloc_address = self.data_addr;
+		let mut reloc_address = fragment.section_address(relocs.section_index as usize);
 		let mut import_index = 0u32;
+		let mut sect_c = fragment.section_address(0);
+		let mut sect_d = fragment.section_address(1);
 		let mut repeat_info = None;
 
 		while next_block < relocs.data.len() {
 			let block_pos = next_block;
-			let block = relocs.data[next_block] as u32;
-			next_block += 1;
-	
-			if (block & 0xC000) == 0 {
-				// RelocBySectDWithSkip
-				let skip_count = (block >> 6) & 0xFF;
-				let reloc_count = block & 0x3F;
-				reloc_address += skip_count * 4;
-				trace!(target: "linker", "[{block_pos:04X}] BySectDWithSkip @ {reloc_address:X} (x{reloc_count})");
-				for _ in 0..reloc_count {
-					self.set_u32(reloc_address, self.data_addr + self.get_u32(reloc_address));
-					reloc_address += 4;
+			let (op, length) = pef::decode_reloc(&relocs.data, block_pos);
+			next_block += length;
+
+			match op {
+				pef::RelocOp::BySectDWithSkip { skip, count } => {
+					reloc_address = reloc_address.wrapping_add(skip * 4);
+					trace!(target: "linker", "[{block_pos:04X}] BySectDWithSkip @ {reloc_address:X} (x{count})");
+					for _ in 0..count {
+						self.relocate_by(reloc_address, sect_d)?;
+						reloc_address += 4;
+					}
 				}
-			} else if (block & 0xFE00) == 0x4000 {
-				// RelocBySectC
-				let run_length = (block & 0x1FF) + 1;
-				trace!(target: "linker", "[{block_pos:04X}] BySectC @ {reloc_address:X} (x{run_length})"); 
-				for _ in 0..run_length {
-					self.set_u32(reloc_address, self.code_addr + self.get_u32(reloc_address));
-					reloc_address += 4;
+				pef::RelocOp::BySectC { count } => {
+					trace!(target: "linker", "[{block_pos:04X}] BySectC @ {reloc_address:X} (x{count})");
+					for _ in 0..count {
+						self.relocate_by(reloc_address, sect_c)?;
+						reloc_address += 4;
+					}
 				}
-			} else if (block & 0xFE00) == 0x4200 {
-				// RelocBySectD
-				let run_length = (block & 0x1FF) + 1;
-				trace!(target: "linker", "[{block_pos:04X}] BySectD @ {reloc_address:X} (x{run_length})");
-				for _ in 0..run_length {
-					self.set_u32(reloc_address, self.data_addr + self.get_u32(reloc_address));
-					reloc_address += 4;
+				pef::RelocOp::BySectD { count } => {
+					trace!(target: "linker", "[{block_pos:04X}] BySectD @ {reloc_address:X} (x{count})");
+					for _ in 0..count {
+						self.relocate_by(reloc_address, sect_d)?;
+						reloc_address += 4;
+					}
 				}
-			} else if (block & 0xFE00) == 0x4400 {
-				// RelocTVector12
-				let run_length = (block & 0x1FF) + 1;
-				trace!(target: "linker", "[{block_pos:04X}] TVector12 @ {reloc_address:X} (x{run_length})");
-				for _ in 0..run_length {
-					self.set_u32(reloc_address, self.code_addr + self.get_u32(reloc_address));
-					reloc_address += 4;
-					self.set_u32(reloc_address, self.data_addr + self.get_u32(reloc_address));
-					reloc_address += 8;
+				pef::RelocOp::TVector12 { count } => {
+					trace!(target: "linker", "[{block_pos:04X}] TVector12 @ {reloc_address:X} (x{count})");
+					for _ in 0..count {
+						self.relocate_by(reloc_address, sect_c)?;
+						reloc_address += 4;
+						self.relocate_by(reloc_address, sect_d)?;
+						reloc_address += 8;
+					}
 				}
-			} else if (block & 0xFE00) == 0x4600 {
-				// RelocTVector8
-				let run_length = (block & 0x1FF) + 1;
-				trace!(target: "linker", "[{block_pos:04X}] TVector8 @ {reloc_address:X} (x{run_length})");
-				for _ in 0..run_length {
-					self.set_u32(reloc_address, self.code_addr + self.get_u32(reloc_address));
-					reloc_address += 4;
-					self.set_u32(reloc_address, self.data_addr + self.get_u32(reloc_address));
+				pef::RelocOp::TVector8 { count } => {
+					trace!(target: "linker", "[{block_pos:04X}] TVector8 @ {reloc_address:X} (x{count})");
+					for _ in 0..count {
+						self.relocate_by(reloc_address, sect_c)?;
+						reloc_address += 4;
+						self.relocate_by(reloc_address, sect_d)?;
+						reloc_address += 4;
+					}
+				}
+				pef::RelocOp::VTable8 { count } => {
+					trace!(target: "linker", "[{block_pos:04X}] VTable8 @ {reloc_address:X} (x{count})");
+					for _ in 0..count {
+						self.relocate_by(reloc_address, sect_d)?;
+						reloc_address += 8;
+					}
+				}
+				pef::RelocOp::ImportRun { count } => {
+					trace!(target: "linker", "[{block_pos:04X}] ImportRun @ {reloc_address:X} (x{count})");
+					for _ in 0..count {
+						let symbol = loader.imported_symbols.get(import_index as usize)
+							.ok_or_else(|| anyhow!("[{block_pos:04X}] ImportRun reaches import {import_index}, which doesn't exist"))?;
+						trace!(target: "linker", "  {reloc_address:X} -> {import_index} - {}", &symbol.name);
+						self.relocate_by_import(reloc_address, import_base + import_index as usize)?;
+						reloc_address += 4;
+						import_index += 1;
+					}
+				}
+				pef::RelocOp::ByImport { index } => {
+					let symbol = loader.imported_symbols.get(index as usize)
+						.ok_or_else(|| anyhow!("[{block_pos:04X}] ByImport refers to import {index}, which doesn't exist"))?;
+					trace!(target: "linker", "[{block_pos:04X}] ByImport @ {reloc_address:X} (sym={index} - {})", &symbol.name);
+					self.relocate_by_import(reloc_address, import_base + index as usize)?;
 					reloc_address += 4;
+					import_index = index + 1;
 				}
-			} else if (block & 0xFE00) == 0x4800 {
-				// RelocVTable8
-				let run_length = (block & 0x1FF) + 1;
-				trace!(target: "linker", "[{block_pos:04X}] VTable8 @ {reloc_address:X} (x{run_length})");
-				for _ in 0..run_length {
-					self.set_u32(reloc_address, self.data_addr + self.get_u32(reloc_address));
-					reloc_address += 8;
+				pef::RelocOp::SetSectC { section } => {
+					sect_c = fragment.section_address(section as usize);
+					trace!(target: "linker", "[{block_pos:04X}] SetSectC (sect={section}) -> {sect_c:X}");
 				}
-			} else if (block & 0xFE00) == 0x4A00 {
-				// RelocImportRun
-				let run_length = (block & 0x1FF) + 1;
-				trace!(target: "linker", "[{block_pos:04X}] ImportRun @ {reloc_address:X} (x{run_length})");
-				for _ in 0..run_length {
-					let symbol = &loader.imported_symbols[import_index as usize];
-					trace!(target: "linker", "  {reloc_address:X} -> {import_index} - {}", &symbol.name);
-					self.set_u32(reloc_address, self.shim_addrs[import_index as usize]);
+				pef::RelocOp::SetSectD { section } => {
+					sect_d = fragment.section_address(section as usize);
+					trace!(target: "linker", "[{block_pos:04X}] SetSectD (sect={section}) -> {sect_d:X}");
+				}
+				pef::RelocOp::BySection { section } => {
+					trace!(target: "linker", "[{block_pos:04X}] BySection @ {reloc_address:X} (sect={section})");
+					self.relocate_by(reloc_address, fragment.section_address(section as usize))?;
 					reloc_address += 4;
-					import_index += 1;
 				}
-			} else if (block & 0xFE00) == 0x6000 {
-				// RelocSmByImport
-				let index = block & 0x1FF;
-				let symbol = &loader.imported_symbols[index as usize];
-				trace!(target: "linker", "[{block_pos:04X}] SmByImport @ {reloc_address:X} (sym={index} - {})", &symbol.name);
-				self.set_u32(reloc_address, self.shim_addrs[index as usize]);
-				reloc_address += 4;
-				import_index = index + 1;
-			} else if (block & 0xFE00) == 0x6200 {
-				// RelocSmSetSectC
-				let index = block & 0x1FF;
-				warn!(target: "linker", "[{block_pos:04X}] UNIMPLEMENTED SmSetSectC (sect={index})");
-			} else if (block & 0xFE00) == 0x6400 {
-				// RelocSmSetSectD
-				let index = block & 0x1FF;
-				warn!(target: "linker", "[{block_pos:04X}] UNIMPLEMENTED SmSetSectD (sect={index})");
-			} else if (block & 0xFE00) == 0x6600 {
-				// RelocSmBySection
-				let index = block & 0x1FF;
-				warn!(target: "linker", "[{block_pos:04X}] UNIMPLEMENTED SmBySection @ {reloc_address:X} (sect={index})");
-				reloc_address += 4;
-			} else if (block & 0xF000) == 0x8000 {
-				// RelocIncrPosition
-				let offset = (block & 0xFFF) + 1;
-				trace!(target: "linker", "[{block_pos:04X}] IncrPosition @ {reloc_address:X} += {offset:X} -> {:X}", reloc_address + offset);
-				reloc_address += offset;
-			} else if (block & 0xF000) == 0x9000 {
-				// RelocSmRepeat
-				let block_count = ((block >> 8) & 0xF) + 1;
-				let repeat_count = (block & 0xFF) + 1;
-				let repeat_start = block_pos - (block_count as usize);
-				match repeat_info {
-					Some((pos, counter)) if pos == block_pos => {
-						// repeat, maybe
-						trace!(target: "linker", "[{block_pos:04X}] SmRepeat from {repeat_start:04X}, iteration {counter}/{repeat_count}");
-						if counter < repeat_count {
-							next_block = repeat_start;
-							repeat_info = Some((pos, counter + 1));
-						}
-					}
-					_ => {
-						// start a repeat
-						trace!(target: "linker", "[{block_pos:04X}] SmRepeat from {repeat_start:04X}, iteration 0/{repeat_count}");
-						next_block = repeat_start;
-						repeat_info = Some((block_pos, 1));
-					}
+				pef::RelocOp::IncrPosition { offset } => {
+					trace!(target: "linker", "[{block_pos:04X}] IncrPosition @ {reloc_address:X} += {offset:X} -> {:X}", reloc_address.wrapping_add(offset));
+					reloc_address = reloc_address.wrapping_add(offset);
 				}
-			} else if (block & 0xFC00) == 0xA000 {
-				// RelocSetPosition
-				let offset = ((block & 0x3FF) << 16) | (relocs.data[next_block] as u32);
-				next_block += 1;
-				trace!(target: "linker", "[{block_pos:04X}] SetPosition = {offset:X}");
-				reloc_address = self.data_addr + offset;
-			} else if (block & 0xFC00) == 0xA400 {
-				// RelocLgByImport
-				let index = ((block & 0x3FF) << 16) | (relocs.data[next_block] as u32);
-				next_block += 1;
-				let symbol = &loader.imported_symbols[index as usize];
-				trace!(target: "linker", "[{block_pos:04X}] LgByImport @ {reloc_address:X} (sym={index} - {})", &symbol.name);
-				self.set_u32(reloc_address, self.shim_addrs[index as usize]);
-				reloc_address += 4;
-				import_index = index + 1;
-			} else if (block & 0xFC00) == 0xB000 {
-				// RelocSmRepeat
-				let block_count = ((block >> 8) & 0xF) + 1;
-				let repeat_count = ((block & 0xFF) << 16) | (relocs.data[next_block] as u32);
-				next_block += 1;
-				let repeat_start = block_pos - (block_count as usize);
-				warn!(target: "linker", "[{block_pos:04X}] UNIMPLEMENTED LgRepeat from {repeat_start:04X}, {repeat_count} times");
-			} else if (block & 0xFFC0) == 0xB400 {
-				// RelocLgBySection
-				let index = ((block & 0x3F) << 16) | (relocs.data[next_block] as u32);
-				next_block += 1;
-				warn!(target: "linker", "[{block_pos:04X}] UNIMPLEMENTED LgBySection @ {reloc_address:X} (sect={index})");
-				reloc_address += 4;
-			} else if (block & 0xFFC0) == 0xB440 {
-				// RelocLgSetSectC
-				let index = ((block & 0x3F) << 16) | (relocs.data[next_block] as u32);
-				next_block += 1;
-				warn!(target: "linker", "[{block_pos:04X}] UNIMPLEMENTED LgSetSectC (sect={index})");
-			} else if (block & 0xFFC0) == 0xB480 {
-				// RelocLgSetSectD
-				let index = ((block & 0x3F) << 16) | (relocs.data[next_block] as u32);
-				next_block += 1;
-				warn!(target: "linker", "[{block_pos:04X}] UNIMPLEMENTED LgSetSectD (sect={index})");
-			} else {
-				warn!(target: "linker", "[{block_pos:04X}] UNKNOWN OPCODE {block:04X}");
+				pef::RelocOp::Repeat { blocks, times } => {
+					let repeat_start = block_pos.checked_sub(blocks as usize)
+						.ok_or_else(|| anyhow!("[{block_pos:04X}] Repeat goes back {blocks} blocks, before the start"))?;
+					trace!(target: "linker", "[{block_pos:04X}] Repeat from {repeat_start:04X}, {times} times");
+					Self::handle_repeat(&mut repeat_info, &mut next_block, block_pos, repeat_start, times);
+				}
+				pef::RelocOp::SetPosition { offset } => {
+					trace!(target: "linker", "[{block_pos:04X}] SetPosition = {offset:X}");
+					reloc_address = fragment.section_address(relocs.section_index as usize).wrapping_add(offset);
+				}
+				pef::RelocOp::Unknown(block) => {
+					warn!(target: "linker", "[{block_pos:04X}] UNKNOWN OPCODE {block:04X}");
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Shared logic for RelocSmRepeat and RelocLgRepeat. The preceding blocks have
+	/// already been run once when we first reach the repeat instruction, so they are
+	/// run `repeat_count` more times on top of that.
+	fn handle_repeat(repeat_info: &mut Option<(usize, u32)>, next_block: &mut usize, block_pos: usize, repeat_start: usize, repeat_count: u32) {
+		match *repeat_info {
+			Some((pos, counter)) if pos == block_pos => {
+				trace!(target: "linker", "  iteration {counter}/{repeat_count}");
+				if counter < repeat_count {
+					*next_block = repeat_start;
+					*repeat_info = Some((pos, counter + 1));
+				} else {
+					*repeat_info = None;
+				}
+			}
+			_ if repeat_count > 0 => {
+				trace!(target: "linker", "  iteration 0/{repeat_count}");
+				*next_block = repeat_start;
+				*repeat_info = Some((block_pos, 1));
 			}
+			_ => {}
+		}
+	}
+}
+
+fn file_name(path: &Path) -> String {
+	path.file_name().map_or_else(String::new, |n| n.to_string_lossy().into_owned())
+}
+
+/// Opens a tool and loads it along with any libraries it needs, picking
+/// the fragment to use from its `cfrg` resource if it has one.
+pub fn load_executable(path: &Path, fragment_choice: Option<&str>, library_path: Vec<PathBuf>, data_import_sizes: fn(&str) -> Option<u32>) -> Result<(Executable, resources::Resources)> {
+	let file = Shared::new(filesystem::MacFile::open(path)?);
+	let res = resources::parse_resources(Shared::clone(&file))?;
+
+	let mut exe = Executable::new();
+	exe.library_path = library_path;
+	exe.data_import_sizes = data_import_sizes;
+
+	{
+		let file = file.borrow();
+		let container = cfrg::find_container(&file.data_fork, Some(&res), fragment_choice)?;
+		if xcoff::is_xcoff(container) {
+			exe.load_xcoff(path, xcoff::read_xcoff(container)?)?;
+		} else {
+			exe.load_pef(path, pef::read_pef(container)?)?;
+		}
+	}
+
+	Ok((exe, res))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn section(kind: pef::SectionType, contents: &[u8]) -> pef::Section {
+		let mut section = pef::Section {
+			name: None,
+			default_address: 0,
+			total_size: 0,
+			unpacked_size: 0,
+			packed_size: 0,
+			packed_contents: None,
+			section_kind: kind,
+			share_kind: pef::ShareType::ProcessShare,
+			alignment: 4
+		};
+		section.set_contents(contents);
+		section
+	}
+
+	fn import(name: &str, class: pef::SymbolClass) -> pef::ImportedSymbol {
+		pef::ImportedSymbol { library: 0, name: name.to_string(), class, weak: false }
+	}
+
+	/// A tool whose data section starts with main's transition vector and two
+	/// import slots, built the same way a linker would lay it out
+	fn fixture(code: &[u8], data: &[u8]) -> pef::PEF {
+		let reloc = |op| pef::encode_reloc(op).unwrap();
+		let loader = pef::Loader {
+			main_section: 1,
+			main_offset: 0,
+			init_section: -1,
+			init_offset: 0,
+			term_section: -1,
+			term_offset: 0,
+			imported_libraries: vec![pef::ImportedLibrary {
+				name: "InterfaceLib".to_string(),
+				old_imp_version: 0,
+				current_version: 0,
+				imported_symbols: Vec::new(),
+				import_order: false,
+				is_weak: false
+			}],
+			imported_symbols: vec![import("NewPtr", pef::SymbolClass::TVect), import("qd", pef::SymbolClass::Data)],
+			reloc_sections: vec![pef::RelocSection {
+				section_index: 1,
+				data: [reloc(pef::RelocOp::TVector8 { count: 1 }), reloc(pef::RelocOp::ImportRun { count: 2 })].concat()
+			}],
+			exports: pef::ExportTable::new(vec![pef::ExportedSymbol {
+				name: "helper".to_string(),
+				class: pef::SymbolClass::TVect,
+				value: 0,
+				section: 1,
+				key: 0
+			}])
+		};
+
+		let pef = pef::PEF {
+			architecture: pef::Architecture::PowerPC_CFM,
+			format_version: 1,
+			date_time_stamp: 0,
+			old_def_version: 0,
+			old_imp_version: 0,
+			current_version: 0,
+			sections: vec![
+				section(pef::SectionType::Code, code),
+				section(pef::SectionType::PatternInitData, data),
+				section(pef::SectionType::Loader, &pef::write_loader(&loader))
+			]
+		};
+		pef::read_pef(&pef::write_pef(&pef)).unwrap()
+	}
+
+	/// Swaps the fixture's relocations for these
+	fn with_relocs(mut pef: pef::PEF, ops: &[pef::RelocOp]) -> pef::PEF {
+		let loader_section = &mut pef.sections[2];
+		let mut loader = pef::parse_loader(loader_section.packed_contents.as_deref().unwrap()).unwrap();
+		loader.reloc_sections[0].data = ops.iter().flat_map(|&op| pef::encode_reloc(op).unwrap()).collect();
+		loader_section.set_contents(&pef::write_loader(&loader));
+		pef
+	}
+
+	#[test]
+	fn load_pef_places_and_relocates() {
+		let code = [0x60, 0, 0, 0].repeat(8); // nop
+		let mut data = vec![
+			0, 0, 0, 8, 0, 0, 0, 0x20, // main: code+8, TOC at data+0x20
+			0, 0, 0, 0, 0, 0, 0, 0 // NewPtr, qd
+		];
+		data.extend([0x12, 0x34, 0, 0].repeat(60));
+
+		let mut exe = Executable::new();
+		exe.load_pef(Path::new("/tools/Fixture"), fixture(&code, &data)).unwrap();
+
+		assert_eq!(exe.fragments.len(), 1);
+		let fragment = &exe.fragments[0];
+		assert_eq!(fragment.name, "Fixture");
+		let code_addr = fragment.sections[0].unwrap();
+		let data_addr = fragment.sections[1].unwrap();
+		assert!(fragment.sections[2].is_none());
+		assert_eq!(exe.region_for(code_addr).unwrap().protection, Protection::ReadExecute);
+		assert_eq!(exe.region_for(data_addr).unwrap().protection, Protection::ReadWrite);
+
+		let memory = |address: u32, len: usize| {
+			let start = (address - exe.memory_base) as usize;
+			&exe.memory[start .. start + len]
+		};
+		assert_eq!(memory(code_addr, code.len()), &code[..]);
+		assert_eq!(memory(data_addr + 0x10, data.len() - 0x10), &data[0x10 ..]);
+
+		assert_eq!(exe.main_vector, data_addr);
+		assert_eq!(exe.get_u32(data_addr), code_addr + 8);
+		assert_eq!(exe.get_u32(data_addr + 4), data_addr + 0x20);
+		assert_eq!(exe.fragments[0].find_export("helper"), Some((data_addr, pef::SymbolClass::TVect)));
+	}
+
+	#[test]
+	fn load_pef_binds_imports_to_shims() {
+		let mut exe = Executable::new();
+		exe.load_pef(Path::new("Fixture"), fixture(&[0; 4], &[0; 16])).unwrap();
+		let data_addr = exe.fragments[0].sections[1].unwrap();
+
+		assert_eq!(exe.libraries, ["InterfaceLib"]);
+		assert_eq!(exe.imports.len(), 2);
+
+		let new_ptr = &exe.imports[0];
+		assert_eq!((new_ptr.name.as_str(), new_ptr.class), ("NewPtr", pef::SymbolClass::TVect));
+		assert_eq!(new_ptr.sites, [data_addr + 8]);
+		assert_eq!(exe.get_u32(data_addr + 8), new_ptr.shim_addr);
+		// the shim is a transition vector into the system call thunk
+		assert_eq!(exe.get_u32(new_ptr.shim_addr), exe.sc_thunk_addr);
+		assert_eq!(exe.get_u32(new_ptr.shim_addr + 4), 0);
+
+		let qd = &exe.imports[1];
+		assert_eq!(qd.sites, [data_addr + 12]);
+		assert_eq!(exe.get_u32(data_addr + 12), qd.shim_addr);
+		assert_eq!(exe.region_for(qd.shim_addr).unwrap().protection, Protection::ReadWrite);
+		assert!(qd.definition.is_none());
+	}
+
+	#[test]
+	fn load_pef_rejects_bad_containers() {
+		for ops in [
+			&[pef::RelocOp::ByImport { index: 2 }][..],
+			&[pef::RelocOp::ImportRun { count: 3 }],
+			&[pef::RelocOp::Repeat { blocks: 1, times: 1 }],
+			&[pef::RelocOp::SetPosition { offset: 0x1000000 }, pef::RelocOp::BySectC { count: 1 }]
+		] {
+			let pef = with_relocs(fixture(&[0; 4], &[0; 16]), ops);
+			assert!(Executable::new().load_pef(Path::new("Fixture"), pef).is_err(), "{ops:?}");
 		}
+
+		let mut pef = fixture(&[0; 4], &[0; 16]);
+		pef.sections[0].alignment = 64;
+		assert!(Executable::new().load_pef(Path::new("Fixture"), pef).is_err());
 	}
 }
@@ -1,42 +1,289 @@
-use std::{rc::Rc, cell::RefCell};
-#[macro_use]
-extern crate log;
-
-mod common;
-mod emulator;
-mod linker;
-mod macbinary;
-mod mac_roman;
-mod filesystem;
-mod pef;
-mod resources;
+use std::path::{Path, PathBuf};
+
+use mpw_emu::cli;
+
+/// Gets the value of an option given as either `--name value` or
+/// `--name=value`, or None if `option` is something else.
+fn option_value(option: &str, name: &str, args: &mut Vec<String>) -> Option<String> {
+	if option == name {
+		if args.is_empty() {
+			eprintln!("{name} needs a value");
+			std::process::exit(1);
+		}
+		return Some(args.remove(0));
+	}
+	option.strip_prefix(name)?.strip_prefix('=').map(String::from)
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> T {
+	match value.parse() {
+		Ok(number) => number,
+		Err(_) => {
+			eprintln!("{name} needs a number, not {value:?}");
+			std::process::exit(1);
+		}
+	}
+}
+
+fn pef_dump(args: &[String]) -> i32 {
+	let usage = || {
+		eprintln!("Usage: mpw-emu pef-dump [--json] [--fragment=<index or name>] <file>");
+		1
+	};
+
+	let mut as_json = false;
+	let mut fragment_choice = None;
+	let mut path = None;
+
+	for arg in args {
+		if arg == "--json" {
+			as_json = true;
+		} else if let Some(value) = arg.strip_prefix("--fragment=") {
+			fragment_choice = Some(value);
+		} else if arg.starts_with("--") || path.is_some() {
+			return usage();
+		} else {
+			path = Some(arg);
+		}
+	}
+
+	let path = match path {
+		Some(p) => p,
+		None => return usage()
+	};
+
+	match cli::pef_dump(path, fragment_choice, as_json) {
+		Ok(()) => 0,
+		Err(e) => {
+			eprintln!("Cannot dump {path:?}: {e}");
+			1
+		}
+	}
+}
+
+fn check(args: &[String]) -> i32 {
+	let usage = || {
+		eprintln!("Usage: mpw-emu check [--fragment=<index or name>] [--library-path=<dirs>] <tool>");
+		1
+	};
+
+	let mut fragment_choice = None;
+	let mut library_path = std::env::var_os("MPW_EMU_LIBRARY_PATH")
+		.map(|paths| std::env::split_paths(&paths).collect::<Vec<_>>())
+		.unwrap_or_default();
+	let mut path = None;
+
+	for arg in args {
+		if let Some(value) = arg.strip_prefix("--fragment=") {
+			fragment_choice = Some(value);
+		} else if let Some(value) = arg.strip_prefix("--library-path=") {
+			library_path.extend(std::env::split_paths(value));
+		} else if arg.starts_with("--") || path.is_some() {
+			return usage();
+		} else {
+			path = Some(arg);
+		}
+	}
+
+	let path = match path {
+		Some(p) => p,
+		None => return usage()
+	};
+
+	match cli::check(path, fragment_choice, library_path) {
+		Ok(true) => 0,
+		Ok(false) => 1,
+		Err(e) => {
+			eprintln!("Cannot check {path:?}: {e}");
+			1
+		}
+	}
+}
+
+fn strip_imports(args: &[String]) -> i32 {
+	let (path, output, names) = match args {
+		[path, output, names @ ..] if !names.is_empty() && !path.starts_with("--") => (path, output, names),
+		_ => {
+			eprintln!("Usage: mpw-emu strip-imports <tool> <output> <symbol>...");
+			return 1;
+		}
+	};
+
+	match cli::strip_imports(Path::new(path), Path::new(output), names) {
+		Ok(count) => {
+			println!("Removed {count} imports");
+			0
+		}
+		Err(e) => {
+			eprintln!("Cannot strip imports from {path:?}: {e}");
+			1
+		}
+	}
+}
+
+#[cfg(unix)]
+fn serve_usage() -> i32 {
+	eprintln!("Usage: mpw-emu serve [--fragment=<index or name>] [--library-path=<dirs>] <socket> <tool>");
+	eprintln!("       mpw-emu client <socket> [arguments...]");
+	1
+}
+
+#[cfg(unix)]
+fn serve(args: &[String]) -> i32 {
+	let mut fragment_choice = None;
+	let mut library_path = std::env::var_os("MPW_EMU_LIBRARY_PATH")
+		.map(|paths| std::env::split_paths(&paths).collect::<Vec<_>>())
+		.unwrap_or_default();
+	let mut positional = Vec::new();
+
+	for arg in args {
+		if let Some(value) = arg.strip_prefix("--fragment=") {
+			fragment_choice = Some(value);
+		} else if let Some(value) = arg.strip_prefix("--library-path=") {
+			library_path.extend(std::env::split_paths(value));
+		} else if arg.starts_with("--") {
+			return serve_usage();
+		} else {
+			positional.push(arg);
+		}
+	}
+
+	let (socket, tool) = match positional[..] {
+		[socket, tool] => (Path::new(socket), tool),
+		_ => return serve_usage()
+	};
+
+	match cli::serve(socket, tool, fragment_choice, library_path) {
+		Ok(status) => status,
+		Err(e) => {
+			eprintln!("{e}");
+			1
+		}
+	}
+}
+
+#[cfg(unix)]
+fn client(args: &[String]) -> i32 {
+	let (socket, tool_args) = match args.split_first() {
+		Some(split) => split,
+		None => return serve_usage()
+	};
+
+	match cli::client(Path::new(socket), tool_args) {
+		Ok(status) => status,
+		Err(e) => {
+			eprintln!("mpw-emu client: {e}");
+			1
+		}
+	}
+}
 
 fn main() {
 	env_logger::init();
 
 	let env_vars = std::env::vars().collect::<Vec<_>>();
-	let args = std::env::args().skip(1).collect::<Vec<_>>();
+	let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+
+	if args.first().map(String::as_str) == Some("pef-dump") {
+		std::process::exit(pef_dump(&args[1..]));
+	}
+	if args.first().map(String::as_str) == Some("check") {
+		std::process::exit(check(&args[1..]));
+	}
+	if args.first().map(String::as_str) == Some("strip-imports") {
+		std::process::exit(strip_imports(&args[1..]));
+	}
+	#[cfg(unix)]
+	if args.first().map(String::as_str) == Some("serve") {
+		std::process::exit(serve(&args[1..]));
+	}
+	#[cfg(unix)]
+	if args.first().map(String::as_str) == Some("client") {
+		std::process::exit(client(&args[1..]));
+	}
+
+	// Options for the emulator itself come before the executable
+	let mut options = cli::RunOptions {
+		library_path: std::env::var_os("MPW_EMU_LIBRARY_PATH")
+			.map(|paths| std::env::split_paths(&paths).collect::<Vec<_>>())
+			.unwrap_or_default(),
+		..Default::default()
+	};
+	let mut snapshot_at = None;
+	let mut snapshot_file = None;
+	while !args.is_empty() && args[0].starts_with("--") {
+		let option = args.remove(0);
+		if let Some(value) = option.strip_prefix("--fragment=") {
+			options.fragment_choice = Some(value.to_string());
+		} else if let Some(value) = option.strip_prefix("--library-path=") {
+			options.library_path.extend(std::env::split_paths(value));
+		} else if option == "--trace-calls" {
+			options.trace_calls = true;
+		} else if let Some(value) = option.strip_prefix("--trace-calls=") {
+			options.trace_output = Some(value.into());
+		} else if let Some(value) = option.strip_prefix("--trace-filter=") {
+			options.trace_filters.extend(value.split(',').map(String::from));
+		} else if option == "--gdb" || option.starts_with("--gdb=") {
+			let value = match option.strip_prefix("--gdb=") {
+				Some(value) => Some(value.to_string()),
+				None if !args.is_empty() => Some(args.remove(0)),
+				None => None
+			};
+			match value.and_then(|v| v.parse().ok()) {
+				Some(port) => options.gdb_port = Some(port),
+				None => {
+					eprintln!("--gdb needs a port number");
+					std::process::exit(1);
+				}
+			}
+		} else if let Some(value) = option_value(&option, "--snapshot-at", &mut args) {
+			snapshot_at = Some(value);
+		} else if let Some(value) = option_value(&option, "--snapshot-file", &mut args) {
+			snapshot_file = Some(value);
+		} else if let Some(value) = option_value(&option, "--resume", &mut args) {
+			options.resume = Some(value.into());
+		} else if option == "--sanitize-heap" {
+			options.sanitize_heap = true;
+		} else if let Some(value) = option_value(&option, "--timeout", &mut args) {
+			let seconds = parse_number::<f64>("--timeout", &value);
+			match std::time::Duration::try_from_secs_f64(seconds) {
+				Ok(timeout) => options.limits.timeout = Some(timeout),
+				Err(_) => {
+					eprintln!("--timeout needs a number, not {value:?}");
+					std::process::exit(1);
+				}
+			}
+		} else if let Some(value) = option_value(&option, "--max-instructions", &mut args) {
+			options.limits.max_instructions = Some(parse_number("--max-instructions", &value));
+		} else if let Some(value) = option_value(&option, "--max-heap", &mut args) {
+			options.limits.max_heap = Some(parse_number("--max-heap", &value));
+		} else {
+			eprintln!("Unknown option: {option}");
+			std::process::exit(1);
+		}
+	}
+
 	if args.is_empty() {
 		eprintln!("No executable specified");
 		return;
 	}
 
-	let file = match filesystem::MacFile::open(&args[0]) {
-		Ok(f) => f,
+	if let Some(at) = snapshot_at {
+		let path = snapshot_file.map(PathBuf::from).unwrap_or_else(|| {
+			let tool = Path::new(&args[0]).file_name().unwrap_or_default();
+			PathBuf::from(format!("{}.snapshot", tool.to_string_lossy()))
+		});
+		options.snapshot = Some((at, path));
+	} else if snapshot_file.is_some() {
+		eprintln!("--snapshot-file needs --snapshot-at");
+		std::process::exit(1);
+	}
+
+	match cli::run(options, &args, &env_vars) {
+		Ok(code) => std::process::exit(code),
 		Err(e) => {
-			eprintln!("Cannot read executable: {:?}", args[0]);
-			eprintln!("{}", e);
-			return;
+			eprintln!("{e}");
+			std::process::exit(1);
 		}
-	};
-	let pef = pef::read_pef(&file.data_fork).expect("PEF parsing failed");
-
-	let file = Rc::new(RefCell::new(file));
-	let res = resources::parse_resources(file).expect("Resource fork loading failed");
-
-	let mut exe = linker::Executable::new();
-	exe.load_pef(pef);
-
-	let code = emulator::emulate(&exe, res, &args, &env_vars).unwrap();
-	std::process::exit(code);
+	}
 }
@@ -3,10 +3,18 @@ use std::io::Cursor;
 use binread::{BinReaderExt, NullString};
 
 mod data;
+mod writer;
 pub use data::{Architecture, SectionType, ShareType};
+pub use writer::{encode_reloc, write_loader, write_pef};
 
 #[derive(Debug)]
 pub struct PEF {
+	pub architecture: Architecture,
+	pub format_version: u32,
+	pub date_time_stamp: u32,
+	pub old_def_version: u32,
+	pub old_imp_version: u32,
+	pub current_version: u32,
 	pub sections: Vec<Section>
 }
 
@@ -69,12 +77,151 @@ pub struct RelocSection {
 	pub data: Vec<u16>
 }
 
-#[derive(Debug)]
+/// A decoded relocation instruction. The small and large forms of an opcode
+/// are decoded to the same thing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelocOp {
+	BySectDWithSkip { skip: u32, count: u32 },
+	BySectC { count: u32 },
+	BySectD { count: u32 },
+	TVector12 { count: u32 },
+	TVector8 { count: u32 },
+	VTable8 { count: u32 },
+	ImportRun { count: u32 },
+	ByImport { index: u32 },
+	SetSectC { section: u32 },
+	SetSectD { section: u32 },
+	BySection { section: u32 },
+	IncrPosition { offset: u32 },
+	/// Runs the preceding `blocks` blocks again, `times` more times
+	Repeat { blocks: u32, times: u32 },
+	SetPosition { offset: u32 },
+	Unknown(u16)
+}
+
+/// Decodes the relocation instruction starting at `pos`, returning it along
+/// with the number of blocks it takes up.
+pub fn decode_reloc(data: &[u16], pos: usize) -> (RelocOp, usize) {
+	let block = data[pos] as u32;
+	let next = data.get(pos + 1).copied().unwrap_or(0) as u32;
+
+	if (block & 0xC000) == 0 {
+		(RelocOp::BySectDWithSkip { skip: (block >> 6) & 0xFF, count: block & 0x3F }, 1)
+	} else if (block & 0xE000) == 0x4000 {
+		let count = (block & 0x1FF) + 1;
+		let op = match block & 0xFE00 {
+			0x4000 => RelocOp::BySectC { count },
+			0x4200 => RelocOp::BySectD { count },
+			0x4400 => RelocOp::TVector12 { count },
+			0x4600 => RelocOp::TVector8 { count },
+			0x4800 => RelocOp::VTable8 { count },
+			0x4A00 => RelocOp::ImportRun { count },
+			_ => RelocOp::Unknown(block as u16)
+		};
+		(op, 1)
+	} else if (block & 0xE000) == 0x6000 {
+		let index = block & 0x1FF;
+		let op = match block & 0xFE00 {
+			0x6000 => RelocOp::ByImport { index },
+			0x6200 => RelocOp::SetSectC { section: index },
+			0x6400 => RelocOp::SetSectD { section: index },
+			0x6600 => RelocOp::BySection { section: index },
+			_ => RelocOp::Unknown(block as u16)
+		};
+		(op, 1)
+	} else if (block & 0xF000) == 0x8000 {
+		(RelocOp::IncrPosition { offset: (block & 0xFFF) + 1 }, 1)
+	} else if (block & 0xF000) == 0x9000 {
+		(RelocOp::Repeat { blocks: ((block >> 8) & 0xF) + 1, times: (block & 0xFF) + 1 }, 1)
+	} else if (block & 0xFC00) == 0xA000 {
+		(RelocOp::SetPosition { offset: ((block & 0x3FF) << 16) | next }, 2)
+	} else if (block & 0xFC00) == 0xA400 {
+		(RelocOp::ByImport { index: ((block & 0x3FF) << 16) | next }, 2)
+	} else if (block & 0xFC00) == 0xB000 {
+		(RelocOp::Repeat { blocks: ((block >> 6) & 0xF) + 1, times: ((block & 0x3F) << 16) | next }, 2)
+	} else if (block & 0xFFC0) == 0xB400 {
+		(RelocOp::BySection { section: ((block & 0x3F) << 16) | next }, 2)
+	} else if (block & 0xFFC0) == 0xB440 {
+		(RelocOp::SetSectC { section: ((block & 0x3F) << 16) | next }, 2)
+	} else if (block & 0xFFC0) == 0xB480 {
+		(RelocOp::SetSectD { section: ((block & 0x3F) << 16) | next }, 2)
+	} else {
+		(RelocOp::Unknown(block as u16), 1)
+	}
+}
+
+#[derive(Debug, Clone)]
 pub struct ExportedSymbol {
 	pub name: String,
 	pub class: SymbolClass,
 	pub value: u32,
-	pub section: i16
+	pub section: i16,
+	/// Hash word from the key table (name length and hash value)
+	pub key: u32
+}
+
+/// Where an exported symbol lives, decoded from its section index
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportLocation {
+	/// Offset within an instantiated section
+	Section(u16, u32),
+	/// Absolute address
+	Absolute(u32),
+	/// Re-export of the imported symbol with this index
+	ReexportedImport(u32)
+}
+
+impl ExportedSymbol {
+	pub fn location(&self) -> ExportLocation {
+		match self.section {
+			-2 => ExportLocation::Absolute(self.value),
+			-3 => ExportLocation::ReexportedImport(self.value),
+			section => ExportLocation::Section(section as u16, self.value)
+		}
+	}
+}
+
+/// One slot of the export hash table, which points at a run of symbols that
+/// share the same hash index
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HashChain {
+	pub first_index: u32,
+	pub count: u32
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExportTable {
+	pub hash_table_power: u32,
+	pub hash_chains: Vec<HashChain>,
+	pub symbols: Vec<ExportedSymbol>
+}
+
+impl ExportTable {
+	/// Finds an exported symbol by name using the hash table.
+	pub fn find(&self, name: &str) -> Option<&ExportedSymbol> {
+		let key = hash_name(name.as_bytes());
+		let mask = 1u32.checked_shl(self.hash_table_power)? - 1;
+		let chain = self.hash_chains.get(((key ^ (key >> self.hash_table_power)) & mask) as usize)?;
+
+		let start = chain.first_index as usize;
+		let end = start.checked_add(chain.count as usize)?;
+		self.symbols.get(start .. end)?
+			.iter()
+			.find(|sym| sym.key == key && sym.name == name)
+	}
+}
+
+/// Computes the hash word for a symbol name, as used in the export key table.
+pub fn hash_name(name: &[u8]) -> u32 {
+	let mut hash = 0i32;
+	let mut length = 0u32;
+
+	for &c in name.iter().take_while(|&&c| c != 0) {
+		length += 1;
+		hash = ((hash << 1).wrapping_sub(hash >> 16)) ^ (c as i32);
+	}
+
+	(length << 16) | ((hash ^ (hash >> 16)) as u32 & 0xFFFF)
 }
 
 #[derive(Debug)]
@@ -88,7 +235,7 @@ pub struct Loader {
 	pub imported_libraries: Vec<ImportedLibrary>,
 	pub imported_symbols: Vec<ImportedSymbol>,
 	pub reloc_sections: Vec<RelocSection>,
-	pub exported_symbols: Vec<ExportedSymbol>,
+	pub exports: ExportTable
 }
 
 pub fn read_pef(data: &[u8]) -> Result<PEF, binread::Error> {
@@ -130,6 +277,12 @@ pub fn read_pef(data: &[u8]) -> Result<PEF, binread::Error> {
 	}
 
 	Ok(PEF {
+		architecture: container_header.architecture,
+		format_version: container_header.format_version,
+		date_time_stamp: container_header.date_time_stamp,
+		old_def_version: container_header.old_def_version,
+		old_imp_version: container_header.old_imp_version,
+		current_version: container_header.current_version,
 		sections
 	})
 }
@@ -249,9 +402,24 @@ pub fn unpack_pattern_data(input: &[u8], output: &mut [u8]) {
 	}
 }
 
+/// Adds an offset to one of the loader's table positions, failing if the
+/// result doesn't fit in 32 bits.
+fn loader_offset(base: u32, offset: u32, what: &str) -> binread::BinResult<u32> {
+	base.checked_add(offset).ok_or_else(|| binread::Error::AssertFail {
+		pos: base.into(),
+		message: format!("{what} offset {offset:X} overflows")
+	})
+}
+
 pub fn parse_loader(data: &[u8]) -> binread::BinResult<Loader> {
 	let mut cursor = Cursor::new(data);
 	let loader_header: data::LoaderHeader = cursor.read_be()?;
+	if loader_header.export_hash_table_power > 31 {
+		return Err(binread::Error::AssertFail {
+			pos: 0,
+			message: format!("Export hash table power {} is too big", loader_header.export_hash_table_power)
+		});
+	}
 
 	let mut imported_libraries_data: Vec<data::ImportedLibrary> = Vec::new();
 	let mut imported_symbols_data: Vec<u32> = Vec::new();
@@ -272,7 +440,7 @@ pub fn parse_loader(data: &[u8]) -> binread::BinResult<Loader> {
 
 	let mut imported_symbols: Vec<ImportedSymbol> = Vec::new();
 	for symbol in imported_symbols_data {
-		cursor.set_position((loader_header.loader_strings_offset + (symbol & 0xFFFFFF)).into());
+		cursor.set_position(loader_offset(loader_header.loader_strings_offset, symbol & 0xFFFFFF, "Import name")?.into());
 		let name = cursor.read_be::<NullString>()?.to_string();
 
 		let class = SymbolClass::parse((symbol >> 24) & 0xF);
@@ -282,18 +450,24 @@ pub fn parse_loader(data: &[u8]) -> binread::BinResult<Loader> {
 
 	let mut imported_libraries: Vec<ImportedLibrary> = Vec::new();
 	for lib in imported_libraries_data {
-		cursor.set_position((loader_header.loader_strings_offset + lib.name_offset).into());
+		cursor.set_position(loader_offset(loader_header.loader_strings_offset, lib.name_offset, "Library name")?.into());
 		let name = cursor.read_be::<NullString>()?.to_string();
 
 		let sym_start = lib.first_imported_symbol as usize;
-		let sym_end = (lib.first_imported_symbol + lib.imported_symbol_count) as usize;
+		let sym_end = sym_start.saturating_add(lib.imported_symbol_count as usize);
+		let Some(library_symbols) = imported_symbols.get_mut(sym_start .. sym_end) else {
+			return Err(binread::Error::AssertFail {
+				pos: cursor.position(),
+				message: format!("Library {name} imports symbols {sym_start}..{sym_end}, but there are only {}", imported_symbols.len())
+			});
+		};
 
 		let index = imported_libraries.len();
-		for sym in &mut imported_symbols[sym_start .. sym_end] {
+		for sym in library_symbols.iter_mut() {
 			sym.library = index;
 		}
 
-		let imported_symbols = imported_symbols[sym_start .. sym_end].to_vec();
+		let imported_symbols = library_symbols.to_vec();
 
 		imported_libraries.push(ImportedLibrary {
 			name,
@@ -310,7 +484,7 @@ pub fn parse_loader(data: &[u8]) -> binread::BinResult<Loader> {
 
 	let mut reloc_sections: Vec<RelocSection> = Vec::new();
 	for header in relocation_headers_data {
-		cursor.set_position((loader_header.reloc_instr_offset + header.first_reloc_offset).into());
+		cursor.set_position(loader_offset(loader_header.reloc_instr_offset, header.first_reloc_offset, "Relocation")?.into());
 
 		let mut data = Vec::new();
 		for _ in 0..header.reloc_count {
@@ -328,22 +502,49 @@ pub fn parse_loader(data: &[u8]) -> binread::BinResult<Loader> {
 
 	let hash_table_entry_count = 1u32 << loader_header.export_hash_table_power;
 
-	let key_table_pos = loader_header.export_hash_offset + hash_table_entry_count * 4;
-	let exports_pos = key_table_pos + loader_header.exported_symbol_count * 4;
+	let exported_symbol_count = loader_header.exported_symbol_count;
+	let too_big = || binread::Error::AssertFail {
+		pos: loader_header.export_hash_offset.into(),
+		message: format!(
+			"Export tables with 2^{} hash entries and {exported_symbol_count} symbols don't fit",
+			loader_header.export_hash_table_power)
+	};
+
+	// the symbol table comes last, so if its end fits, so does every entry before it
+	let key_table_pos = hash_table_entry_count.checked_mul(4)
+		.and_then(|size| loader_header.export_hash_offset.checked_add(size))
+		.ok_or_else(too_big)?;
+	let exports_pos = exported_symbol_count.checked_mul(4)
+		.and_then(|size| key_table_pos.checked_add(size))
+		.filter(|&pos| exported_symbol_count.checked_mul(10).and_then(|size| pos.checked_add(size)).is_some())
+		.ok_or_else(too_big)?;
+
+	let mut hash_chains = Vec::new();
+	cursor.set_position(loader_header.export_hash_offset.into());
+	for _ in 0..hash_table_entry_count {
+		let entry: u32 = cursor.read_be()?;
+		hash_chains.push(HashChain {
+			first_index: entry & 0x3FFFF,
+			count: entry >> 18
+		});
+	}
 
 	let mut exported_symbols = Vec::new();
 
-	for i in 0..loader_header.exported_symbol_count {
+	for i in 0..exported_symbol_count {
 		cursor.set_position((key_table_pos + i * 4).into());
 		let key: u32 = cursor.read_be()?;
 
 		cursor.set_position((exports_pos + i * 10).into());
 		let export: data::ExportedSymbol = cursor.read_be()?;
 
-		let name_offset = (loader_header.loader_strings_offset + (export.class_and_name & 0xFFFFFF)) as usize;
-		let name_length = key >> 16;
-		let name = data[name_offset .. name_offset + name_length as usize].to_vec();
-		let name = String::from_utf8(name).unwrap();
+		let name_offset = loader_offset(loader_header.loader_strings_offset, export.class_and_name & 0xFFFFFF, "Export name")? as usize;
+		let name_length = (key >> 16) as usize;
+		let name = data.get(name_offset .. name_offset + name_length).ok_or_else(|| binread::Error::AssertFail {
+			pos: name_offset as u64,
+			message: format!("Export name of {name_length} bytes runs past the end of the loader")
+		})?;
+		let name = String::from_utf8_lossy(name).into_owned();
 
 		let class = SymbolClass::parse((export.class_and_name >> 24) & 0xF);
 
@@ -351,7 +552,8 @@ pub fn parse_loader(data: &[u8]) -> binread::BinResult<Loader> {
 			name,
 			class,
 			value: export.symbol_value,
-			section: export.section_index
+			section: export.section_index,
+			key
 		});
 	}
 
@@ -365,7 +567,11 @@ pub fn parse_loader(data: &[u8]) -> binread::BinResult<Loader> {
 		imported_libraries,
 		imported_symbols,
 		reloc_sections,
-		exported_symbols
+		exports: ExportTable {
+			hash_table_power: loader_header.export_hash_table_power,
+			hash_chains,
+			symbols: exported_symbols
+		}
 	})
 }
 
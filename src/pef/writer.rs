@@ -0,0 +1,814 @@
+use super::{data::SectionType, ExportTable, ExportedSymbol, HashChain, Loader, RelocOp, Section, PEF, hash_name};
+
+// --
+// PATTERN DATA
+
+/// Largest block size considered when looking for repeated or interleaved
+/// patterns. Bigger patterns are rare in real data sections and cost a lot
+/// of time to search for.
+const MAX_PATTERN_SIZE: usize = 16;
+
+fn arg_length(value: usize) -> usize {
+	let mut length = 1;
+	let mut value = value >> 7;
+	while value != 0 {
+		length += 1;
+		value >>= 7;
+	}
+	length
+}
+
+fn opcode_length(count: usize) -> usize {
+	if count < 32 { 1 } else { 1 + arg_length(count) }
+}
+
+struct PatternWriter {
+	output: Vec<u8>
+}
+
+impl PatternWriter {
+	fn write_arg(&mut self, value: usize) {
+		let length = arg_length(value);
+		for i in (0..length).rev() {
+			let byte = ((value >> (i * 7)) & 0x7F) as u8;
+			self.output.push(if i == 0 { byte } else { byte | 0x80 });
+		}
+	}
+
+	fn write_opcode(&mut self, opcode: u8, count: usize) {
+		if count < 32 {
+			self.output.push((opcode << 5) | count as u8);
+		} else {
+			self.output.push(opcode << 5);
+			self.write_arg(count);
+		}
+	}
+}
+
+#[derive(Clone, Copy)]
+enum Pattern {
+	Zero { count: usize },
+	RepeatedBlock { size: usize, repeat_count: usize },
+	/// `repeat_count` runs of custom data, each one followed by the common block
+	Interleave { common_size: usize, custom_size: usize, repeat_count: usize, zero: bool }
+}
+
+impl Pattern {
+	/// Number of output bytes produced by this instruction
+	fn covered(&self) -> usize {
+		match *self {
+			Pattern::Zero { count } => count,
+			Pattern::RepeatedBlock { size, repeat_count } => size * (repeat_count + 1),
+			Pattern::Interleave { common_size, custom_size, repeat_count, .. } =>
+				common_size + repeat_count * (custom_size + common_size)
+		}
+	}
+
+	/// Number of bytes needed to encode this instruction
+	fn cost(&self) -> usize {
+		match *self {
+			Pattern::Zero { count } => opcode_length(count),
+			Pattern::RepeatedBlock { size, repeat_count } =>
+				opcode_length(size) + arg_length(repeat_count) + size,
+			Pattern::Interleave { common_size, custom_size, repeat_count, zero } =>
+				opcode_length(common_size) + arg_length(custom_size) + arg_length(repeat_count) +
+				(if zero { 0 } else { common_size }) + custom_size * repeat_count
+		}
+	}
+
+	fn gain(&self) -> isize {
+		self.covered() as isize - self.cost() as isize
+	}
+}
+
+/// Finds the most profitable instruction that can start at `pos`, if any.
+fn find_pattern(data: &[u8], pos: usize) -> Option<Pattern> {
+	let rest = &data[pos ..];
+	let mut best: Option<Pattern> = None;
+	let mut consider = |pattern: Pattern| {
+		if best.map_or(true, |b| pattern.gain() > b.gain()) {
+			best = Some(pattern);
+		}
+	};
+
+	let zeroes = rest.iter().take_while(|&&b| b == 0).count();
+	if zeroes > 0 {
+		consider(Pattern::Zero { count: zeroes });
+	}
+
+	for size in 1 ..= MAX_PATTERN_SIZE.min(rest.len() / 2) {
+		let block = &rest[.. size];
+		let repeat_count = rest[size ..].chunks_exact(size).take_while(|&chunk| chunk == block).count();
+		if repeat_count > 0 {
+			consider(Pattern::RepeatedBlock { size, repeat_count });
+		}
+	}
+
+	for common_size in 1 ..= MAX_PATTERN_SIZE {
+		if rest.len() < common_size * 2 {
+			break;
+		}
+		let common = &rest[.. common_size];
+		let zero = common.iter().all(|&b| b == 0);
+
+		for custom_size in 1 ..= MAX_PATTERN_SIZE {
+			let stride = custom_size + common_size;
+			let mut repeat_count = 0;
+			while rest.get(stride * (repeat_count + 1) .. stride * (repeat_count + 1) + common_size) == Some(common) {
+				repeat_count += 1;
+			}
+			if repeat_count > 0 {
+				consider(Pattern::Interleave { common_size, custom_size, repeat_count, zero });
+			}
+		}
+	}
+
+	best
+}
+
+/// Compresses data using the pattern-initialisation opcodes, producing
+/// something that `unpack_pattern_data` turns back into `input`.
+pub fn pack_pattern_data(input: &[u8]) -> Vec<u8> {
+	let mut writer = PatternWriter { output: Vec::new() };
+	let mut literal_start = 0;
+	let mut pos = 0;
+
+	let flush_literal = |writer: &mut PatternWriter, start: usize, end: usize| {
+		if end > start {
+			writer.write_opcode(1, end - start);
+			writer.output.extend_from_slice(&input[start .. end]);
+		}
+	};
+
+	while pos < input.len() {
+		// a pattern has to save a few bytes to be worth breaking up a literal run
+		let pattern = match find_pattern(input, pos) {
+			Some(p) if p.gain() > 2 || (p.gain() > 0 && literal_start == pos) => p,
+			_ => {
+				pos += 1;
+				continue;
+			}
+		};
+
+		flush_literal(&mut writer, literal_start, pos);
+
+		match pattern {
+			Pattern::Zero { count } => writer.write_opcode(0, count),
+			Pattern::RepeatedBlock { size, repeat_count } => {
+				writer.write_opcode(2, size);
+				writer.write_arg(repeat_count);
+				writer.output.extend_from_slice(&input[pos .. pos + size]);
+			}
+			Pattern::Interleave { common_size, custom_size, repeat_count, zero } => {
+				writer.write_opcode(if zero { 4 } else { 3 }, common_size);
+				writer.write_arg(custom_size);
+				writer.write_arg(repeat_count);
+				if !zero {
+					writer.output.extend_from_slice(&input[pos .. pos + common_size]);
+				}
+				for i in 0..repeat_count {
+					let start = pos + common_size + i * (custom_size + common_size);
+					writer.output.extend_from_slice(&input[start .. start + custom_size]);
+				}
+			}
+		}
+
+		pos += pattern.covered();
+		literal_start = pos;
+	}
+
+	flush_literal(&mut writer, literal_start, pos);
+	writer.output
+}
+
+// --
+// RELOCATIONS
+
+/// Encodes a relocation instruction, using the small form where the operands
+/// fit and splitting runs that are too long for a single instruction.
+/// Returns None if an operand can't be represented at all.
+pub fn encode_reloc(op: RelocOp) -> Option<Vec<u16>> {
+	let small_run = |opcode: u16, count: u32| {
+		let mut blocks = Vec::new();
+		let mut remaining = count;
+		while remaining > 0 {
+			let chunk = remaining.min(0x200);
+			blocks.push(opcode | (chunk - 1) as u16);
+			remaining -= chunk;
+		}
+		blocks
+	};
+	let index_op = |small: u16, large: u16, index: u32| {
+		if index < 0x200 {
+			Some(vec![small | index as u16])
+		} else if index < 0x400000 {
+			Some(vec![large | (index >> 16) as u16, index as u16])
+		} else {
+			None
+		}
+	};
+
+	match op {
+		RelocOp::BySectDWithSkip { skip, count } =>
+			(skip < 0x100 && count < 0x40).then(|| vec![((skip << 6) | count) as u16]),
+		RelocOp::BySectC { count } => Some(small_run(0x4000, count)),
+		RelocOp::BySectD { count } => Some(small_run(0x4200, count)),
+		RelocOp::TVector12 { count } => Some(small_run(0x4400, count)),
+		RelocOp::TVector8 { count } => Some(small_run(0x4600, count)),
+		RelocOp::VTable8 { count } => Some(small_run(0x4800, count)),
+		RelocOp::ImportRun { count } => Some(small_run(0x4A00, count)),
+		RelocOp::ByImport { index } => {
+			if index < 0x200 {
+				Some(vec![0x6000 | index as u16])
+			} else if index < 0x4000000 {
+				Some(vec![0xA400 | (index >> 16) as u16, index as u16])
+			} else {
+				None
+			}
+		}
+		RelocOp::SetSectC { section } => index_op(0x6200, 0xB440, section),
+		RelocOp::SetSectD { section } => index_op(0x6400, 0xB480, section),
+		RelocOp::BySection { section } => index_op(0x6600, 0xB400, section),
+		RelocOp::IncrPosition { offset } => Some(small_run(0x8000, offset)),
+		RelocOp::Repeat { blocks, times } => {
+			if blocks == 0 || blocks > 16 || times == 0 {
+				None
+			} else if times <= 0x100 {
+				Some(vec![0x9000 | ((blocks - 1) << 8) as u16 | (times - 1) as u16])
+			} else if times < 0x400000 {
+				Some(vec![0xB000 | ((blocks - 1) << 6) as u16 | (times >> 16) as u16, times as u16])
+			} else {
+				None
+			}
+		}
+		RelocOp::SetPosition { offset } =>
+			(offset < 0x4000000).then(|| vec![0xA000 | (offset >> 16) as u16, offset as u16]),
+		RelocOp::Unknown(block) => Some(vec![block])
+	}
+}
+
+// --
+// EXPORTS
+
+/// Hash tables are sized so that chains average fewer than this many symbols
+const AVERAGE_CHAIN_LIMIT: usize = 10;
+
+impl ExportTable {
+	/// Builds a hash table for a set of exports, reordering them so that each
+	/// hash chain is contiguous.
+	pub fn new(mut symbols: Vec<ExportedSymbol>) -> ExportTable {
+		let mut hash_table_power = 0;
+		while hash_table_power < 16 && (symbols.len() >> hash_table_power) >= AVERAGE_CHAIN_LIMIT {
+			hash_table_power += 1;
+		}
+
+		let mask = (1u32 << hash_table_power) - 1;
+		let slot = |key: u32| (key ^ (key >> hash_table_power)) & mask;
+
+		for sym in &mut symbols {
+			sym.key = hash_name(sym.name.as_bytes());
+		}
+		symbols.sort_by_key(|sym| slot(sym.key));
+
+		let mut hash_chains = vec![HashChain::default(); 1 << hash_table_power];
+		for (i, sym) in symbols.iter().enumerate() {
+			let chain = &mut hash_chains[slot(sym.key) as usize];
+			if chain.count == 0 {
+				chain.first_index = i as u32;
+			}
+			chain.count += 1;
+		}
+
+		ExportTable { hash_table_power, hash_chains, symbols }
+	}
+}
+
+// --
+// LOADER SECTION
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+	out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+	out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn align(out: &mut Vec<u8>, alignment: usize) {
+	while out.len() % alignment != 0 {
+		out.push(0);
+	}
+}
+
+/// Serialises a loader section. Each library's symbols must be contiguous
+/// in `imported_symbols`, which is how `parse_loader` produces them.
+pub fn write_loader(loader: &Loader) -> Vec<u8> {
+	const HEADER_SIZE: usize = 56;
+	const LIBRARY_SIZE: usize = 24;
+	const RELOC_HEADER_SIZE: usize = 12;
+
+	// the string table goes after the relocations, but we need to know the
+	// offsets before writing the tables that refer to it
+	let mut strings = Vec::new();
+	let mut add_string = |s: &str, terminate: bool| {
+		let offset = strings.len() as u32;
+		strings.extend_from_slice(s.as_bytes());
+		if terminate {
+			strings.push(0);
+		}
+		offset
+	};
+	let library_names: Vec<u32> = loader.imported_libraries.iter().map(|lib| add_string(&lib.name, true)).collect();
+	let import_names: Vec<u32> = loader.imported_symbols.iter().map(|sym| add_string(&sym.name, true)).collect();
+	// export names are not terminated, their length comes from the key
+	let export_names: Vec<u32> = loader.exports.symbols.iter().map(|sym| add_string(&sym.name, false)).collect();
+
+	let reloc_instr_offset = HEADER_SIZE +
+		loader.imported_libraries.len() * LIBRARY_SIZE +
+		loader.imported_symbols.len() * 4 +
+		loader.reloc_sections.len() * RELOC_HEADER_SIZE;
+	let reloc_size: usize = loader.reloc_sections.iter().map(|r| r.data.len() * 2).sum();
+	let loader_strings_offset = reloc_instr_offset + reloc_size;
+	let export_hash_offset = (loader_strings_offset + strings.len() + 3) & !3;
+
+	let mut out = Vec::new();
+
+	// header
+	push_u32(&mut out, loader.main_section as u32);
+	push_u32(&mut out, loader.main_offset);
+	push_u32(&mut out, loader.init_section as u32);
+	push_u32(&mut out, loader.init_offset);
+	push_u32(&mut out, loader.term_section as u32);
+	push_u32(&mut out, loader.term_offset);
+	push_u32(&mut out, loader.imported_libraries.len() as u32);
+	push_u32(&mut out, loader.imported_symbols.len() as u32);
+	push_u32(&mut out, loader.reloc_sections.len() as u32);
+	push_u32(&mut out, reloc_instr_offset as u32);
+	push_u32(&mut out, loader_strings_offset as u32);
+	push_u32(&mut out, export_hash_offset as u32);
+	push_u32(&mut out, loader.exports.hash_table_power);
+	push_u32(&mut out, loader.exports.symbols.len() as u32);
+
+	// imported libraries
+	for (index, lib) in loader.imported_libraries.iter().enumerate() {
+		let first = loader.imported_symbols.iter().position(|sym| sym.library == index)
+			.unwrap_or(loader.imported_symbols.len());
+		let count = loader.imported_symbols.iter().filter(|sym| sym.library == index).count();
+		let options = (if lib.import_order { 0x80 } else { 0 }) | (if lib.is_weak { 0x40 } else { 0 });
+
+		push_u32(&mut out, library_names[index]);
+		push_u32(&mut out, lib.old_imp_version);
+		push_u32(&mut out, lib.current_version);
+		push_u32(&mut out, count as u32);
+		push_u32(&mut out, first as u32);
+		out.push(options);
+		out.push(0);
+		push_u16(&mut out, 0);
+	}
+
+	// imported symbols
+	for (sym, &name_offset) in loader.imported_symbols.iter().zip(&import_names) {
+		let weak = if sym.weak { 0x80000000 } else { 0 };
+		push_u32(&mut out, weak | ((sym.class as u32) << 24) | name_offset);
+	}
+
+	// relocation headers, then the instructions themselves
+	let mut first_reloc_offset = 0;
+	for relocs in &loader.reloc_sections {
+		push_u16(&mut out, relocs.section_index);
+		push_u16(&mut out, 0);
+		push_u32(&mut out, relocs.data.len() as u32);
+		push_u32(&mut out, first_reloc_offset);
+		first_reloc_offset += relocs.data.len() as u32 * 2;
+	}
+	for relocs in &loader.reloc_sections {
+		for &block in &relocs.data {
+			push_u16(&mut out, block);
+		}
+	}
+
+	out.extend_from_slice(&strings);
+	align(&mut out, 4);
+
+	// exports: hash table, key table, symbol table
+	for chain in &loader.exports.hash_chains {
+		push_u32(&mut out, (chain.count << 18) | chain.first_index);
+	}
+	for sym in &loader.exports.symbols {
+		push_u32(&mut out, hash_name(sym.name.as_bytes()));
+	}
+	for (sym, &name_offset) in loader.exports.symbols.iter().zip(&export_names) {
+		push_u32(&mut out, ((sym.class as u32) << 24) | name_offset);
+		push_u32(&mut out, sym.value);
+		push_u16(&mut out, sym.section as u16);
+	}
+
+	out
+}
+
+// --
+// CONTAINER
+
+impl Section {
+	/// Replaces the section's contents, compressing them if this is a pattern
+	/// data section.
+	pub fn set_contents(&mut self, unpacked: &[u8]) {
+		let packed = if self.section_kind == SectionType::PatternInitData {
+			pack_pattern_data(unpacked)
+		} else {
+			unpacked.to_vec()
+		};
+
+		self.unpacked_size = unpacked.len() as u32;
+		self.total_size = self.total_size.max(self.unpacked_size);
+		self.packed_size = packed.len() as u32;
+		self.packed_contents = Some(packed);
+	}
+
+	fn is_instantiated(&self) -> bool {
+		matches!(self.section_kind,
+			SectionType::Code | SectionType::UnpackedData | SectionType::PatternInitData |
+			SectionType::Constant | SectionType::ExecutableData)
+	}
+}
+
+/// Serialises a container. Instantiated sections should come before the
+/// others, as the Code Fragment Manager expects.
+pub fn write_pef(pef: &PEF) -> Vec<u8> {
+	const HEADER_SIZE: usize = 40;
+	const SECTION_HEADER_SIZE: usize = 28;
+	const CONTENTS_ALIGNMENT: usize = 16;
+
+	let mut names = Vec::new();
+	let name_offsets: Vec<i32> = pef.sections.iter().map(|section| match &section.name {
+		Some(name) => {
+			let offset = names.len() as i32;
+			names.extend_from_slice(name.as_bytes());
+			names.push(0);
+			offset
+		}
+		None => -1
+	}).collect();
+
+	let mut offset = HEADER_SIZE + pef.sections.len() * SECTION_HEADER_SIZE + names.len();
+	let mut container_offsets = Vec::new();
+	for section in &pef.sections {
+		offset = (offset + CONTENTS_ALIGNMENT - 1) & !(CONTENTS_ALIGNMENT - 1);
+		container_offsets.push(offset as u32);
+		offset += section.packed_contents.as_ref().map_or(0, Vec::len);
+	}
+
+	let mut out = Vec::new();
+	out.extend_from_slice(b"Joy!peff");
+	push_u32(&mut out, pef.architecture as u32);
+	push_u32(&mut out, pef.format_version);
+	push_u32(&mut out, pef.date_time_stamp);
+	push_u32(&mut out, pef.old_def_version);
+	push_u32(&mut out, pef.old_imp_version);
+	push_u32(&mut out, pef.current_version);
+	push_u16(&mut out, pef.sections.len() as u16);
+	push_u16(&mut out, pef.sections.iter().filter(|s| s.is_instantiated()).count() as u16);
+	push_u32(&mut out, 0);
+
+	for ((section, &name_offset), &container_offset) in pef.sections.iter().zip(&name_offsets).zip(&container_offsets) {
+		let packed_size = section.packed_contents.as_ref().map_or(0, Vec::len) as u32;
+		push_u32(&mut out, name_offset as u32);
+		push_u32(&mut out, section.default_address);
+		push_u32(&mut out, section.total_size);
+		push_u32(&mut out, section.unpacked_size);
+		push_u32(&mut out, packed_size);
+		push_u32(&mut out, if packed_size > 0 { container_offset } else { 0 });
+		out.push(section.section_kind as u8);
+		out.push(section.share_kind as u8);
+		out.push(section.alignment);
+		out.push(0);
+	}
+
+	out.extend_from_slice(&names);
+
+	for section in &pef.sections {
+		if let Some(contents) = &section.packed_contents {
+			align(&mut out, CONTENTS_ALIGNMENT);
+			out.extend_from_slice(contents);
+		}
+	}
+
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::pef::{decode_reloc, parse_loader, read_pef, unpack_pattern_data, ImportedLibrary, ImportedSymbol, RelocSection, SymbolClass};
+	use crate::pef::data::{Architecture, ShareType};
+
+	/// Bytes that don't compress, from a simple LCG so the tests are repeatable
+	fn noise(seed: u32, len: usize) -> Vec<u8> {
+		let mut state = seed;
+		(0..len).map(|_| {
+			state = state.wrapping_mul(1103515245).wrapping_add(12345);
+			(state >> 16) as u8
+		}).collect()
+	}
+
+	fn round_trip(input: &[u8]) -> Vec<u8> {
+		let packed = pack_pattern_data(input);
+		let mut output = vec![0xAA; input.len()];
+		unpack_pattern_data(&packed, &mut output);
+		assert_eq!(output, input);
+		packed
+	}
+
+	#[test]
+	fn pattern_data_literals_and_zeroes() {
+		round_trip(&[]);
+		round_trip(&[0]);
+		round_trip(&[1, 2, 3]);
+		round_trip(&noise(1, 1000));
+
+		let packed = round_trip(&[0; 5000]);
+		assert!(packed.len() < 4);
+
+		let mut mixed = noise(2, 40);
+		mixed.extend_from_slice(&[0; 100]);
+		mixed.extend(noise(3, 3));
+		mixed.extend_from_slice(&[0; 2]);
+		round_trip(&mixed);
+	}
+
+	#[test]
+	fn pattern_data_repeated_block() {
+		let input: Vec<u8> = [0x12, 0x34, 0x56, 0x78].repeat(200);
+		let packed = round_trip(&input);
+		assert_eq!(packed[0] >> 5, 2);
+		assert!(packed.len() < 16);
+
+		// a repeated block in the middle of literal data
+		let mut input = noise(4, 50);
+		input.extend([0xFF, 0xFE, 0xFD].repeat(30));
+		input.extend(noise(5, 50));
+		let packed = round_trip(&input);
+		assert!(packed.len() < input.len() - 60);
+	}
+
+	#[test]
+	fn pattern_data_interleaved() {
+		// an array of structs that share a common header, like vtables
+		let mut input = Vec::new();
+		for i in 0..64u8 {
+			input.extend_from_slice(&[0x40, 0x00, 0x12, 0x34]);
+			input.extend_from_slice(&[i, i.wrapping_mul(7)]);
+		}
+		input.extend_from_slice(&[0x40, 0x00, 0x12, 0x34]);
+		let packed = round_trip(&input);
+		assert_eq!(packed[0] >> 5, 3);
+		assert!(packed.len() < input.len() / 2);
+
+		// the same with zeroes in between, which needs no common block
+		let mut input = vec![0; 8];
+		for i in 0..64u8 {
+			input.extend_from_slice(&[i, 0x80 | i, 0x55]);
+			input.extend_from_slice(&[0; 8]);
+		}
+		let packed = round_trip(&input);
+		assert_eq!(packed[0] >> 5, 4);
+		assert!(packed.len() < input.len() / 2);
+
+		let mut input = noise(6, 33);
+		input.extend_from_slice(&[0; 7]);
+		for chunk in noise(7, 99).chunks(3) {
+			input.extend_from_slice(chunk);
+			input.extend_from_slice(&[0; 7]);
+		}
+		round_trip(&input);
+	}
+
+	fn decode_all(blocks: &[u16]) -> Vec<RelocOp> {
+		let mut ops = Vec::new();
+		let mut pos = 0;
+		while pos < blocks.len() {
+			let (op, length) = decode_reloc(blocks, pos);
+			ops.push(op);
+			pos += length;
+		}
+		ops
+	}
+
+	#[test]
+	fn relocation_round_trip() {
+		let ops = [
+			RelocOp::BySectDWithSkip { skip: 0xFF, count: 0x3F },
+			RelocOp::BySectC { count: 1 },
+			RelocOp::BySectD { count: 0x200 },
+			RelocOp::TVector12 { count: 3 },
+			RelocOp::TVector8 { count: 4 },
+			RelocOp::VTable8 { count: 5 },
+			RelocOp::ImportRun { count: 6 },
+			RelocOp::ByImport { index: 0x1FF },
+			RelocOp::ByImport { index: 0x12345 },
+			RelocOp::SetSectC { section: 2 },
+			RelocOp::SetSectC { section: 0x300 },
+			RelocOp::SetSectD { section: 1 },
+			RelocOp::SetSectD { section: 0x3FFFFF },
+			RelocOp::BySection { section: 3 },
+			RelocOp::BySection { section: 0x10000 },
+			RelocOp::IncrPosition { offset: 0x100 },
+			RelocOp::Repeat { blocks: 16, times: 0x100 },
+			RelocOp::Repeat { blocks: 3, times: 0x12345 },
+			RelocOp::SetPosition { offset: 0x3FFFFFF }
+		];
+		for op in ops {
+			let blocks = encode_reloc(op).unwrap();
+			assert_eq!(decode_all(&blocks), [op], "{op:?} encoded as {blocks:04X?}");
+		}
+	}
+
+	#[test]
+	fn relocation_long_runs_are_split() {
+		let blocks = encode_reloc(RelocOp::ImportRun { count: 0x450 }).unwrap();
+		let counts: Vec<u32> = decode_all(&blocks).into_iter().map(|op| match op {
+			RelocOp::ImportRun { count } => count,
+			op => panic!("unexpected {op:?}")
+		}).collect();
+		assert_eq!(counts, [0x200, 0x200, 0x50]);
+
+		assert_eq!(encode_reloc(RelocOp::BySectC { count: 0 }), Some(Vec::new()));
+	}
+
+	#[test]
+	fn relocation_out_of_range() {
+		assert_eq!(encode_reloc(RelocOp::BySectDWithSkip { skip: 0x100, count: 1 }), None);
+		assert_eq!(encode_reloc(RelocOp::BySectDWithSkip { skip: 1, count: 0x40 }), None);
+		assert_eq!(encode_reloc(RelocOp::ByImport { index: 0x4000000 }), None);
+		assert_eq!(encode_reloc(RelocOp::SetSectC { section: 0x400000 }), None);
+		assert_eq!(encode_reloc(RelocOp::Repeat { blocks: 17, times: 1 }), None);
+		assert_eq!(encode_reloc(RelocOp::Repeat { blocks: 1, times: 0 }), None);
+		assert_eq!(encode_reloc(RelocOp::SetPosition { offset: 0x4000000 }), None);
+	}
+
+	fn section(name: &str, kind: SectionType, contents: &[u8]) -> Section {
+		let mut section = Section {
+			name: Some(name.to_string()),
+			default_address: 0,
+			total_size: 0,
+			unpacked_size: 0,
+			packed_size: 0,
+			packed_contents: None,
+			section_kind: kind,
+			share_kind: ShareType::ProcessShare,
+			alignment: 4
+		};
+		section.set_contents(contents);
+		section
+	}
+
+	fn import(library: usize, name: &str, class: SymbolClass, weak: bool) -> ImportedSymbol {
+		ImportedSymbol { library, name: name.to_string(), class, weak }
+	}
+
+	fn library(name: &str, is_weak: bool) -> ImportedLibrary {
+		ImportedLibrary {
+			name: name.to_string(),
+			old_imp_version: 0,
+			current_version: 0x01008000,
+			imported_symbols: Vec::new(),
+			import_order: false,
+			is_weak
+		}
+	}
+
+	#[test]
+	fn container_round_trip() {
+		let exports: Vec<ExportedSymbol> = (0..300).map(|i| ExportedSymbol {
+			name: format!("export_{i}"),
+			class: if i % 2 == 0 { SymbolClass::TVect } else { SymbolClass::Data },
+			value: i * 8,
+			section: 1,
+			key: 0
+		}).collect();
+		let exports = ExportTable::new(exports);
+		assert!(exports.hash_table_power > 0);
+
+		let imported_symbols = vec![
+			import(0, "NewPtr", SymbolClass::TVect, false),
+			import(0, "qd", SymbolClass::Data, false),
+			import(1, "printf", SymbolClass::TVect, false),
+			import(1, "__ctype", SymbolClass::Data, true)
+		];
+		let loader = Loader {
+			main_section: 1,
+			main_offset: 0x10,
+			init_section: -1,
+			init_offset: 0,
+			term_section: 0,
+			term_offset: 0x20,
+			imported_libraries: vec![library("InterfaceLib", false), library("StdCLib", true)],
+			imported_symbols,
+			reloc_sections: vec![RelocSection {
+				section_index: 1,
+				data: [
+					encode_reloc(RelocOp::BySectC { count: 2 }).unwrap(),
+					encode_reloc(RelocOp::ImportRun { count: 4 }).unwrap()
+				].concat()
+			}],
+			exports
+		};
+
+		let code = noise(8, 0x64);
+		let mut data = vec![0; 0x40];
+		data.extend([0x10, 0, 0, 0, 0, 0, 0, 0].repeat(40));
+		let pef = PEF {
+			architecture: Architecture::PowerPC_CFM,
+			format_version: 1,
+			date_time_stamp: 0xB1234567,
+			old_def_version: 0,
+			old_imp_version: 0,
+			current_version: 0,
+			sections: vec![
+				section("code", SectionType::Code, &code),
+				section("data", SectionType::PatternInitData, &data),
+				section("loader", SectionType::Loader, &write_loader(&loader))
+			]
+		};
+
+		let read = read_pef(&write_pef(&pef)).unwrap();
+		assert_eq!(read.architecture, Architecture::PowerPC_CFM);
+		assert_eq!(read.date_time_stamp, 0xB1234567);
+		assert_eq!(read.sections.len(), 3);
+		for (read, written) in read.sections.iter().zip(&pef.sections) {
+			assert_eq!(read.name, written.name);
+			assert_eq!(read.section_kind, written.section_kind);
+			assert_eq!(read.total_size, written.total_size);
+			assert_eq!(read.unpacked_size, written.unpacked_size);
+			assert_eq!(read.packed_contents, written.packed_contents);
+		}
+		assert_eq!(read.sections[0].packed_contents.as_deref(), Some(&code[..]));
+
+		let data_section = &read.sections[1];
+		assert!(data_section.packed_size < data_section.unpacked_size);
+		let mut unpacked = vec![0xAA; data_section.unpacked_size as usize];
+		unpack_pattern_data(data_section.packed_contents.as_ref().unwrap(), &mut unpacked);
+		assert_eq!(unpacked, data);
+
+		let parsed = parse_loader(read.sections[2].packed_contents.as_ref().unwrap()).unwrap();
+		assert_eq!((parsed.main_section, parsed.main_offset), (1, 0x10));
+		assert_eq!(parsed.init_section, -1);
+		assert_eq!((parsed.term_section, parsed.term_offset), (0, 0x20));
+
+		let libraries: Vec<_> = parsed.imported_libraries.iter()
+			.map(|lib| (lib.name.as_str(), lib.imported_symbols.len(), lib.is_weak))
+			.collect();
+		assert_eq!(libraries, [("InterfaceLib", 2, false), ("StdCLib", 2, true)]);
+		for (read, written) in parsed.imported_symbols.iter().zip(&loader.imported_symbols) {
+			assert_eq!((read.library, &read.name, read.class, read.weak), (written.library, &written.name, written.class, written.weak));
+		}
+
+		assert_eq!(parsed.reloc_sections.len(), 1);
+		assert_eq!(parsed.reloc_sections[0].section_index, 1);
+		assert_eq!(parsed.reloc_sections[0].data, loader.reloc_sections[0].data);
+
+		assert_eq!(parsed.exports.hash_table_power, loader.exports.hash_table_power);
+		assert_eq!(parsed.exports.symbols.len(), 300);
+		for i in 0..300 {
+			let name = format!("export_{i}");
+			let sym = parsed.exports.find(&name).unwrap_or_else(|| panic!("{name} not found"));
+			assert_eq!((sym.value, sym.section), (i * 8, 1));
+			assert_eq!(sym.class, if i % 2 == 0 { SymbolClass::TVect } else { SymbolClass::Data });
+		}
+		assert!(parsed.exports.find("export_300").is_none());
+	}
+	#[test]
+	fn loader_with_oversized_export_tables() {
+		let exports = ExportTable::new(vec![ExportedSymbol {
+			name: "main".to_string(),
+			class: SymbolClass::TVect,
+			value: 0,
+			section: 1,
+			key: 0
+		}]);
+		let loader = Loader {
+			main_section: -1,
+			main_offset: 0,
+			init_section: -1,
+			init_offset: 0,
+			term_section: -1,
+			term_offset: 0,
+			imported_libraries: Vec::new(),
+			imported_symbols: Vec::new(),
+			reloc_sections: Vec::new(),
+			exports
+		};
+		let written = write_loader(&loader);
+		assert!(parse_loader(&written).unwrap().exports.find("main").is_some());
+
+		// export_hash_table_power, then exported_symbol_count
+		for (offset, value) in [(0x30, 32u32), (0x30, 31), (0x34, 0x20000000)] {
+			let mut data = written.clone();
+			data[offset .. offset + 4].copy_from_slice(&value.to_be_bytes());
+			assert!(parse_loader(&data).is_err());
+		}
+
+		let table = ExportTable { hash_table_power: 32, ..loader.exports };
+		assert!(table.find("main").is_none());
+	}
+}
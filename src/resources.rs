@@ -1,8 +1,8 @@
-use std::{io::{Cursor, Read}, collections::HashMap, rc::Rc, cell::RefCell};
+use std::{io::{Cursor, Read}, collections::HashMap};
 
 use binread::{BinRead, BinReaderExt, BinResult};
 
-use crate::{common::FourCC, filesystem::MacFile};
+use crate::{common::{FourCC, Shared}, filesystem::MacFile};
 
 #[derive(BinRead, Debug)]
 struct Header {
@@ -48,13 +48,13 @@ pub struct Resource {
 }
 
 pub struct Resources {
-	pub file: Rc<RefCell<MacFile>>,
+	pub file: Shared<MacFile>,
 	pub attributes: u16,
-	pub types: HashMap<FourCC, Vec<Rc<RefCell<Resource>>>>
+	pub types: HashMap<FourCC, Vec<Shared<Resource>>>
 }
 
 impl Resources {
-	pub fn add(&mut self, ty: FourCC, id: i16, name: Option<Vec<u8>>) -> Option<Rc<RefCell<Resource>>> {
+	pub fn add(&mut self, ty: FourCC, id: i16, name: Option<Vec<u8>>) -> Option<Shared<Resource>> {
 		if !self.types.contains_key(&ty) {
 			self.types.insert(ty, Vec::new());
 		}
@@ -79,16 +79,16 @@ impl Resources {
 			attributes: 0,
 			data: Vec::new()
 		};
-		let res = Rc::new(RefCell::new(res));
-		list.insert(insert_pos, Rc::clone(&res));
+		let res = Shared::new(res);
+		list.insert(insert_pos, Shared::clone(&res));
 		Some(res)
 	}
 
-	pub fn get(&self, ty: FourCC, id: i16) -> Option<Rc<RefCell<Resource>>> {
+	pub fn get(&self, ty: FourCC, id: i16) -> Option<Shared<Resource>> {
 		if let Some(list) = self.types.get(&ty) {
 			for res in list {
 				if res.borrow().id == id {
-					return Some(Rc::clone(res));
+					return Some(Shared::clone(res));
 				}
 			}
 		}
@@ -239,9 +239,15 @@ impl Resources {
 	}
 }
 
-pub fn parse_resources(file: Rc<RefCell<MacFile>>) -> BinResult<Resources> {
+pub fn parse_resources(file: Shared<MacFile>) -> BinResult<Resources> {
 	let file_ref = file.borrow();
-	let mut cursor = Cursor::new(&file_ref.resource_fork);
+	parse_resource_fork(Shared::clone(&file), &file_ref.resource_fork)
+}
+
+/// Parses a resource fork that belongs to `file`, but might not be what's
+/// currently in it.
+pub fn parse_resource_fork(file: Shared<MacFile>, fork: &[u8]) -> BinResult<Resources> {
+	let mut cursor = Cursor::new(fork);
 
 	let header: Header = cursor.read_be()?;
 	let data_offset = header.data_offset as u64;
@@ -282,7 +288,7 @@ pub fn parse_resources(file: Rc<RefCell<MacFile>>) -> BinResult<Resources> {
 			let res_start = res_header + 4;
 			let res_end = res_start + res_size as u64;
 
-			let data = file_ref.resource_fork[res_start as usize .. res_end as usize].to_vec();
+			let data = fork[res_start as usize .. res_end as usize].to_vec();
 
 			let res = Resource {
 				id: ref_list_entry.id,
@@ -290,13 +296,11 @@ pub fn parse_resources(file: Rc<RefCell<MacFile>>) -> BinResult<Resources> {
 				attributes: (ref_list_entry.attributes_and_data_offset >> 24) as u8,
 				data
 			};
-			resources.push(Rc::new(RefCell::new(res)));
+			resources.push(Shared::new(res));
 		}
 
 		types.insert(type_list_entry.type_id, resources);
 	}
-	drop(file_ref);
-
 	Ok(Resources {
 		file,
 		attributes: map.attributes,
@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+const MAGIC: &[u8; 8] = b"MPWSNAP\0";
+const VERSION: u32 = 3;
+
+/// Builds up the contents of a snapshot file. Everything is big-endian, and
+/// variable-length things are preceded by their length.
+pub struct SnapshotWriter {
+	buffer: Vec<u8>
+}
+
+impl SnapshotWriter {
+	pub fn new() -> Self {
+		let mut buffer = Vec::new();
+		buffer.extend_from_slice(MAGIC);
+		buffer.extend_from_slice(&VERSION.to_be_bytes());
+		SnapshotWriter { buffer }
+	}
+
+	pub fn into_bytes(self) -> Vec<u8> {
+		self.buffer
+	}
+
+	pub fn u8(&mut self, value: u8) {
+		self.buffer.push(value);
+	}
+
+	pub fn bool(&mut self, value: bool) {
+		self.u8(value as u8);
+	}
+
+	pub fn u16(&mut self, value: u16) {
+		self.buffer.extend_from_slice(&value.to_be_bytes());
+	}
+
+	pub fn i16(&mut self, value: i16) {
+		self.buffer.extend_from_slice(&value.to_be_bytes());
+	}
+
+	pub fn u32(&mut self, value: u32) {
+		self.buffer.extend_from_slice(&value.to_be_bytes());
+	}
+
+	pub fn i32(&mut self, value: i32) {
+		self.buffer.extend_from_slice(&value.to_be_bytes());
+	}
+
+	pub fn u64(&mut self, value: u64) {
+		self.buffer.extend_from_slice(&value.to_be_bytes());
+	}
+
+	pub fn count(&mut self, count: usize) {
+		self.u32(count as u32);
+	}
+
+	pub fn bytes(&mut self, value: &[u8]) {
+		self.count(value.len());
+		self.buffer.extend_from_slice(value);
+	}
+
+	pub fn str(&mut self, value: &str) {
+		self.bytes(value.as_bytes());
+	}
+
+	pub fn path(&mut self, value: &std::path::Path) {
+		self.str(&value.to_string_lossy());
+	}
+}
+
+/// Reads back what a `SnapshotWriter` produced.
+pub struct SnapshotReader<'a> {
+	data: &'a [u8]
+}
+
+impl<'a> SnapshotReader<'a> {
+	pub fn new(data: &'a [u8]) -> Result<Self> {
+		let mut reader = SnapshotReader { data };
+		if reader.take(MAGIC.len())? != MAGIC {
+			return Err(anyhow!("not a snapshot file"));
+		}
+		let version = reader.u32()?;
+		if version != VERSION {
+			return Err(anyhow!("snapshot is version {version}, but only version {VERSION} is supported"));
+		}
+		Ok(reader)
+	}
+
+	fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+		if self.data.len() < length {
+			return Err(anyhow!("snapshot is truncated"));
+		}
+		let (value, rest) = self.data.split_at(length);
+		self.data = rest;
+		Ok(value)
+	}
+
+	fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+		Ok(self.take(N)?.try_into().unwrap())
+	}
+
+	pub fn u8(&mut self) -> Result<u8> {
+		Ok(self.take(1)?[0])
+	}
+
+	pub fn bool(&mut self) -> Result<bool> {
+		Ok(self.u8()? != 0)
+	}
+
+	pub fn u16(&mut self) -> Result<u16> {
+		Ok(u16::from_be_bytes(self.array()?))
+	}
+
+	pub fn i16(&mut self) -> Result<i16> {
+		Ok(i16::from_be_bytes(self.array()?))
+	}
+
+	pub fn u32(&mut self) -> Result<u32> {
+		Ok(u32::from_be_bytes(self.array()?))
+	}
+
+	pub fn i32(&mut self) -> Result<i32> {
+		Ok(i32::from_be_bytes(self.array()?))
+	}
+
+	pub fn u64(&mut self) -> Result<u64> {
+		Ok(u64::from_be_bytes(self.array()?))
+	}
+
+	pub fn count(&mut self) -> Result<usize> {
+		Ok(self.u32()? as usize)
+	}
+
+	pub fn bytes(&mut self) -> Result<Vec<u8>> {
+		let length = self.count()?;
+		Ok(self.take(length)?.to_vec())
+	}
+
+	pub fn str(&mut self) -> Result<String> {
+		Ok(String::from_utf8(self.bytes()?)?)
+	}
+
+	pub fn path(&mut self) -> Result<PathBuf> {
+		Ok(PathBuf::from(self.str()?))
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.data.is_empty()
+	}
+}
@@ -0,0 +1,157 @@
+use std::{io::{self, Write}, path::PathBuf, sync::{Arc, Mutex}};
+
+use anyhow::{anyhow, Result};
+
+use crate::{emulator, linker};
+
+/// Everything needed to run a tool once. Nothing is inherited from the
+/// current process: the tool gets an empty environment and empty input
+/// unless told otherwise, and its output is captured.
+pub struct Tool {
+	path: PathBuf,
+	args: Vec<String>,
+	env_vars: Vec<(String, String)>,
+	current_dir: Option<PathBuf>,
+	stdin: Vec<u8>,
+	stdout: Option<Box<dyn Write + Send>>,
+	stderr: Option<Box<dyn Write + Send>>,
+	fragment_choice: Option<String>,
+	library_path: Vec<PathBuf>,
+	limits: emulator::Limits
+}
+
+/// How a tool finished
+pub struct Output {
+	pub status: i32,
+	/// Everything the tool wrote to stdout, unless it was sent elsewhere
+	pub stdout: Vec<u8>,
+	/// Everything the tool wrote to stderr, unless it was sent elsewhere
+	pub stderr: Vec<u8>
+}
+
+/// Collects output so it can be handed back once the tool is done
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+	fn take(&self) -> Vec<u8> {
+		std::mem::take(&mut *self.0.lock().unwrap())
+	}
+}
+
+impl Write for Capture {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0.lock().unwrap().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Tool {
+	pub fn new(path: impl Into<PathBuf>) -> Self {
+		Tool {
+			path: path.into(),
+			args: Vec::new(),
+			env_vars: Vec::new(),
+			current_dir: None,
+			stdin: Vec::new(),
+			stdout: None,
+			stderr: None,
+			fragment_choice: None,
+			library_path: Vec::new(),
+			limits: emulator::Limits::default()
+		}
+	}
+
+	pub fn arg(mut self, arg: impl Into<String>) -> Self {
+		self.args.push(arg.into());
+		self
+	}
+
+	pub fn args<I: IntoIterator<Item = S>, S: Into<String>>(mut self, args: I) -> Self {
+		self.args.extend(args.into_iter().map(Into::into));
+		self
+	}
+
+	pub fn env(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+		self.env_vars.push((name.into(), value.into()));
+		self
+	}
+
+	/// Directory that the tool's relative paths start from. Defaults to the
+	/// current directory of this process.
+	pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.current_dir = Some(dir.into());
+		self
+	}
+
+	pub fn stdin(mut self, data: impl Into<Vec<u8>>) -> Self {
+		self.stdin = data.into();
+		self
+	}
+
+	/// Sends stdout here as it's written, instead of capturing it
+	pub fn stdout(mut self, sink: impl Write + Send + 'static) -> Self {
+		self.stdout = Some(Box::new(sink));
+		self
+	}
+
+	/// Sends stderr here as it's written, instead of capturing it
+	pub fn stderr(mut self, sink: impl Write + Send + 'static) -> Self {
+		self.stderr = Some(Box::new(sink));
+		self
+	}
+
+	/// Picks a fragment by index or name, for tools that contain several
+	pub fn fragment(mut self, choice: impl Into<String>) -> Self {
+		self.fragment_choice = Some(choice.into());
+		self
+	}
+
+	/// Adds a directory to search for shared libraries the tool imports
+	pub fn library_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+		self.library_path.push(dir.into());
+		self
+	}
+
+	/// Stops the tool early if it runs for too long or uses too much memory;
+	/// the exit status then says which limit it hit
+	pub fn limits(mut self, limits: emulator::Limits) -> Self {
+		self.limits = limits;
+		self
+	}
+
+	/// Loads the tool and runs it to completion.
+	pub fn run(self) -> Result<Output> {
+		let (exe, res) = linker::load_executable(&self.path, self.fragment_choice.as_deref(), self.library_path, emulator::data_import_size)?;
+
+		let (stdout, stderr) = (Capture::default(), Capture::default());
+		let console = emulator::Console {
+			stdin: Box::new(io::Cursor::new(self.stdin)),
+			stdout: self.stdout.unwrap_or_else(|| Box::new(stdout.clone())),
+			stderr: self.stderr.unwrap_or_else(|| Box::new(stderr.clone()))
+		};
+		let options = emulator::Options {
+			console,
+			current_dir: self.current_dir,
+			limits: self.limits,
+			..Default::default()
+		};
+
+		// argv[0] is the tool itself, as it is on the command line
+		let mut args = vec![self.path.to_string_lossy().into_owned()];
+		args.extend(self.args);
+
+		let status = emulator::emulate(&exe, res, &args, &self.env_vars, options)
+			.map_err(|e| anyhow!("emulation failed: {e:?}"))?;
+
+		Ok(Output {
+			status,
+			stdout: stdout.take(),
+			stderr: stderr.take()
+		})
+	}
+}
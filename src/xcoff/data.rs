@@ -0,0 +1,83 @@
+use binread::BinRead;
+
+#[derive(BinRead, Debug)]
+#[br(big, magic = 0x01DFu16)]
+pub struct FileHeader {
+	pub section_count: u16,
+	_time_date: u32,
+	_symbol_table_offset: u32,
+	_symbol_count: u32,
+	pub aux_header_size: u16,
+	_flags: u16
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+pub struct AuxHeader {
+	pub magic: u16,
+	_version: u16,
+	_text_size: u32,
+	_data_size: u32,
+	_bss_size: u32,
+	pub entry: u32,
+	_text_start: u32,
+	_data_start: u32,
+	pub toc: u32,
+	pub entry_section: u16,
+	_text_section: u16,
+	_data_section: u16,
+	_toc_section: u16,
+	_loader_section: u16,
+	_bss_section: u16,
+	pub text_alignment: u16,
+	pub data_alignment: u16
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+pub struct SectionHeader {
+	pub name: [u8; 8],
+	_physical_address: u32,
+	pub virtual_address: u32,
+	pub size: u32,
+	pub raw_data_offset: u32,
+	_reloc_offset: u32,
+	_line_number_offset: u32,
+	_reloc_count: u16,
+	_line_number_count: u16,
+	pub flags: u32
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+pub struct LoaderHeader {
+	pub version: u32,
+	pub symbol_count: u32,
+	pub reloc_count: u32,
+	_import_strings_length: u32,
+	pub import_file_count: u32,
+	pub import_strings_offset: u32,
+	_strings_length: u32,
+	pub strings_offset: u32
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+pub struct LoaderSymbol {
+	pub name: [u8; 8],
+	pub value: u32,
+	pub section_number: i16,
+	pub symbol_type: u8,
+	pub storage_class: u8,
+	pub import_file: u32,
+	_parameter_check: u32
+}
+
+#[derive(BinRead, Debug)]
+#[br(big)]
+pub struct LoaderReloc {
+	pub virtual_address: u32,
+	pub symbol_index: u32,
+	pub reloc_type: u16,
+	pub section_number: i16
+}
@@ -0,0 +1,259 @@
+use std::io::Cursor;
+
+use binread::{BinReaderExt, BinResult, NullString};
+
+mod data;
+
+pub const MAGIC: u16 = 0x01DF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SectionKind {
+	Text,
+	Data,
+	Bss,
+	Loader,
+	Other(u32)
+}
+
+#[derive(Debug)]
+pub struct Section {
+	pub name: String,
+	pub kind: SectionKind,
+	pub virtual_address: u32,
+	pub size: u32,
+	pub alignment: u8,
+	pub contents: Vec<u8>
+}
+
+#[derive(Debug)]
+pub struct ImportFile {
+	pub path: String,
+	pub base: String,
+	pub member: String
+}
+
+impl ImportFile {
+	/// The name that this import should be resolved against. Mac tools put the
+	/// shared library name in the base, but fall back on the others just in case.
+	pub fn library_name(&self) -> &str {
+		[&self.base, &self.member, &self.path].into_iter()
+			.find(|s| !s.is_empty())
+			.map_or("", |s| s.as_str())
+	}
+}
+
+#[derive(Debug)]
+pub struct LoaderSymbol {
+	pub name: String,
+	pub value: u32,
+	/// 1-based section number; 0 for imports
+	pub section: i16,
+	pub is_import: bool,
+	pub is_export: bool,
+	pub is_weak: bool,
+	pub storage_class: u8,
+	/// Index into the import file list (0 is the default library path)
+	pub import_file: u32
+}
+
+#[derive(Debug)]
+pub struct Relocation {
+	pub virtual_address: u32,
+	/// 0, 1 and 2 refer to .text, .data and .bss, anything above that is a
+	/// loader symbol index plus 3
+	pub symbol_index: u32,
+	pub reloc_type: u8,
+	pub bit_length: u8,
+	pub signed: bool,
+	/// 1-based number of the section containing `virtual_address`
+	pub section: i16
+}
+
+#[derive(Debug)]
+pub struct Loader {
+	pub import_files: Vec<ImportFile>,
+	pub symbols: Vec<LoaderSymbol>,
+	pub relocations: Vec<Relocation>
+}
+
+#[derive(Debug)]
+pub struct XCOFF {
+	/// Address of the entry point's function descriptor
+	pub entry_point: Option<u32>,
+	pub toc_anchor: u32,
+	pub sections: Vec<Section>,
+	pub loader: Option<Loader>
+}
+
+// storage mapping classes
+pub const XMC_PR: u8 = 0;
+pub const XMC_GL: u8 = 6;
+pub const XMC_DS: u8 = 10;
+pub const XMC_TC0: u8 = 15;
+
+// relocation types
+pub const R_POS: u8 = 0;
+
+pub fn is_xcoff(data: &[u8]) -> bool {
+	data.len() >= 2 && u16::from_be_bytes([data[0], data[1]]) == MAGIC
+}
+
+fn parse_name(raw: &[u8]) -> String {
+	let end = raw.iter().position(|&c| c == 0).unwrap_or(raw.len());
+	String::from_utf8_lossy(&raw[.. end]).into_owned()
+}
+
+pub fn read_xcoff(data: &[u8]) -> BinResult<XCOFF> {
+	let mut cursor = Cursor::new(data);
+	let file_header: data::FileHeader = cursor.read_be()?;
+
+	let aux_start = cursor.position();
+	// anything shorter than the whole header would have us reading section
+	// headers as alignments
+	let aux_header: Option<data::AuxHeader> = if file_header.aux_header_size >= 0x30 {
+		let aux: data::AuxHeader = cursor.read_be()?;
+		if aux.magic != 0x010B {
+			warn!(target: "xcoff", "Unexpected auxiliary header magic {:04X}", aux.magic);
+		}
+		Some(aux)
+	} else {
+		None
+	};
+	cursor.set_position(aux_start + file_header.aux_header_size as u64);
+
+	let mut sections = Vec::new();
+	for number in 1 ..= file_header.section_count {
+		let hdr: data::SectionHeader = cursor.read_be()?;
+
+		let kind = match hdr.flags & 0xFFFF {
+			0x0020 => SectionKind::Text,
+			0x0040 => SectionKind::Data,
+			0x0080 => SectionKind::Bss,
+			0x1000 => SectionKind::Loader,
+			n => SectionKind::Other(n)
+		};
+
+		let contents = if kind == SectionKind::Bss || hdr.raw_data_offset == 0 {
+			Vec::new()
+		} else {
+			let start = hdr.raw_data_offset as usize;
+			let end = start + hdr.size as usize;
+			match data.get(start .. end) {
+				Some(contents) => contents.to_vec(),
+				None => {
+					warn!(target: "xcoff", "Section {number} lies outside the file");
+					Vec::new()
+				}
+			}
+		};
+
+		let alignment = match (&aux_header, kind) {
+			(Some(aux), SectionKind::Text) => aux.text_alignment as u8,
+			(Some(aux), SectionKind::Data | SectionKind::Bss) => aux.data_alignment as u8,
+			_ => 2
+		};
+
+		sections.push(Section {
+			name: parse_name(&hdr.name),
+			kind,
+			virtual_address: hdr.virtual_address,
+			size: hdr.size,
+			alignment,
+			contents
+		});
+	}
+
+	let loader = match sections.iter().find(|s| s.kind == SectionKind::Loader) {
+		Some(section) => Some(parse_loader(&section.contents)?),
+		None => None
+	};
+
+	let entry_point = aux_header.as_ref()
+		.filter(|aux| aux.entry_section != 0 && aux.entry != 0xFFFFFFFF)
+		.map(|aux| aux.entry);
+
+	Ok(XCOFF {
+		entry_point,
+		toc_anchor: aux_header.as_ref().map_or(0, |aux| aux.toc),
+		sections,
+		loader
+	})
+}
+
+pub fn parse_loader(data: &[u8]) -> BinResult<Loader> {
+	let mut cursor = Cursor::new(data);
+	let header: data::LoaderHeader = cursor.read_be()?;
+	if header.version != 1 {
+		warn!(target: "xcoff", "Unexpected loader section version {}", header.version);
+	}
+
+	let mut symbols_data: Vec<data::LoaderSymbol> = Vec::new();
+	for _ in 0..header.symbol_count {
+		symbols_data.push(cursor.read_be()?);
+	}
+	let mut relocs_data: Vec<data::LoaderReloc> = Vec::new();
+	for _ in 0..header.reloc_count {
+		relocs_data.push(cursor.read_be()?);
+	}
+
+	// --
+	// IMPORT FILES
+
+	let mut import_files = Vec::new();
+	cursor.set_position(header.import_strings_offset.into());
+	for _ in 0..header.import_file_count {
+		let path = cursor.read_be::<NullString>()?.to_string();
+		let base = cursor.read_be::<NullString>()?.to_string();
+		let member = cursor.read_be::<NullString>()?.to_string();
+		import_files.push(ImportFile { path, base, member });
+	}
+
+	// --
+	// SYMBOLS
+
+	let mut symbols = Vec::new();
+	for sym in symbols_data {
+		// names that don't fit in 8 bytes are in the string table, with a
+		// 16-bit length before them
+		let name = if sym.name[.. 4] == [0, 0, 0, 0] {
+			let offset = u32::from_be_bytes(sym.name[4 ..].try_into().unwrap());
+			let start = (header.strings_offset + offset) as usize;
+			let length = match start.checked_sub(2).and_then(|len_pos| data.get(len_pos .. start)) {
+				Some(&[hi, lo]) => u16::from_be_bytes([hi, lo]) as usize,
+				_ => 0
+			};
+			parse_name(data.get(start .. start + length).unwrap_or(&[]))
+		} else {
+			parse_name(&sym.name)
+		};
+
+		symbols.push(LoaderSymbol {
+			name,
+			value: sym.value,
+			section: sym.section_number,
+			is_import: (sym.symbol_type & 0x40) != 0,
+			is_export: (sym.symbol_type & 0x10) != 0,
+			is_weak: (sym.symbol_type & 0x08) != 0,
+			storage_class: sym.storage_class,
+			import_file: sym.import_file
+		});
+	}
+
+	// --
+	// RELOCATIONS
+
+	let relocations = relocs_data.into_iter().map(|reloc| Relocation {
+		virtual_address: reloc.virtual_address,
+		symbol_index: reloc.symbol_index,
+		reloc_type: reloc.reloc_type as u8,
+		bit_length: ((reloc.reloc_type >> 8) & 0x1F) as u8 + 1,
+		signed: (reloc.reloc_type & 0x8000) != 0,
+		section: reloc.section_number
+	}).collect();
+
+	Ok(Loader {
+		import_files,
+		symbols,
+		relocations
+	})
+}